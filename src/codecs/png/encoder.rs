@@ -0,0 +1,433 @@
+use std::io::Write;
+use std::io::BufWriter;
+
+use crate::image::{GenericImageTo, GenericImage, GenericImageColors, WriteImage};
+use crate::error::*;
+use crate::compress::zlib;
+use crate::hashs::crc32::Crc32;
+use super::png::{PngImage, ColorType, FilterType, PNG_SIGNATURE, peath_predictor};
+
+const FILTER_TYPES: [FilterType; 5] = [
+    FilterType::None,
+    FilterType::Sub,
+    FilterType::Up,
+    FilterType::Average,
+    FilterType::Paeth,
+];
+
+/// How the encoder picks the filter of each scanline
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterStrategy {
+    /// Same filter for every scanline
+    Fixed(FilterType),
+    /// Filter with the minimum sum of absolute differences, bytes taken as signed
+    MinSum,
+    /// Filter with the lowest Shannon entropy estimate of the filtered bytes
+    Entropy,
+    /// Filter giving the smallest output when each candidate is compressed
+    BruteForce,
+}
+
+/// PNG writer, ancillary chunks are only written when requested
+#[derive(Debug, Clone)]
+pub struct PngEncoder {
+    colors: Option<GenericImageColors>,
+    compression_level: u8,
+    filter_strategy: FilterStrategy,
+    texts: Vec<(String, String, bool)>,
+    phys: Option<(u32, u32, bool)>,
+    time: Option<(u16, u8, u8, u8, u8, u8)>,
+}
+
+impl Default for PngEncoder {
+    fn default() -> Self {
+        PngEncoder::new()
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, name: &[u8; 4], data: &[u8]) -> Result<(), ImageError> {
+    let mut hasher = Crc32::new();
+    hasher.update(name);
+    hasher.update(data);
+
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(name)?;
+    writer.write_all(data)?;
+    writer.write_all(&hasher.finalise().to_be_bytes())?;
+    Ok(())
+}
+
+fn filter_scanline(filter_type: FilterType, bpp: usize, prev: &[u8], sl: &[u8], out: &mut Vec<u8>) {
+    out.push(filter_type as u8);
+
+    for i in 0..sl.len() {
+        let a = if i >= bpp { sl[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+
+        let predictor = match filter_type {
+            FilterType::None => 0,
+            FilterType::Sub => a,
+            FilterType::Up => b,
+            FilterType::Average => ((a as u16 + b as u16) / 2) as u8,
+            FilterType::Paeth => peath_predictor(a as i16, b as i16, c as i16),
+        };
+        out.push(sl[i].wrapping_sub(predictor));
+    }
+}
+
+fn sum_abs_diff(filtered: &[u8]) -> u64 {
+    filtered.iter().map(|b| (*b as i8).unsigned_abs() as u64).sum()
+}
+
+fn entropy_estimate(filtered: &[u8]) -> f64 {
+    let mut histogram = [0u32; 256];
+    for b in filtered {
+        histogram[*b as usize] += 1;
+    }
+
+    let total = filtered.len() as f64;
+    histogram.iter()
+        .filter(|n| **n > 0)
+        .map(|n| {
+            let p = *n as f64 / total;
+            -p * p.log2() * total
+        })
+        .sum()
+}
+
+fn latin1_bytes(s: &str) -> Result<Vec<u8>, ImageError> {
+    s.chars()
+        .map(|c| {
+            if (c as u32) < 256 {
+                Ok(c as u8)
+            } else {
+                Err(ImageError::Encoding(EncodingError { str: format!("'{}' cannot be written as Latin-1", c) }))
+            }
+        })
+        .collect()
+}
+
+fn keyword_bytes(keyword: &str) -> Result<Vec<u8>, ImageError> {
+    let bytes = latin1_bytes(keyword)?;
+
+    let printable = bytes.iter().all(|b| (32..=126).contains(b) || *b >= 161);
+    if bytes.is_empty() || bytes.len() > 79 || ! printable
+        || keyword.starts_with(' ') || keyword.ends_with(' ') || keyword.contains("  ") {
+        return Err(ImageError::Encoding(EncodingError { str: format!("Invalid keyword '{}'", keyword) }));
+    }
+    Ok(bytes)
+}
+
+impl PngEncoder {
+    pub fn new() -> Self {
+        PngEncoder {
+            colors: None,
+            compression_level: 6,
+            filter_strategy: FilterStrategy::MinSum,
+            texts: Vec::new(),
+            phys: None,
+            time: None,
+        }
+    }
+
+    /// Color format of the output. By default the image is read as RGBA and
+    /// written as RGB when every pixel is opaque.
+    pub fn colors(mut self, colors: GenericImageColors) -> Self {
+        self.colors = Some(colors);
+        self
+    }
+
+    /// zlib compression level, from 0 (store) to 10, the crate own deflater
+    /// stops at 9
+    pub fn compression_level(mut self, level: u8) -> Self {
+        self.compression_level = level.min(10);
+        self
+    }
+
+    /// Scanline filter selection, the minimum sum of absolute differences
+    /// heuristic is used by default
+    pub fn filter_strategy(mut self, strategy: FilterStrategy) -> Self {
+        self.filter_strategy = strategy;
+        self
+    }
+
+    /// Add a tEXt chunk, keyword and text must be Latin-1
+    pub fn text(mut self, keyword: &str, text: &str) -> Self {
+        self.texts.push((keyword.to_string(), text.to_string(), false));
+        self
+    }
+
+    /// Add a zTXt chunk, keyword and text must be Latin-1
+    pub fn compressed_text(mut self, keyword: &str, text: &str) -> Self {
+        self.texts.push((keyword.to_string(), text.to_string(), true));
+        self
+    }
+
+    /// Add a pHYs chunk, in pixels per meter when `meter` is set, otherwise
+    /// only the aspect ratio is meaningful
+    pub fn physical_size(mut self, ppux: u32, ppuy: u32, meter: bool) -> Self {
+        self.phys = Some((ppux, ppuy, meter));
+        self
+    }
+
+    /// Add a tIME chunk, last modification time in UTC
+    pub fn time(mut self, year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+        self.time = Some((year, month, day, hour, minute, second));
+        self
+    }
+
+    fn convert<I: GenericImageTo>(&self, image: &I) -> Result<GenericImage, ImageError> {
+        match self.colors {
+            Some(GenericImageColors::RGB) => image.to_rgb(),
+            Some(GenericImageColors::RGBA) => image.to_rgba(),
+            Some(GenericImageColors::G) => image.to_g(),
+            None => {
+                let img = image.to_rgba()?;
+                if img.data.chunks(4).all(|p| p[3] == 255) {
+                    let data = img.data.chunks(4).flat_map(|p| p[..3].iter().copied()).collect();
+                    return Ok(GenericImage { data, colors: GenericImageColors::RGB, ..img });
+                }
+                Ok(img)
+            }
+        }
+    }
+
+    fn ihdr_data(img: &GenericImage) -> Vec<u8> {
+        let color_type = match img.colors {
+            GenericImageColors::RGB => ColorType::TrueColor,
+            GenericImageColors::RGBA => ColorType::TrueColorAlpha,
+            GenericImageColors::G => ColorType::GrayScale,
+        };
+
+        let mut data: Vec<u8> = Vec::with_capacity(13);
+        data.extend_from_slice(&img.width.to_be_bytes());
+        data.extend_from_slice(&img.height.to_be_bytes());
+        data.push(8); // bit depth
+        data.push(color_type as u8);
+        data.push(0); // compression method
+        data.push(0); // filter method
+        data.push(0); // interlace method
+        data
+    }
+
+    fn text_data(&self, keyword: &str, text: &str, compressed: bool) -> Result<Vec<u8>, ImageError> {
+        let mut data = keyword_bytes(keyword)?;
+        data.push(0);
+
+        let text = latin1_bytes(text)?;
+        if compressed {
+            data.push(0); // compression method
+            data.extend(zlib::compress(&text, self.compression_level));
+        } else {
+            data.extend(text);
+        }
+        Ok(data)
+    }
+
+    fn select_filter(&self, bpp: usize, prev: &[u8], sl: &[u8], candidate: &mut Vec<u8>, out: &mut Vec<u8>) {
+        if let FilterStrategy::Fixed(filter_type) = self.filter_strategy {
+            filter_scanline(filter_type, bpp, prev, sl, out);
+            return;
+        }
+
+        let mut best: Option<(f64, FilterType)> = None;
+        for filter_type in FILTER_TYPES.iter() {
+            candidate.clear();
+            filter_scanline(*filter_type, bpp, prev, sl, candidate);
+
+            let cost = match self.filter_strategy {
+                FilterStrategy::MinSum => sum_abs_diff(&candidate[1..]) as f64,
+                FilterStrategy::Entropy => entropy_estimate(&candidate[1..]),
+                FilterStrategy::BruteForce => zlib::compress(candidate, self.compression_level).len() as f64,
+                FilterStrategy::Fixed(_) => unreachable!(),
+            };
+            if best.is_none_or(|(best_cost, _)| cost < best_cost) {
+                best = Some((cost, *filter_type));
+            }
+        }
+
+        let (_, filter_type) = best.unwrap();
+        debug!("filter: {:?}", filter_type);
+        filter_scanline(filter_type, bpp, prev, sl, out);
+    }
+
+    fn scanlines(&self, img: &GenericImage) -> Vec<u8> {
+        let bpp = img.nb_components();
+        let scanline_len = img.width as usize * bpp;
+        let mut ret: Vec<u8> = Vec::with_capacity((scanline_len + 1) * img.height as usize);
+        let mut candidate: Vec<u8> = Vec::with_capacity(scanline_len + 1);
+
+        if scanline_len == 0 {
+            return ret;
+        }
+        let sl0: Vec<u8> = vec!(0u8; scanline_len);
+        let mut prev_scanline: &[u8] = sl0.as_ref();
+
+        for sl in img.data.chunks(scanline_len) {
+            self.select_filter(bpp, prev_scanline, sl, &mut candidate, &mut ret);
+            prev_scanline = sl;
+        }
+        ret
+    }
+
+    pub fn encode<W: Write, I: GenericImageTo>(&self, writer: W, image: &I) -> Result<(), ImageError> {
+        let img = self.convert(image)?;
+        if img.width == 0 || img.height == 0 {
+            return Err(ImageError::Encoding(EncodingError::new("PNG images cannot be empty")));
+        }
+        if img.data.len() != img.width as usize * img.height as usize * img.nb_components() {
+            return Err(ImageError::Encoding(EncodingError::new("Image data size does not match its dimensions")));
+        }
+
+        let mut buf = BufWriter::new(writer);
+        buf.write_all(&PNG_SIGNATURE)?;
+        write_chunk(&mut buf, b"IHDR", &Self::ihdr_data(&img))?;
+
+        if let Some((ppux, ppuy, meter)) = self.phys {
+            let mut data: Vec<u8> = Vec::with_capacity(9);
+            data.extend_from_slice(&ppux.to_be_bytes());
+            data.extend_from_slice(&ppuy.to_be_bytes());
+            data.push(meter as u8);
+            write_chunk(&mut buf, b"pHYs", &data)?;
+        }
+        if let Some((year, month, day, hour, minute, second)) = self.time {
+            let mut data: Vec<u8> = Vec::with_capacity(7);
+            data.extend_from_slice(&year.to_be_bytes());
+            data.extend_from_slice(&[month, day, hour, minute, second]);
+            write_chunk(&mut buf, b"tIME", &data)?;
+        }
+        for (keyword, text, compressed) in &self.texts {
+            let name = if *compressed { b"zTXt" } else { b"tEXt" };
+            write_chunk(&mut buf, name, &self.text_data(keyword, text, *compressed)?)?;
+        }
+
+        let idat = zlib::compress(&self.scanlines(&img), self.compression_level);
+        write_chunk(&mut buf, b"IDAT", &idat)?;
+        write_chunk(&mut buf, b"IEND", &[])?;
+
+        buf.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write, I: GenericImageTo> WriteImage<W, I> for PngImage {
+    fn write_image(writer: W, image: &I) -> Result<(), ImageError> {
+        PngEncoder::new().encode(writer, image)
+    }
+}
+
+#[cfg(test)]
+fn roundtrip(encoder: &PngEncoder, img: &GenericImage) -> PngImage {
+    use crate::image::ReadImage;
+
+    let mut out: Vec<u8> = Vec::new();
+    encoder.encode(&mut out, img).unwrap();
+    *PngImage::read_image(out.as_slice()).unwrap()
+}
+
+#[test]
+fn test_encode_roundtrip() {
+    let rgba = GenericImage {
+        width: 3,
+        height: 2,
+        colors: GenericImageColors::RGBA,
+        data: vec!(1, 2, 3, 255, 4, 5, 6, 0, 7, 8, 9, 128,
+                   10, 11, 12, 255, 13, 14, 15, 255, 16, 17, 18, 1),
+    };
+
+    let png = roundtrip(&PngEncoder::new(), &rgba);
+    assert_eq!(png.color_type(), ColorType::TrueColorAlpha);
+    assert_eq!(png.to_rgba().unwrap().data, rgba.data);
+
+    let png = roundtrip(&PngEncoder::new().colors(GenericImageColors::RGB), &rgba);
+    assert_eq!(png.color_type(), ColorType::TrueColor);
+    assert_eq!(png.to_rgb().unwrap().data, rgba.to_rgb().unwrap().data);
+
+    let png = roundtrip(&PngEncoder::new().colors(GenericImageColors::G), &rgba);
+    assert_eq!(png.color_type(), ColorType::GrayScale);
+    assert_eq!(png.to_g().unwrap().data, rgba.to_g().unwrap().data);
+}
+
+#[test]
+fn test_encode_opaque_as_rgb() {
+    let rgba = GenericImage {
+        width: 2,
+        height: 1,
+        colors: GenericImageColors::RGBA,
+        data: vec!(1, 2, 3, 255, 4, 5, 6, 255),
+    };
+
+    let encoder = PngEncoder::new()
+        .text("Title", "Caf\u{e9}")
+        .compressed_text("Comment", "zipped")
+        .physical_size(2835, 2835, true)
+        .time(2021, 12, 24, 10, 0, 0);
+    let png = roundtrip(&encoder, &rgba);
+    assert_eq!(png.color_type(), ColorType::TrueColor);
+    assert_eq!(png.to_rgb().unwrap().data, vec!(1, 2, 3, 4, 5, 6));
+}
+
+#[test]
+fn test_encode_metadata() {
+    use crate::metadata::{Timestamp, PixelDensity, DensityUnit};
+
+    let g = GenericImage { width: 1, height: 1, colors: GenericImageColors::G, data: vec!(0) };
+    let png = roundtrip(&PngEncoder::new(), &g);
+    assert!(png.metadata().is_empty());
+
+    let encoder = PngEncoder::new()
+        .text("Title", "Caf\u{e9}")
+        .compressed_text("Comment", "zipped")
+        .physical_size(3780, 3780, true)
+        .time(2021, 12, 24, 10, 0, 0);
+    let png = roundtrip(&encoder, &g);
+    let metadata = png.metadata();
+    assert_eq!(metadata.text("Title"), Some("Caf\u{e9}"));
+    assert_eq!(metadata.text("Comment"), Some("zipped"));
+    assert_eq!(metadata.text("Author"), None);
+    assert_eq!(metadata.time, Timestamp::new(2021, 12, 24, 10, 0, 0));
+    assert_eq!(metadata.density, Some(PixelDensity { x: 3780, y: 3780, unit: DensityUnit::Meter }));
+    let (dpi, _) = metadata.density.unwrap().dpi().unwrap();
+    assert!((dpi - 96.0).abs() < 0.1);
+}
+
+#[test]
+fn test_encode_invalid_keyword() {
+    let g = GenericImage { width: 1, height: 1, colors: GenericImageColors::G, data: vec!(0) };
+    let mut out: Vec<u8> = Vec::new();
+
+    assert!(PngEncoder::new().text("", "empty").encode(&mut out, &g).is_err());
+    assert!(PngEncoder::new().text(" lead", "space").encode(&mut out, &g).is_err());
+    assert!(PngEncoder::new().text("Title", "\u{263a}").encode(&mut out, &g).is_err());
+}
+
+#[test]
+fn test_encode_filter_strategies() {
+    let data: Vec<u8> = (0..16 * 8 * 3).map(|i: u32| ((i * 7) ^ (i / 24)) as u8).collect();
+    let rgb = GenericImage { width: 16, height: 8, colors: GenericImageColors::RGB, data };
+
+    let mut strategies: Vec<FilterStrategy> = FILTER_TYPES.iter().map(|f| FilterStrategy::Fixed(*f)).collect();
+    strategies.extend_from_slice(&[FilterStrategy::MinSum, FilterStrategy::Entropy, FilterStrategy::BruteForce]);
+
+    for strategy in strategies {
+        let png = roundtrip(&PngEncoder::new().filter_strategy(strategy), &rgb);
+        assert_eq!(png.to_rgb().unwrap().data, rgb.data, "{:?}", strategy);
+    }
+}
+
+#[test]
+fn test_select_filter_gradient() {
+    let encoder = PngEncoder::new();
+    let prev = [0u8; 8];
+    let sl = [10u8, 20, 30, 40, 50, 60, 70, 80];
+    let mut candidate: Vec<u8> = Vec::new();
+    let mut out: Vec<u8> = Vec::new();
+
+    encoder.select_filter(1, &prev, &sl, &mut candidate, &mut out);
+    assert_eq!(out, vec!(FilterType::Sub as u8, 10, 10, 10, 10, 10, 10, 10, 10));
+
+    out.clear();
+    encoder.filter_strategy(FilterStrategy::Entropy).select_filter(1, &prev, &sl, &mut candidate, &mut out);
+    assert_eq!(out[0], FilterType::Sub as u8);
+}
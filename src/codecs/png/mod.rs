@@ -1,5 +1,10 @@
 
-pub use self::png::{PngImage};
+pub use self::png::{PngImage, ColorType, FilterType, PNG_SIGNATURE};
+pub use self::encoder::{PngEncoder, FilterStrategy};
+pub use self::stream::{PngStreamDecoder, PngRows, Row, probe};
+pub use self::validate::validate;
 
 mod png;
-
+mod encoder;
+mod stream;
+mod validate;
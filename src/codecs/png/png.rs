@@ -6,15 +6,25 @@ use nom::number::complete::*;
 use nom::sequence::{tuple, terminated};
 use nom::multi::count;
 use std::str;
-use miniz_oxide::inflate::decompress_to_vec_zlib;
 use std::ops::Div;
+use std::convert::TryFrom;
 use std::io::Read;
 
-use crate::image::{GenericImageTo, GenericImage, GenericImageColors, ReadImage};
+use crate::image::{GenericImageTo, GenericImage, GenericImageColors, ReadImage, ConvertOptions, AlphaMode, ImageInfo, ImageFormat, ImageColorType, Limits};
 use crate::error::*;
+use crate::metadata::{Metadata, TextEntry, Timestamp, PixelDensity, DensityUnit};
+use crate::exif::Exif;
+use crate::color::{ColorSpace, Chromaticities, RenderingIntent, IccProfile, Transform};
+use crate::compress::zlib;
+use crate::hashs::crc32::Crc32;
+use super::stream;
+use super::validate::ChunkValidator;
+use super::stream::PngRows;
+
+pub const PNG_SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
 
 #[derive(Debug)]
-struct Chunk<'a> {
+pub(super) struct Chunk<'a> {
     len: u32,
     name: &'a str,
     data: &'a [u8],
@@ -22,12 +32,11 @@ struct Chunk<'a> {
 }
 
 impl Chunk<'_> {
-    fn check_crc(&self) -> bool {
-        let mut hasher = crc32fast::Hasher::new();
+    fn computed_crc(&self) -> u32 {
+        let mut hasher = Crc32::new();
         hasher.update(self.name.as_bytes());
         hasher.update(self.data);
-        let computed_crc: u32 = hasher.finalize();
-        return computed_crc == self.crc;
+        hasher.finalise()
     }
 }
 
@@ -48,19 +57,42 @@ struct IDAT<'a> {
 }
 
 
+/// tRNS chunk content, depending on the image color type
+#[derive(Debug)]
+enum Transparency {
+    /// Alpha for each palette entry, missing entries are opaque
+    Indexed(Vec<u8>),
+    /// Gray sample value that is fully transparent
+    GrayScale(u16),
+    /// RGB sample values that are fully transparent
+    TrueColor(u16, u16, u16),
+}
+
 #[derive(Debug)]
 pub struct PngImage {
     ihdr: Option<IHDR>,
     idat: Vec<u8>,
+    /// Offset of the first IDAT chunk in the file
+    idat_offset: Option<u64>,
     color_index: Option<Vec<(u8, u8, u8)>>,
+    transparency: Option<Transparency>,
     background: Option<(u8, u8, u8)>,
+    metadata: Metadata,
+    color_space: ColorSpace,
     bpp: usize,
     has_end: bool,
+    limits: Limits,
+    /// Recover from damaged image data instead of failing
+    lenient: bool,
+    /// Samples decoded while checking the image data, reused by `samples`
+    decoded: Option<Vec<u16>>,
+    /// Metadata bytes read so far, checked against the limits
+    metadata_len: u64,
 }
 
-#[derive(Debug, PartialEq, PartialOrd)]
+#[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
 #[repr(u8)]
-enum FilterType {
+pub enum FilterType {
     None = 0,
     Sub = 1,
     Up = 2,
@@ -69,21 +101,21 @@ enum FilterType {
 }
 
 impl FilterType {
-    fn from_u8(val: u8) -> Self {
+    pub(super) fn from_u8(val: u8) -> Result<Self, ImageError> {
         match val {
-            0 => FilterType::None,
-            1 => FilterType::Sub,
-            2 => FilterType::Up,
-            3 => FilterType::Average,
-            4 => FilterType::Paeth,
-            _ => panic!("unknown type")
+            0 => Ok(FilterType::None),
+            1 => Ok(FilterType::Sub),
+            2 => Ok(FilterType::Up),
+            3 => Ok(FilterType::Average),
+            4 => Ok(FilterType::Paeth),
+            _ => Err(ImageError::decoding(DecodingErrorKind::UnknownFilterType(val)))
         }
     }
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
 #[repr(u8)]
-enum ColorType {
+pub enum ColorType {
     GrayScale = 0,
     TrueColor = 2,
     IndexedColor = 3,
@@ -92,19 +124,19 @@ enum ColorType {
 }
 
 impl ColorType  {
-    fn from_u8(val: u8) -> Self {
+    fn from_u8(val: u8) -> Result<Self, ImageError> {
         match val {
-            0 => ColorType::GrayScale,
-            2 => ColorType::TrueColor,
-            3 => ColorType::IndexedColor,
-            4 => ColorType::GrayScaleAlpha,
-            6 => ColorType::TrueColorAlpha,
-            _ => panic!("unknown type")
+            0 => Ok(ColorType::GrayScale),
+            2 => Ok(ColorType::TrueColor),
+            3 => Ok(ColorType::IndexedColor),
+            4 => Ok(ColorType::GrayScaleAlpha),
+            6 => Ok(ColorType::TrueColorAlpha),
+            _ => Err(ImageError::decoding(DecodingErrorKind::InvalidHeaderField { field: "color type", value: val as u32 }))
         }
     }
 }
 
-fn peath_predictor(a: i16, b: i16, c: i16) -> u8 {
+pub(super) fn peath_predictor(a: i16, b: i16, c: i16) -> u8 {
     let p = a + b - c;
     let pa = (p - a).abs();
     let pb = (p - b).abs();
@@ -122,75 +154,188 @@ fn peath_predictor(a: i16, b: i16, c: i16) -> u8 {
     return (ret % 256) as u8
 }
 
-fn map_indexed_color(index: &Vec<(u8, u8, u8)>, img: &Vec<u8>, size: usize) -> Vec<u8> {
+/// Adam7 passes as (x start, y start, x step, y step)
+pub(super) const ADAM7_PASSES: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+pub(super) fn adam7_pass_size(size: u32, start: u32, step: u32) -> u32 {
+    if size <= start {
+        return 0;
+    }
+    (size - start).div_ceil(step)
+}
+
+fn scale_to_u8(sample: u16, bit_depth: u8) -> u8 {
+    match bit_depth {
+        16 => ((sample as u32 * 255 + 32767) / 65535) as u8,
+        8 => sample as u8,
+        depth => {
+            let max = (1u16 << depth) - 1;
+            (sample * 255 / max) as u8
+        }
+    }
+}
+
+pub(super) fn unfilter_scanline(bpp: usize, prev: &[u8], sl: &mut [u8], filter_method: FilterType) {
+    let scanline_len = sl.len();
+
+    match filter_method {
+        FilterType::None => {
+
+        }
+        FilterType::Sub => {
+            for i in bpp..scanline_len {
+                sl[i] = sl[i].overflowing_add(sl[i-bpp]).0;
+            }
+        }
+        FilterType::Up => {
+            for i in 0..scanline_len {
+                sl[i] = sl[i].overflowing_add(prev[i]).0;
+            }
+        }
+        FilterType::Average => {
+            for i in 0..bpp.min(scanline_len) {
+                sl[i] = sl[i].overflowing_add(prev[i].div(2)).0;
+            }
+
+            for i in bpp..scanline_len {
+                sl[i] = sl[i].overflowing_add((sl[i-bpp] as u16 + prev[i] as u16).div(2) as u8).0;
+            }
+        }
+        FilterType::Paeth => {
+            for i in 0..bpp.min(scanline_len) {
+                sl[i] = sl[i].overflowing_add(peath_predictor(0, prev[i] as i16, 0)).0;
+            }
+
+            for i in bpp..scanline_len {
+                sl[i] = sl[i].overflowing_add(peath_predictor(sl[i - bpp] as i16, prev[i] as i16, prev[i - bpp] as i16)).0;
+            }
+        }
+    }
+}
+
+fn map_indexed_color(index: &Vec<(u8, u8, u8)>, img: &Vec<u8>, size: usize) -> Result<Vec<u8>, ImageError> {
     let mut ret: Vec<u8> = Vec::with_capacity(size);
     
     for b in img {
-        let (r, g, b) = index.get(*b as usize).unwrap();
+        let (r, g, b) = index.get(*b as usize).ok_or(ImageError::decoding(DecodingErrorKind::InvalidPaletteIndex(*b)))?;
         ret.push(*r);
         ret.push(*g);
         ret.push(*b);
     }
-    return ret;
+    return Ok(ret);
 }
 
 
 
 impl PngImage {
-    fn new() -> PngImage {
+    pub(super) fn new() -> PngImage {
+        PngImage::with_limits(&Limits::default())
+    }
+
+    pub(super) fn with_limits(limits: &Limits) -> PngImage {
         PngImage {
             ihdr: None,
             idat: Vec::new(),
+            idat_offset: None,
             bpp: 0,
             color_index: None,
+            transparency: None,
             background: None,
+            metadata: Metadata::default(),
+            color_space: ColorSpace::default(),
             has_end: false,
+            limits: *limits,
+            lenient: false,
+            decoded: None,
+            metadata_len: 0,
         }
     }
 
-    fn filter_scanline(&self, prev: &[u8], sl: &mut [u8], filter_method: FilterType) {
-        let bpp = self.bpp;
-        let scanline_len = self.scanline_len();
+    pub(super) fn limits(&self) -> &Limits {
+        &self.limits
+    }
 
-        match filter_method {
-            FilterType::None => {
+    pub fn width(&self) -> u32 {
+        self.ihdr.as_ref().unwrap().width
+    }
 
-            }
-            FilterType::Sub => {
-                for i in bpp..scanline_len {
-                    sl[i] = sl[i].overflowing_add(sl[i-bpp]).0;
-                }
-            }
-            FilterType::Up => {
-                for i in 0..scanline_len {
-                    sl[i] = sl[i].overflowing_add(prev[i]).0;
-                }
-            }
-            FilterType::Average => {
-                for i in 0..bpp {
-                    sl[i] = sl[i].overflowing_add(prev[i].div(2)).0;
-                }
+    pub fn height(&self) -> u32 {
+        self.ihdr.as_ref().unwrap().height
+    }
 
-                for i in bpp..scanline_len {
-                    sl[i] = sl[i].overflowing_add((sl[i-bpp] as u16 + prev[i] as u16).div(2) as u8).0;
-                }
-            }
-            FilterType::Paeth => {
-                for i in 0..bpp {
-                    sl[i] = sl[i].overflowing_add(peath_predictor(0, prev[i] as i16, 0)).0;
-                }
+    pub fn bit_depth(&self) -> u8 {
+        self.ihdr.as_ref().unwrap().bit_depth
+    }
 
-                for i in bpp..scanline_len {
-                    sl[i] = sl[i].overflowing_add(peath_predictor(sl[i - bpp] as i16, prev[i] as i16, prev[i - bpp] as i16)).0;
-                }
-            }
+    pub fn color_type(&self) -> ColorType {
+        self.ihdr.as_ref().unwrap().color_type
+    }
+
+    pub(super) fn idat(&self) -> &[u8] {
+        &self.idat
+    }
+
+    /// Offset of the first IDAT chunk, to locate errors in the image data
+    pub(super) fn idat_offset(&self) -> u64 {
+        self.idat_offset.unwrap_or(0)
+    }
+
+    pub(super) fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+
+    pub(super) fn has_header(&self) -> bool {
+        self.ihdr.is_some()
+    }
+
+    /// Text, time, pixel density and Exif data read so far
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Gamma, chromaticities, sRGB intent and ICC profile read so far
+    pub fn color_space(&self) -> &ColorSpace {
+        &self.color_space
+    }
+
+    /// Header properties of the image
+    pub fn info(&self) -> ImageInfo {
+        let ihdr = self.ihdr.as_ref().unwrap();
+        let color_type = match ihdr.color_type {
+            ColorType::GrayScale => ImageColorType::Gray,
+            ColorType::GrayScaleAlpha => ImageColorType::GrayAlpha,
+            ColorType::TrueColor => ImageColorType::RGB,
+            ColorType::TrueColorAlpha => ImageColorType::RGBA,
+            ColorType::IndexedColor => ImageColorType::Indexed,
+        };
+
+        ImageInfo {
+            format: ImageFormat::Png,
+            width: ihdr.width,
+            height: ihdr.height,
+            color_type,
+            bit_depth: ihdr.bit_depth,
+            interlaced: ihdr.interlace_method == 1,
+            metadata: None,
         }
     }
 
-    fn nb_pixels(&self) -> u32 {
+    pub fn is_interlaced(&self) -> bool {
+        self.ihdr.as_ref().unwrap().interlace_method == 1
+    }
+
+    fn nb_pixels(&self) -> u64 {
         let ihdr = self.ihdr.as_ref().unwrap();
 
-        return ihdr.height * ihdr.width;
+        return ihdr.height as u64 * ihdr.width as u64;
     }
 
     fn scanline_nb_pixel_components(&self) -> u32 {
@@ -203,40 +348,259 @@ impl PngImage {
         }
     }
 
-    fn calculate_bpp(&mut self) {
-        self.bpp = self.scanline_nb_pixel_components() as usize;
+    pub(super) fn bits_per_pixel(&self) -> usize {
+        self.scanline_nb_pixel_components() as usize * self.bit_depth() as usize
+    }
+
+    // Filters work on whole bytes, sub-byte pixels use the previous byte
+    pub(super) fn calculate_bpp(&mut self) {
+        self.bpp = self.bits_per_pixel().div_ceil(8).max(1);
+    }
+
+    /// Size of the decompressed image data, filter type bytes included
+    fn image_data_len(&self) -> u64 {
+        let rows_len = |width: u32, height: u32| {
+            if width == 0 || height == 0 { 0 } else { (self.scanline_len(width) as u64 + 1) * height as u64 }
+        };
+
+        if ! self.is_interlaced() {
+            return rows_len(self.width(), self.height());
+        }
+        ADAM7_PASSES.iter()
+            .map(|&(x0, y0, dx, dy)| rows_len(adam7_pass_size(self.width(), x0, dx), adam7_pass_size(self.height(), y0, dy)))
+            .sum()
     }
 
     fn scanline_pixel_data_size(&self) -> usize {
-        self.nb_pixels() as usize * self.scanline_nb_pixel_components() as usize
+        (self.nb_pixels() * self.scanline_nb_pixel_components() as u64) as usize
     }
 
-    fn scanline_len(&self) -> usize {
-        return self.ihdr.as_ref().unwrap().width as usize * self.scanline_nb_pixel_components() as usize;
+    pub(super) fn scanline_len(&self, width: u32) -> usize {
+        (width as usize * self.bits_per_pixel()).div_ceil(8)
     }
-    
-    fn decode_scanlines(&self) -> Vec<u8> {
-        let mut decoded = decompress_to_vec_zlib(self.idat.as_slice()).unwrap();
-        let scanline_len = self.scanline_len() as usize;
 
+    pub(super) fn unpack_scanline(&self, sl: &[u8], width: u32, out: &mut Vec<u16>) {
+        let nb_samples = width as usize * self.scanline_nb_pixel_components() as usize;
+
+        match self.bit_depth() {
+            16 => {
+                for s in sl.chunks_exact(2).take(nb_samples) {
+                    out.push(u16::from_be_bytes([s[0], s[1]]));
+                }
+            }
+            8 => {
+                out.extend(sl.iter().take(nb_samples).map(|b| *b as u16));
+            }
+            depth => {
+                let per_byte = 8 / depth as usize;
+                let mask = (1u8 << depth) - 1;
+
+                for i in 0..nb_samples.min(sl.len() * per_byte) {
+                    let byte = sl[i / per_byte];
+                    let shift = 8 - depth as usize * (i % per_byte + 1);
+                    out.push(((byte >> shift) & mask) as u16);
+                }
+            }
+        }
+    }
 
-        let mut ret: Vec<u8> = Vec::with_capacity(self.scanline_pixel_data_size());
-        let sl0: Vec<u8> = vec!(0u8; self.scanline_pixel_data_size());
+    /// Unfilter `height` scanlines of `width` pixels from the start of `data`.
+    /// Returns the unfiltered scanlines and the number of bytes consumed.
+    /// In lenient mode the scanlines before a missing or damaged one are
+    /// returned, the problem is added to `problems` and all data is consumed.
+    fn decode_scanlines(&self, data: &mut [u8], width: u32, height: u32, problems: &mut Vec<DecodingError>) -> Result<(Vec<u8>, usize), ImageError> {
+        let scanline_len = self.scanline_len(width);
+        let mut consumed = (scanline_len + 1) * height as usize;
+        if data.len() < consumed {
+            if ! self.lenient {
+                return Err(ImageError::decoding(DecodingErrorKind::TruncatedImageData));
+            }
+            problems.push(ImageError::decoding(DecodingErrorKind::TruncatedImageData).into_diagnostic("IDAT", self.idat_offset()));
+            consumed = data.len() - data.len() % (scanline_len + 1);
+        }
 
-        let mut prev_scanline: &[u8] = sl0.as_ref();
+        let mut ret: Vec<u8> = Vec::with_capacity(scanline_len * height as usize);
+        let sl0: Vec<u8> = vec!(0u8; scanline_len);
 
-        for scanline in decoded.chunks_mut(scanline_len + 1) {
-            let filter_method = FilterType::from_u8(scanline[0]);
+        let mut prev_scanline: &[u8] = sl0.as_ref();
+        let data_len = data.len();
+
+        for scanline in data[..consumed].chunks_mut(scanline_len + 1) {
+            let filter_method = match FilterType::from_u8(scanline[0]) {
+                Ok(filter_method) => filter_method,
+                Err(e) if self.lenient => {
+                    problems.push(e.into_diagnostic("IDAT", self.idat_offset()));
+                    return Ok((ret, data_len));
+                }
+                Err(e) => return Err(e),
+            };
             let sl = scanline[1..].as_mut();
             
             debug!("method: {:?}", filter_method);
             
-            self.filter_scanline(prev_scanline, sl, filter_method);
+            unfilter_scanline(self.bpp, prev_scanline, sl, filter_method);
             ret.extend_from_slice(sl);
             prev_scanline = sl;
         }
 
-        return ret;
+        if consumed < (scanline_len + 1) * height as usize {
+            consumed = data_len;
+        }
+        Ok((ret, consumed))
+    }
+
+    /// Decoded samples at the image bit depth, in the PNG channel order.
+    /// Sub-byte samples are unpacked but not rescaled, so indexed images
+    /// yield palette indices and 16 bits images keep their full precision.
+    /// Images read in lenient mode have the pixels that could not be decoded
+    /// set to the background color.
+    pub fn samples(&self) -> Result<Vec<u16>, ImageError> {
+        if let Some(decoded) = &self.decoded {
+            return Ok(decoded.clone());
+        }
+        self.decode_samples(&mut Vec::new()).map_err(|e| e.in_chunk("IDAT", self.idat_offset()))
+    }
+
+    fn decode_samples(&self, problems: &mut Vec<DecodingError>) -> Result<Vec<u16>, ImageError> {
+        let mut decoded = if self.lenient {
+            self.inflate_partial(problems)?
+        } else {
+            match self.limits.max_decoded_bytes {
+                None => zlib::decompress(self.idat.as_slice())?,
+                Some(max) => zlib::decompress_with_limit(self.idat.as_slice(), usize::try_from(max).unwrap_or(usize::MAX))?
+                    .ok_or(ImageError::decoding(DecodingErrorKind::LimitsExceeded { limit: "decoded size", max }))?,
+            }
+        };
+        let width = self.width();
+        let height = self.height();
+
+        if self.is_interlaced() {
+            return self.deinterlace(&mut decoded, problems);
+        }
+
+        let scanline_len = self.scanline_len(width);
+        let (img, _) = self.decode_scanlines(&mut decoded, width, height, problems)?;
+
+        let mut ret: Vec<u16> = Vec::with_capacity(self.scanline_pixel_data_size());
+        for sl in img.chunks(scanline_len) {
+            self.unpack_scanline(sl, width, &mut ret);
+        }
+
+        // missing rows of a damaged image
+        let background = self.background_pixel();
+        while ret.len() < self.scanline_pixel_data_size() {
+            ret.extend_from_slice(&background);
+        }
+
+        Ok(ret)
+    }
+
+    /// Inflate the image data up to its expected size, keeping what could be
+    /// decoded before an error
+    fn inflate_partial(&self, problems: &mut Vec<DecodingError>) -> Result<Vec<u8>, ImageError> {
+        let expected = self.image_data_len();
+        self.limits.check_decoded_bytes(expected)?;
+
+        // output of a failing call is lost, so at most one row is asked for
+        let step = self.scanline_len(self.width()) + 1;
+        let mut ret: Vec<u8> = vec!(0; expected as usize);
+        let mut filled = 0;
+        let result = zlib::ZlibDecoder::new(self.idat.as_slice()).and_then(|mut zlib| {
+            while filled < ret.len() {
+                let end = ret.len().min(filled + step);
+                let n = zlib.decompress_into(&mut ret[filled..end])?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            problems.push(e.into_diagnostic("IDAT", self.idat_offset()));
+        }
+        ret.truncate(filled);
+        Ok(ret)
+    }
+
+    /// Samples of a pixel of the background color, white without bKGD
+    fn background_pixel(&self) -> Vec<u16> {
+        let (r, g, b) = self.background.unwrap_or((255, 255, 255));
+        let max = ((1u32 << self.bit_depth()) - 1) as u16;
+        let scale = |c: u8| (c as u32 * max as u32 / 255) as u16;
+
+        match self.color_type() {
+            ColorType::GrayScale => vec!(scale(r)),
+            ColorType::GrayScaleAlpha => vec!(scale(r), max),
+            ColorType::TrueColor => vec!(scale(r), scale(g), scale(b)),
+            ColorType::TrueColorAlpha => vec!(scale(r), scale(g), scale(b), max),
+            ColorType::IndexedColor => {
+                let index = self.color_index.as_ref().and_then(|index| index.iter().position(|c| *c == (r, g, b)));
+                vec!(index.unwrap_or(0) as u16)
+            }
+        }
+    }
+
+    /// Reconstruct the seven Adam7 passes, each pass is a reduced image
+    /// unfiltered on its own, then scattered into the final buffer.
+    fn deinterlace(&self, decoded: &mut [u8], problems: &mut Vec<DecodingError>) -> Result<Vec<u16>, ImageError> {
+        let width = self.width();
+        let height = self.height();
+        let components = self.scanline_nb_pixel_components() as usize;
+
+        // pixels of passes missing from a damaged image keep the background
+        let mut ret: Vec<u16> = if self.lenient {
+            self.background_pixel().iter().copied().cycle().take(self.scanline_pixel_data_size()).collect()
+        } else {
+            vec!(0; self.scanline_pixel_data_size())
+        };
+        let mut data = decoded;
+
+        for (pass, &(x0, y0, dx, dy)) in ADAM7_PASSES.iter().enumerate() {
+            let pass_width = adam7_pass_size(width, x0, dx);
+            let pass_height = adam7_pass_size(height, y0, dy);
+            if pass_width == 0 || pass_height == 0 {
+                continue;
+            }
+            debug!("adam7 pass {}: {}x{}", pass + 1, pass_width, pass_height);
+
+            let (img, consumed) = self.decode_scanlines(data, pass_width, pass_height, problems)?;
+            data = &mut data[consumed..];
+            let complete = img.len() == self.scanline_len(pass_width) * pass_height as usize;
+
+            let scanline_len = self.scanline_len(pass_width);
+            let mut pass_samples: Vec<u16> = Vec::with_capacity(pass_width as usize * components);
+            for (j, sl) in img.chunks(scanline_len).enumerate() {
+                pass_samples.clear();
+                self.unpack_scanline(sl, pass_width, &mut pass_samples);
+
+                let y = (y0 + j as u32 * dy) as usize;
+                for (i, pixel) in pass_samples.chunks(components).enumerate() {
+                    let x = (x0 + i as u32 * dx) as usize;
+                    let offset = (y * width as usize + x) * components;
+                    ret[offset..offset + pixel.len()].copy_from_slice(pixel);
+                }
+            }
+            if ! complete {
+                break;
+            }
+        }
+
+        Ok(ret)
+    }
+
+    fn samples_u8(&self, samples: &[u16]) -> Vec<u8> {
+        let bit_depth = self.bit_depth();
+
+        if self.color_type() == ColorType::IndexedColor {
+            return samples.iter().map(|s| *s as u8).collect();
+        }
+        samples.iter().map(|s| scale_to_u8(*s, bit_depth)).collect()
+    }
+
+    fn samples_nb_pixels(&self, samples: &[u16]) -> usize {
+        samples.len() / self.scanline_nb_pixel_components() as usize
     }
 
     fn alpha_coeff(&self, alpha: u8) -> f32 {
@@ -247,17 +611,92 @@ impl PngImage {
         (alpha_coeff * component_a as f32 + (1.0 - alpha_coeff) * component_b as f32) as u8
     }
 
-    fn decode_to_rgb(&self) -> Vec<u8> {
-        let img = self.decode_scanlines();
+    fn decode_to_rgba(&self, samples: &[u16]) -> Result<Vec<u8>, ImageError> {
+        let bit_depth = self.bit_depth();
+        let mut ret: Vec<u8> = Vec::with_capacity(self.samples_nb_pixels(samples) * 4);
+
+        match self.ihdr.as_ref().unwrap().color_type {
+            ColorType::IndexedColor => {
+                let index = self.color_index.as_ref().ok_or(ImageError::decoding(DecodingErrorKind::MissingPalette))?;
+                let alphas: &[u8] = match &self.transparency {
+                    Some(Transparency::Indexed(alphas)) => alphas,
+                    _ => &[],
+                };
+
+                for s in samples.iter().copied() {
+                    let (r, g, b) = index.get(s as usize).ok_or(ImageError::decoding(DecodingErrorKind::InvalidPaletteIndex(s as u8)))?;
+                    ret.extend_from_slice(&[*r, *g, *b, *alphas.get(s as usize).unwrap_or(&255)]);
+                }
+            }
+            ColorType::GrayScale => {
+                let key = match self.transparency {
+                    Some(Transparency::GrayScale(key)) => Some(key),
+                    _ => None,
+                };
+
+                for s in samples.iter().copied() {
+                    let g = scale_to_u8(s, bit_depth);
+                    let alpha = if key == Some(s) { 0 } else { 255 };
+                    ret.extend_from_slice(&[g, g, g, alpha]);
+                }
+            }
+            ColorType::TrueColor => {
+                let key = match self.transparency {
+                    Some(Transparency::TrueColor(r, g, b)) => Some([r, g, b]),
+                    _ => None,
+                };
+
+                for s in samples.chunks(3) {
+                    let alpha = if key.as_ref().map(|k| &k[..]) == Some(s) { 0 } else { 255 };
+                    ret.extend_from_slice(&[scale_to_u8(s[0], bit_depth), scale_to_u8(s[1], bit_depth), scale_to_u8(s[2], bit_depth), alpha]);
+                }
+            }
+            ColorType::GrayScaleAlpha => {
+                for s in samples.chunks(2) {
+                    let g = scale_to_u8(s[0], bit_depth);
+                    ret.extend_from_slice(&[g, g, g, scale_to_u8(s[1], bit_depth)]);
+                }
+            }
+            ColorType::TrueColorAlpha => {
+                ret.extend(samples.iter().map(|s| scale_to_u8(*s, bit_depth)));
+            }
+        }
+        Ok(ret)
+    }
+
+    fn blend_background(&self, rgba: &[u8]) -> Vec<u8> {
+        let mut ret: Vec<u8> = Vec::with_capacity(rgba.len() / 4 * 3);
+        let back = self.background.unwrap_or((255, 255, 255));
+
+        for b in rgba.chunks(4) {
+            let alpha: f32 = self.alpha_coeff(b[3]);
+            if alpha == 1.0 {
+                ret.extend_from_slice(&b[..3]);
+            } else {
+                ret.push(self.alpha_blend(b[0], back.0, alpha));
+                ret.push(self.alpha_blend(b[1], back.1, alpha));
+                ret.push(self.alpha_blend(b[2], back.2, alpha));
+            }
+        }
+        ret
+    }
+
+    fn decode_to_rgb(&self, samples: &[u16]) -> Result<Vec<u8>, ImageError> {
+        if self.transparency.is_some() {
+            return Ok(self.blend_background(&self.decode_to_rgba(samples)?));
+        }
+
+        let img = self.samples_u8(samples);
+        let nb_pixels = self.samples_nb_pixels(samples);
         let mut ret: Vec<u8>;
 
         match self.ihdr.as_ref().unwrap().color_type {
             ColorType::IndexedColor => {
-                //TODO: tRNS chunk transparency
-                ret = map_indexed_color(self.color_index.as_ref().unwrap(), &img, (self.nb_pixels() * 3) as usize);
+                let index = self.color_index.as_ref().ok_or(ImageError::decoding(DecodingErrorKind::MissingPalette))?;
+                ret = map_indexed_color(index, &img, nb_pixels * 3)?;
             }
             ColorType::TrueColorAlpha => {
-                ret = Vec::with_capacity((self.nb_pixels() * 3) as usize);
+                ret = Vec::with_capacity(nb_pixels * 3);
                 let back = self.background.unwrap_or((255, 255, 255));
 
                 for b in img.chunks(4) {
@@ -277,7 +716,7 @@ impl PngImage {
                 ret = img;
             }
             ColorType::GrayScale => {
-                ret = Vec::with_capacity((self.nb_pixels() * 3) as usize);
+                ret = Vec::with_capacity(nb_pixels * 3);
 
                 for b in img {
                     ret.push(b);
@@ -286,7 +725,7 @@ impl PngImage {
                 }
             }
             ColorType::GrayScaleAlpha => {
-                ret = Vec::with_capacity((self.nb_pixels() * 3) as usize);
+                ret = Vec::with_capacity(nb_pixels * 3);
                 let back = self.background.unwrap_or((255, 255, 255));
 
                 for b in img.chunks(2) {
@@ -303,14 +742,86 @@ impl PngImage {
                 }
             }
         }
-        return ret;
+        return Ok(ret);
     }
-}
 
-impl GenericImageTo for PngImage {
-    fn to_rgb(&self) -> Result<GenericImage, ImageError> {
+    fn decode_to_rgb_with(&self, samples: &[u16], options: &ConvertOptions) -> Result<Vec<u8>, ImageError> {
+        match options.alpha {
+            AlphaMode::Composite => self.decode_to_rgb(samples),
+            AlphaMode::Drop => {
+                let rgba = self.decode_to_rgba(samples)?;
+                Ok(rgba.chunks(4).flat_map(|p| p[..3].iter().copied()).collect())
+            }
+        }
+    }
+
+    /// Convert decoded samples, of the whole image or of some rows, to 8 bits
+    /// per channel `colors`. `transform` comes from `conversion_transform`,
+    /// built once per conversion.
+    pub(super) fn convert_samples(&self, samples: &[u16], colors: GenericImageColors, options: &ConvertOptions, transform: Option<&Transform>) -> Result<Vec<u8>, ImageError> {
+        match colors {
+            GenericImageColors::RGB => {
+                let mut rgb = self.decode_to_rgb_with(samples, options)?;
+                if let Some(transform) = transform {
+                    transform.apply_rgb(&mut rgb, 3);
+                }
+                Ok(rgb)
+            }
+            GenericImageColors::RGBA => {
+                let mut rgba = self.decode_to_rgba(samples)?;
+                if let Some(transform) = transform {
+                    transform.apply_rgb(&mut rgba, 4);
+                }
+                Ok(rgba)
+            }
+            GenericImageColors::G => {
+                let opaque_gray = self.color_type() == ColorType::GrayScale && self.transparency.is_none();
+                if opaque_gray {
+                    let mut gray = self.samples_u8(samples);
+                    if let Some(transform) = transform {
+                        transform.apply_gray(&mut gray, 1);
+                    }
+                    return Ok(gray);
+                }
+                let rgb = self.convert_samples(samples, GenericImageColors::RGB, options, transform)?;
+                Ok(rgb.chunks(3).map(|p| options.luma.luma(p[0], p[1], p[2])).collect())
+            }
+        }
+    }
+
+    /// Transform applied by a conversion with `options`, None when the
+    /// colors are kept as stored
+    pub(super) fn conversion_transform(&self, options: &ConvertOptions) -> Option<Transform> {
+        if options.to_srgb { self.srgb_transform() } else { None }
+    }
+
+    /// Transform of the declared color space to sRGB, the primaries of
+    /// gray images are ignored
+    fn srgb_transform(&self) -> Option<Transform> {
+        let mut transform = self.color_space.srgb_transform()?;
+        if matches!(self.color_type(), ColorType::GrayScale | ColorType::GrayScaleAlpha) {
+            transform.matrix = None;
+        }
+        Some(transform)
+    }
+
+    /// Decode the image row by row, each row is unfiltered and converted to
+    /// RGB as with `to_rgb`. Only the current rows are kept in memory, except
+    /// for interlaced images and images read in lenient mode, which are
+    /// decoded as a whole first.
+    pub fn rows(&self) -> Result<PngRows<'_>, ImageError> {
+        self.rows_with(GenericImageColors::RGB, &ConvertOptions::default())
+    }
+
+    /// Same as `rows`, converting to `colors` with the given options
+    pub fn rows_with(&self, colors: GenericImageColors, options: &ConvertOptions) -> Result<PngRows<'_>, ImageError> {
+        PngRows::new(self, colors, options)
+    }
+
+    pub fn to_rgb_with(&self, options: &ConvertOptions) -> Result<GenericImage, ImageError> {
         let ihdr = self.ihdr.as_ref().unwrap();
-        let data = self.decode_to_rgb();
+        let transform = self.conversion_transform(options);
+        let data = self.convert_samples(&self.samples()?, GenericImageColors::RGB, options, transform.as_ref())?;
         let ret: GenericImage = GenericImage {
             data,
             colors: GenericImageColors::RGB,
@@ -319,27 +830,170 @@ impl GenericImageTo for PngImage {
         };
         return Ok(ret);
     }
-    
+
+    pub fn to_g_with(&self, options: &ConvertOptions) -> Result<GenericImage, ImageError> {
+        let transform = self.conversion_transform(options);
+        let data = self.convert_samples(&self.samples()?, GenericImageColors::G, options, transform.as_ref())?;
+
+        Ok(GenericImage {
+            data,
+            colors: GenericImageColors::G,
+            height: self.height(),
+            width: self.width(),
+        })
+    }
+}
+
+impl GenericImageTo for PngImage {
+    fn to_rgb(&self) -> Result<GenericImage, ImageError> {
+        self.to_rgb_with(&ConvertOptions::default())
+    }
+
     fn to_rgba(&self) -> Result<GenericImage, ImageError> {
-        todo!();
-    } 
+        let data = self.convert_samples(&self.samples()?, GenericImageColors::RGBA, &ConvertOptions::default(), None)?;
+        Ok(GenericImage {
+            data,
+            colors: GenericImageColors::RGBA,
+            height: self.height(),
+            width: self.width(),
+        })
+    }
+
     fn to_g(&self) -> Result<GenericImage, ImageError> {
-        todo!();
+        self.to_g_with(&ConvertOptions::default())
     }
 }
 
 impl<R: Read> ReadImage<R> for PngImage {
-    fn read_image(mut reader: R) -> Result<Box<Self>, ImageError> {
-        let mut data: Vec<u8> = Vec::new();
-        reader.read_to_end(&mut data)?;
+    fn read_image_with_limits(mut reader: R, limits: &Limits) -> Result<Box<Self>, ImageError> {
+        debug!("Parsing png");
+        stream::read_signature(&mut reader)?;
+        let mut image = PngImage::with_limits(limits);
+
+        let mut offset = PNG_SIGNATURE.len() as u64;
+        while let Some(header) = stream::read_chunk_header(&mut reader, offset)? {
+            stream::read_chunk(&mut reader, header, offset, &mut image)?;
+            offset += stream::chunk_size(header);
+        }
+        debug!("End of parsing");
+
+        if image.ihdr.is_none() {
+            return Err(ImageError::decoding(DecodingErrorKind::MissingIHDR));
+        }
+        Ok(Box::new(image))
+    }
+}
+
+impl PngImage {
+    /// Read a possibly damaged image, recovering what can be. Bad crcs,
+    /// broken ancillary chunks, a truncated stream, a missing IEND and data
+    /// after it are reported as diagnostics instead of failing. Rows that
+    /// cannot be decoded are filled with the background color, here and in
+    /// every later conversion of the image.
+    pub fn read_image_lenient<R: Read>(reader: R, limits: &Limits) -> Result<(Box<PngImage>, Vec<DecodingError>), ImageError> {
+        let mut diagnostics: Vec<DecodingError> = Vec::new();
+        let image = PngImage::read_checked(reader, limits, None, &mut diagnostics)?;
+        Ok((Box::new(image), diagnostics))
+    }
+
+    /// Read an image that must follow the specification, see `validate`.
+    /// The first problem found is returned as error.
+    pub fn read_image_strict<R: Read>(reader: R, limits: &Limits) -> Result<Box<PngImage>, ImageError> {
+        let mut problems: Vec<DecodingError> = Vec::new();
+        let mut image = PngImage::read_checked(reader, limits, Some(ChunkValidator::new()), &mut problems)?;
+        if ! problems.is_empty() {
+            return Err(ImageError::Decoding(problems.remove(0)));
+        }
+        image.lenient = false;
+        Ok(Box::new(image))
+    }
+
+    /// Lenient reading, the problems found are added to `diagnostics`, with
+    /// the broken ordering rules when a validator is given
+    pub(super) fn read_checked<R: Read>(mut reader: R, limits: &Limits, mut validator: Option<ChunkValidator>, diagnostics: &mut Vec<DecodingError>) -> Result<PngImage, ImageError> {
+        stream::read_signature(&mut reader)?;
+        let mut image = PngImage::with_limits(limits);
+        image.lenient = true;
+
+        let mut offset = PNG_SIGNATURE.len() as u64;
+        loop {
+            let header = match stream::read_chunk_header(&mut reader, offset) {
+                Ok(Some(header)) => header,
+                Ok(None) => break,
+                Err(ImageError::Decoding(e)) => {
+                    diagnostics.push(e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let name = stream::chunk_name(header);
+
+            image.limits.check_chunk_size(header.0).map_err(|e| e.in_chunk(&name, offset))?;
+            let (raw, complete) = stream::read_partial_chunk(&mut reader, header).map_err(|e| e.in_chunk(&name, offset))?;
+            if ! complete {
+                diagnostics.push(ImageError::decoding(DecodingErrorKind::TruncatedChunk).into_diagnostic(&name, offset));
+                if name == "IDAT" && raw.len() > 8 {
+                    let data = &raw[8..raw.len().min(8 + header.0 as usize)];
+                    image.handle_chunk(Chunk { len: data.len() as u32, name: "IDAT", data, crc: 0 }, offset)
+                        .map_err(|e| e.in_chunk(&name, offset))?;
+                }
+                break;
+            }
+
+            let (_, chunk) = parse_chunk_unchecked(&raw).map_err(|e| e.in_chunk(&name, offset))?;
+            let computed = chunk.computed_crc();
+            if computed != chunk.crc {
+                let e = ImageError::decoding(DecodingErrorKind::CrcMismatch { expected: chunk.crc, computed });
+                diagnostics.push(e.into_diagnostic(&name, offset));
+            }
+            if let Some(validator) = validator.as_mut() {
+                for kind in validator.check(&name, chunk.data) {
+                    diagnostics.push(ImageError::decoding(kind).into_diagnostic(&name, offset));
+                }
+            }
+
+            let critical = name.starts_with(|c: char| c.is_ascii_uppercase());
+            match image.handle_chunk(chunk, offset) {
+                Ok(()) => {}
+                Err(e) if critical || e.is_limits_exceeded() => return Err(e.in_chunk(&name, offset)),
+                Err(e) => diagnostics.push(e.into_diagnostic(&name, offset)),
+            }
+            offset += stream::chunk_size(header);
 
-        let image: PngImage = parse_png(data.as_ref())?.1;
-        return Ok(Box::new(image));
+            if image.has_end {
+                let mut byte = [0u8; 1];
+                if reader.read(&mut byte)? > 0 {
+                    diagnostics.push(ImageError::decoding(DecodingErrorKind::TrailingData).into_diagnostic("IEND", offset));
+                }
+                break;
+            }
+        }
+
+        if image.ihdr.is_none() {
+            return Err(ImageError::decoding(DecodingErrorKind::MissingIHDR));
+        }
+        if let Some(validator) = validator.as_ref() {
+            for kind in validator.finish() {
+                diagnostics.push(ImageError::decoding(kind).into_diagnostic("IEND", offset));
+            }
+        }
+        if ! image.has_end {
+            diagnostics.push(ImageError::decoding(DecodingErrorKind::MissingIEND).into_diagnostic("IEND", offset));
+        }
+        if image.idat.is_empty() {
+            diagnostics.push(ImageError::decoding(DecodingErrorKind::MissingImageData).into_diagnostic("IDAT", offset));
+        } else {
+            // decode once to find the problems of the image data, and keep
+            // the result for the conversions
+            image.decoded = Some(image.decode_samples(diagnostics)?);
+        }
+
+        Ok(image)
     }
 }
 
 fn parse_idat(idat_chunk: Chunk) -> Result<IDAT, ImageError> {
-    assert_eq!(idat_chunk.name, "IDAT");
+    debug_assert_eq!(idat_chunk.name, "IDAT");
     
     let idat = IDAT {
         data: idat_chunk.data,
@@ -349,14 +1003,18 @@ fn parse_idat(idat_chunk: Chunk) -> Result<IDAT, ImageError> {
 }
 
 fn parse_iend(iend_chunk: Chunk) -> Result<bool, ImageError> {
-    assert_eq!(iend_chunk.name, "IEND");
-    assert_eq!(iend_chunk.len, 0);
+    debug_assert_eq!(iend_chunk.name, "IEND");
+    if iend_chunk.len != 0 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(iend_chunk.len)));
+    }
     return Ok(true);
 }
 
 fn parse_ihdr(ihdr_chunk: Chunk) -> Result<IHDR, ImageError> {
-    assert_eq!(ihdr_chunk.name, "IHDR");
-    assert_eq!(ihdr_chunk.len, 13);
+    debug_assert_eq!(ihdr_chunk.name, "IHDR");
+    if ihdr_chunk.len != 13 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(ihdr_chunk.len)));
+    }
 
     let (
         _i,(
@@ -369,34 +1027,40 @@ fn parse_ihdr(ihdr_chunk: Chunk) -> Result<IHDR, ImageError> {
         interlace_method)
     ) = tuple((be_u32, be_u32, u8, u8, u8, u8, u8))(ihdr_chunk.data)?;
 
-    if ! [1, 2, 4, 8, 16].contains(&bit_depth) {
-        return Err(ImageError::Decoding(DecodingError {str: format!("Wrong bit depht {}", bit_depth)}));
+    if width == 0 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidHeaderField { field: "width", value: 0 }));
+    }
+    if height == 0 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidHeaderField { field: "height", value: 0 }));
     }
-    if bit_depth != 8 { //TODO: remove
-        return Err(ImageError::Decoding(DecodingError {str: format!("Unsupported bit deph {}", bit_depth)}));
+    if ! [1, 2, 4, 8, 16].contains(&bit_depth) {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidHeaderField { field: "bit depth", value: bit_depth as u32 }));
     }
-    if ! [0, 2, 3, 4, 6].contains(&color_type) {
-        return Err(ImageError::Decoding(DecodingError {str: format!("Unknown color type {}", color_type)}));
+    let color = ColorType::from_u8(color_type)?;
+    let allowed_depths: &[u8] = match color_type {
+        0 => &[1, 2, 4, 8, 16],
+        3 => &[1, 2, 4, 8],
+        _ => &[8, 16],
+    };
+    if ! allowed_depths.contains(&bit_depth) {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidHeaderField { field: "bit depth", value: bit_depth as u32 }));
     }
     if ! [0, 1].contains(&interlace_method) {
-        return Err(ImageError::Decoding(DecodingError {str: format!("Unknown interlace method {}", interlace_method)}));
-    }
-    if interlace_method != 0 { //TODO: remove
-        return Err(ImageError::Decoding(DecodingError {str: format!("Unsupported interlace method {}", interlace_method)}));
+        return Err(ImageError::decoding(DecodingErrorKind::Unsupported(format!("interlace method {}", interlace_method))));
     }
     if compression_method != 0 {
-        return Err(ImageError::Decoding(DecodingError {str: format!("Unknown compression method {}", compression_method)}));
+        return Err(ImageError::decoding(DecodingErrorKind::Unsupported(format!("compression method {}", compression_method))));
     }
 
     if filter_method != 0 {
-        return Err(ImageError::Decoding(DecodingError {str: format!("Unknown filter method {}", filter_method)}));
+        return Err(ImageError::decoding(DecodingErrorKind::Unsupported(format!("filter method {}", filter_method))));
     }
     
     let ihdr = IHDR {
         width,
         height,
         bit_depth,
-        color_type: ColorType::from_u8(color_type),
+        color_type: color,
         compression_method,
         filter_method,
         interlace_method,
@@ -405,62 +1069,172 @@ fn parse_ihdr(ihdr_chunk: Chunk) -> Result<IHDR, ImageError> {
     return Ok(ihdr);
 }
 
-fn parse_text(text_chunk: Chunk) -> Result<&str, ImageError> {
-    assert_eq!(text_chunk.name, "tEXt");
+/// Latin-1 maps each byte to the code point of the same value
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Keyword and the rest of a text chunk
+fn parse_keyword(data: &[u8]) -> Result<(&[u8], String), ImageError> {
+    let (r, keyword) = terminated(take_while(|b: u8| b != 0), tag([0x0]))(data)?;
+    Ok((r, latin1(keyword)))
+}
+
+/// Inflate the data of a zTXt, iTXt or iCCP chunk to at most `max_len`
+/// bytes, `limit` names what is limited
+fn inflate_chunk(compression_method: u8, data: &[u8], max_len: u64, limit: &'static str) -> Result<Vec<u8>, ImageError> {
+    if compression_method != 0 {
+        return Err(ImageError::decoding(DecodingErrorKind::Unsupported(format!("compression method {}", compression_method))));
+    }
+    zlib::decompress_with_limit(data, usize::try_from(max_len).unwrap_or(usize::MAX))?
+        .ok_or(ImageError::decoding(DecodingErrorKind::LimitsExceeded { limit, max: max_len }))
+}
 
-    let text = str::from_utf8(text_chunk.data)?;
-    info!("text: {}", text);
+/// Keyword and Latin-1 text of a tEXt chunk
+fn parse_text(text_chunk: Chunk) -> Result<TextEntry, ImageError> {
+    debug_assert_eq!(text_chunk.name, "tEXt");
+    let (text, keyword) = match parse_keyword(text_chunk.data) {
+        Ok((r, keyword)) => (latin1(r), keyword),
+        // a keyword without text
+        Err(_) => (String::new(), latin1(text_chunk.data)),
+    };
+    info!("text {}: {}", keyword, text);
 
-    return Ok(&text);
+    Ok(TextEntry::new(&keyword, &text))
 }
 
-fn parse_ztxt(text_chunk: Chunk) -> Result<(&str, String), ImageError> {
-    assert_eq!(text_chunk.name, "zTXt");
-    let (r, keyword) = terminated(take_while(|b: u8| b != 0), tag([0x0]))(text_chunk.data)?;
+/// Keyword and Latin-1 text of a zTXt chunk, whose text must not inflate
+/// to more than `max_len` bytes
+fn parse_ztxt(text_chunk: Chunk, max_len: u64) -> Result<TextEntry, ImageError> {
+    debug_assert_eq!(text_chunk.name, "zTXt");
+    let (r, keyword) = parse_keyword(text_chunk.data)?;
     let (r, compression_method) = u8(r)?;
 
-    assert_eq!(compression_method, 0);
-    let keyword_utf = str::from_utf8(keyword)?;
+    let text = latin1(&inflate_chunk(compression_method, r, max_len, "decompressed text size")?);
+    info!("ztxt {}: {}", keyword, text);
 
-    let decoded = decompress_to_vec_zlib(r)?;
-    let text = String::from_utf8(decoded)?;
-    info!("ztxt {}: {}", keyword_utf, text);
+    Ok(TextEntry { compressed: true, ..TextEntry::new(&keyword, &text) })
+}
 
-    return Ok((&keyword_utf, text));
+/// International text of an iTXt chunk, whose text must not inflate to
+/// more than `max_len` bytes
+fn parse_itxt(text_chunk: Chunk, max_len: u64) -> Result<TextEntry, ImageError> {
+    debug_assert_eq!(text_chunk.name, "iTXt");
+    let (r, keyword) = parse_keyword(text_chunk.data)?;
+    let (r, (compression_flag, compression_method)) = tuple((u8, u8))(r)?;
+    let (r, language) = terminated(take_while(|b: u8| b != 0), tag([0x0]))(r)?;
+    let (r, translated_keyword) = terminated(take_while(|b: u8| b != 0), tag([0x0]))(r)?;
+
+    let text = match compression_flag {
+        0 => String::from_utf8(r.to_vec())?,
+        1 => String::from_utf8(inflate_chunk(compression_method, r, max_len, "decompressed text size")?)?,
+        _ => return Err(ImageError::decoding(DecodingErrorKind::InvalidChunk(format!("compression flag {}", compression_flag)))),
+    };
+    let language = str::from_utf8(language)?;
+    let translated_keyword = str::from_utf8(translated_keyword)?;
+    info!("itxt {} [{}] {}: {}", keyword, language, translated_keyword, text);
+
+    Ok(TextEntry {
+        keyword,
+        text,
+        language: Some(language.to_string()).filter(|l| !l.is_empty()),
+        translated_keyword: Some(translated_keyword.to_string()).filter(|t| !t.is_empty()),
+        compressed: compression_flag == 1,
+    })
 }
 
-fn parse_phys(chunk: Chunk) -> Result<(u32, u32, bool), ImageError> {
-    assert_eq!(chunk.name, "pHYs");
-    assert_eq!(chunk.len, 9);
+fn parse_phys(chunk: Chunk) -> Result<PixelDensity, ImageError> {
+    debug_assert_eq!(chunk.name, "pHYs");
+    if chunk.len != 9 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(chunk.len)));
+    }
     let (r, ppux) = be_u32(chunk.data)?;
     let (r, ppuy) = be_u32(r)?;
     let (_r, unit) = be_u8(r)?;
 
-    if unit == 0 {
-        info!("phys: ppuX: {} ppuY: {}", ppux, ppuy);
-    } else if unit == 1 {
-        info!("phys: ppuX: {}m ppuY: {}m", ppux, ppuy);
-    } else {
-        panic!("unknown unit");
+    let unit = match unit {
+        0 => DensityUnit::Unknown,
+        1 => DensityUnit::Meter,
+        _ => return Err(ImageError::decoding(DecodingErrorKind::UnknownUnit(unit))),
+    };
+    info!("phys: ppuX: {} ppuY: {} {:?}", ppux, ppuy, unit);
+
+    Ok(PixelDensity { x: ppux, y: ppuy, unit })
+}
+
+fn parse_gama(chunk: Chunk) -> Result<f64, ImageError> {
+    debug_assert_eq!(chunk.name, "gAMA");
+    if chunk.len != 4 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(chunk.len)));
     }
+    let (_r, gamma) = be_u32(chunk.data)?;
+    if gamma == 0 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunk("gamma of 0".to_string())));
+    }
+    info!("gamma: {}", gamma);
+
+    Ok(gamma as f64 / 100000.0)
+}
+
+fn parse_chrm(chunk: Chunk) -> Result<Chromaticities, ImageError> {
+    debug_assert_eq!(chunk.name, "cHRM");
+    if chunk.len != 32 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(chunk.len)));
+    }
+    let (_r, v) = count(be_u32, 8)(chunk.data)?;
+    let xy = |i: usize| (v[i] as f64 / 100000.0, v[i + 1] as f64 / 100000.0);
+    if xy(0).1 == 0.0 || xy(2).1 == 0.0 || xy(4).1 == 0.0 || xy(6).1 == 0.0 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunk("chromaticity with y of 0".to_string())));
+    }
+    let chromaticities = Chromaticities { white: xy(0), red: xy(2), green: xy(4), blue: xy(6) };
+    info!("chromaticities: {:?}", chromaticities);
 
-    Ok((ppux, ppuy, unit == 1))
+    Ok(chromaticities)
 }
 
-fn parse_time(chunk: Chunk) -> Result<(u16, u8, u8, u8, u8, u8), ImageError> {
-    assert_eq!(chunk.name, "tIME");
-    assert_eq!(chunk.len, 7);
+fn parse_srgb(chunk: Chunk) -> Result<RenderingIntent, ImageError> {
+    debug_assert_eq!(chunk.name, "sRGB");
+    if chunk.len != 1 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(chunk.len)));
+    }
+    let intent = chunk.data[0];
+    RenderingIntent::from_u8(intent)
+        .ok_or(ImageError::decoding(DecodingErrorKind::InvalidChunk(format!("rendering intent {}", intent))))
+}
+
+/// Name and profile of an iCCP chunk, whose profile must not inflate to
+/// more than `max_len` bytes
+fn parse_iccp(chunk: Chunk, max_len: u64) -> Result<IccProfile, ImageError> {
+    debug_assert_eq!(chunk.name, "iCCP");
+    let (r, name) = parse_keyword(chunk.data)?;
+    let (r, compression_method) = u8(r)?;
+
+    let data = inflate_chunk(compression_method, r, max_len, "decompressed profile size")?;
+    info!("icc profile {}: {} bytes", name, data.len());
+
+    Ok(IccProfile { name, data })
+}
+
+fn parse_time(chunk: Chunk) -> Result<Timestamp, ImageError> {
+    debug_assert_eq!(chunk.name, "tIME");
+    if chunk.len != 7 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(chunk.len)));
+    }
 
     let (_, (year, month, day, hour, minute, second)) = tuple((be_u16, u8, u8, u8, u8, u8))(chunk.data)?;
 
-    info!("time: {}-{:#02}-{:#02} {:#02}:{:#02}:{:#02}", year, month, day, hour, minute, second);
+    let time = Timestamp::new(year, month, day, hour, minute, second)
+        .ok_or(ImageError::decoding(DecodingErrorKind::InvalidChunk(format!("invalid time {}-{}-{} {}:{}:{}", year, month, day, hour, minute, second))))?;
+    info!("time: {}", time);
 
-    Ok((year, month, day, hour, minute, second))
+    Ok(time)
 }
 
 fn parse_plte(chunk: Chunk) -> Result<Vec<(u8, u8, u8)>, ImageError> {
-    assert_eq!(chunk.name, "PLTE");
-    assert_eq!(chunk.len % 3, 0);
+    debug_assert_eq!(chunk.name, "PLTE");
+    if ! chunk.len.is_multiple_of(3) {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(chunk.len)));
+    }
     let nb_colors = chunk.len / 3;
 
     let (_, colors) = count(tuple((u8, u8, u8)), nb_colors as usize)(chunk.data)?;
@@ -470,19 +1244,52 @@ fn parse_plte(chunk: Chunk) -> Result<Vec<(u8, u8, u8)>, ImageError> {
     Ok(colors)
 }
 
-fn parse_bkgd(chunk: Chunk, color_type: ColorType, indexed_colors: &Option<Vec<(u8, u8 , u8)>>) -> Result<(u8, u8, u8), ImageError> {
-    assert_eq!(chunk.name, "bKGD");
+fn parse_trns(chunk: Chunk, ihdr: &IHDR, indexed_colors: &Option<Vec<(u8, u8, u8)>>) -> Result<Transparency, ImageError> {
+    debug_assert_eq!(chunk.name, "tRNS");
+
+    let ret = match ihdr.color_type {
+        ColorType::IndexedColor => {
+            // one alpha value at most per palette entry, a tRNS chunk before
+            // PLTE is reported by the validator
+            let entries = indexed_colors.as_ref().map_or(usize::MAX, Vec::len);
+            if chunk.data.len() > entries {
+                return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(chunk.len)));
+            }
+            Transparency::Indexed(chunk.data.to_vec())
+        }
+        ColorType::GrayScale => {
+            let (_, gray) = be_u16(chunk.data)?;
+            Transparency::GrayScale(gray)
+        }
+        ColorType::TrueColor => {
+            let (_, (r, g, b)) = tuple((be_u16, be_u16, be_u16))(chunk.data)?;
+            Transparency::TrueColor(r, g, b)
+        }
+        ColorType::GrayScaleAlpha | ColorType::TrueColorAlpha => {
+            return Err(ImageError::decoding(DecodingErrorKind::InvalidChunk("tRNS chunk is not allowed for images with an alpha channel".to_string())));
+        }
+    };
+
+    info!("trns: {:?}", ret);
+
+    Ok(ret)
+}
+
+fn parse_bkgd(chunk: Chunk, ihdr: &IHDR, indexed_colors: &Option<Vec<(u8, u8 , u8)>>) -> Result<(u8, u8, u8), ImageError> {
+    debug_assert_eq!(chunk.name, "bKGD");
 
     let ret: (u8, u8, u8);
+    let bit_depth = ihdr.bit_depth;
 
-    match color_type {
+    match ihdr.color_type {
         ColorType::TrueColor | ColorType::TrueColorAlpha => {
-            let (_, colors) = take(3 as usize)(chunk.data)?;
-            ret = (colors[0], colors[1], colors[2]);
+            let (_, (r, g, b)) = tuple((be_u16, be_u16, be_u16))(chunk.data)?;
+            ret = (scale_to_u8(r, bit_depth), scale_to_u8(g, bit_depth), scale_to_u8(b, bit_depth));
         }
         ColorType::GrayScale | ColorType::GrayScaleAlpha => {
-            let (_, color) = take(1 as usize)(chunk.data)?;
-            ret = (color[0], color[0], color[0]);
+            let (_, color) = be_u16(chunk.data)?;
+            let gray = scale_to_u8(color, bit_depth);
+            ret = (gray, gray, gray);
         }
         ColorType::IndexedColor => {
             let (_, color_index) = be_u8(chunk.data)?;
@@ -505,7 +1312,19 @@ fn parse_bkgd(chunk: Chunk, color_type: ColorType, indexed_colors: &Option<Vec<(
 }
 
 
-fn parse_chunk(chunk: &[u8]) -> Result<(&[u8], Chunk), ImageError> {
+pub(super) fn parse_chunk(chunk: &[u8]) -> Result<(&[u8], Chunk<'_>), ImageError> {
+    let (r, chunk) = parse_chunk_unchecked(chunk)?;
+
+    let computed = chunk.computed_crc();
+    if computed != chunk.crc {
+        return Err(ImageError::decoding(DecodingErrorKind::CrcMismatch { expected: chunk.crc, computed }));
+    }
+
+    Ok((r, chunk))
+}
+
+/// Same as `parse_chunk` without checking the crc
+fn parse_chunk_unchecked(chunk: &[u8]) -> Result<(&[u8], Chunk<'_>), ImageError> {
     let (r, len): (&[u8], u32) = be_u32(chunk)?;
     let (r, name_bytes): (&[u8], &[u8]) = take(4 as u32)(r)?;
     let (r, data): (&[u8], &[u8]) = take(len)(r)?;
@@ -517,83 +1336,492 @@ fn parse_chunk(chunk: &[u8]) -> Result<(&[u8], Chunk), ImageError> {
 
     let chunk = Chunk { len, name, data, crc };
 
-    if ! chunk.check_crc() {
-        return Err(ImageError::Decoding(DecodingError::new("Chunk crc error")));
-    }
-
     Ok((r, chunk))
 }
 
-fn parse_png(chunk: &[u8]) -> Result<(&[u8], PngImage), ImageError> {
-    debug!("Parsing png");
-    let (r, _) = tag([0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A])(chunk)?;
-    let mut suite = r;
-    let mut image = PngImage::new();
+impl PngImage {
+    /// Add the content of the chunk read at `offset` to the image, IDAT data
+    /// is appended
+    pub(super) fn handle_chunk(&mut self, chunk: Chunk, offset: u64) -> Result<(), ImageError> {
+        if self.ihdr.is_none() && chunk.name != "IHDR" {
+            return Err(ImageError::decoding(DecodingErrorKind::ChunkBeforeIHDR));
+        }
 
-    //TODO: check for mandatory chunks
-    while suite.len() > 0 {
-        let p = parse_chunk(suite)?;
-        match p.1.name {
+        match chunk.name {
             "IHDR" => {
-                image.ihdr = Some(parse_ihdr(p.1)?);
-                image.calculate_bpp();
-                info!("IHDR: {:?}", image.ihdr);
+                let ihdr = parse_ihdr(chunk)?;
+                self.limits.check_dimensions(ihdr.width, ihdr.height)?;
+                self.ihdr = Some(ihdr);
+                self.calculate_bpp();
+                self.limits.check_decoded_bytes(self.image_data_len())?;
+                info!("IHDR: {:?}", self.ihdr);
             }
             "IDAT" => {
-                image.idat.extend(parse_idat(p.1)?.data);
+                self.idat_offset.get_or_insert(offset);
+                self.idat.extend(parse_idat(chunk)?.data);
                 info!("IDAT: new chunk added");
             }
             "PLTE" => {
-                image.color_index = Some(parse_plte(p.1)?);
+                self.color_index = Some(parse_plte(chunk)?);
             }
             "IEND" => {
-                image.has_end = true;
-                info!("IEND: {}", parse_iend(p.1)?);
+                self.has_end = true;
+                info!("IEND: {}", parse_iend(chunk)?);
             }
             "bKGD" => {
-                let c: ColorType = image.ihdr.as_ref().unwrap().color_type;
-                let bcolor: (u8, u8, u8) = parse_bkgd(p.1, c, &image.color_index)?;
-                image.background = Some(bcolor);
-            }
-            "tEXt" => {
-                let txt = parse_text(p.1);
-                match txt {
-                    Err(e) => error!("Cannot parse tEXt chunk: {:?}", e),
-                    Ok(t) => warn!("Do someting with tEXt: {}", t)
-                }
+                let ihdr = self.ihdr.as_ref().ok_or(ImageError::decoding(DecodingErrorKind::MissingIHDR))?;
+                let bcolor: (u8, u8, u8) = parse_bkgd(chunk, ihdr, &self.color_index)?;
+                self.background = Some(bcolor);
+            }
+            "tRNS" => {
+                let ihdr = self.ihdr.as_ref().ok_or(ImageError::decoding(DecodingErrorKind::MissingIHDR))?;
+                self.transparency = Some(parse_trns(chunk, ihdr, &self.color_index)?);
             }
-            "zTXt" => {
-                let ztxt = parse_ztxt(p.1);
-                match ztxt {
-                    Err(e) => error!("Cannot parse zTXt chunk: {:?}", e),
-                    Ok(_t) => warn!("Do someting with zTXt")
+            "tEXt" | "zTXt" | "iTXt" => {
+                if let Some(text) = self.read_text(chunk)? {
+                    self.metadata.texts.push(text);
                 }
             }
             "pHYs" => {
-                let phys = parse_phys(p.1);
-                match phys {
+                match parse_phys(chunk) {
                     Err(e) => error!("Cannot parse pHYs chunk: {:?}", e),
-                    Ok(_t) => warn!("Do someting with pHYs")
+                    Ok(density) => self.metadata.density = Some(density),
                 }
             }
             "tIME" => {
-                let time = parse_time(p.1);
-                match time {
+                match parse_time(chunk) {
                     Err(e) => error!("Cannot parse tIME chunk: {:?}", e),
-                    Ok(_t) => warn!("Do someting with tIME")
-                }                
+                    Ok(time) => self.metadata.time = Some(time),
+                }
+            }
+            "gAMA" => {
+                match parse_gama(chunk) {
+                    Err(e) => error!("Cannot parse gAMA chunk: {:?}", e),
+                    Ok(gamma) => self.color_space.gamma = Some(gamma),
+                }
+            }
+            "cHRM" => {
+                match parse_chrm(chunk) {
+                    Err(e) => error!("Cannot parse cHRM chunk: {:?}", e),
+                    Ok(chromaticities) => self.color_space.chromaticities = Some(chromaticities),
+                }
+            }
+            "sRGB" => {
+                match parse_srgb(chunk) {
+                    Err(e) => error!("Cannot parse sRGB chunk: {:?}", e),
+                    Ok(intent) => self.color_space.srgb = Some(intent),
+                }
+            }
+            "iCCP" => {
+                let max_len = self.limits.max_metadata_bytes.map_or(u64::MAX, |max| max.saturating_sub(self.metadata_len));
+                match parse_iccp(chunk, max_len) {
+                    Err(e) if e.is_limits_exceeded() => return Err(e),
+                    Err(e) => error!("Cannot parse iCCP chunk: {:?}", e),
+                    Ok(profile) => {
+                        self.metadata_len += (profile.name.len() + profile.data.len()) as u64;
+                        self.limits.check_metadata_bytes(self.metadata_len)?;
+                        self.color_space.icc_profile = Some(profile);
+                    }
+                }
+            }
+            "eXIf" => {
+                self.metadata_len += chunk.len as u64;
+                self.limits.check_metadata_bytes(self.metadata_len)?;
+                match Exif::parse(chunk.data) {
+                    Err(e) => error!("Cannot parse eXIf chunk: {:?}", e),
+                    Ok(exif) => self.metadata.exif = Some(exif),
+                }
             }
             name => {
                 warn!("no parsing for chunk: {}", name);
                 let first_letter: char = name.chars().nth(0).unwrap();
                 if first_letter.is_ascii_uppercase() {
-                    return Err(ImageError::Decoding(DecodingError { str: format!("No parsing for mandotary chunk {}", name)}));
+                    return Err(ImageError::decoding(DecodingErrorKind::Unsupported(format!("critical chunk {}", name))));
                 }
             } 
         }
 
-        suite = p.0;
+        Ok(())
+    }
+
+    /// Keyword and text of a tEXt, zTXt or iTXt chunk, counted in the metadata
+    /// limit. Malformed text is logged and skipped.
+    pub(super) fn read_text(&mut self, chunk: Chunk) -> Result<Option<TextEntry>, ImageError> {
+        let name = chunk.name;
+        let max_len = self.limits.max_metadata_bytes.map_or(u64::MAX, |max| max.saturating_sub(self.metadata_len));
+        let text = match name {
+            "tEXt" => parse_text(chunk),
+            "zTXt" => parse_ztxt(chunk, max_len),
+            _ => parse_itxt(chunk, max_len),
+        };
+
+        match text {
+            Ok(text) => {
+                let optional_len = |s: &Option<String>| s.as_ref().map_or(0, |s| s.len());
+                self.metadata_len += (text.keyword.len() + text.text.len() + optional_len(&text.language) + optional_len(&text.translated_keyword)) as u64;
+                self.limits.check_metadata_bytes(self.metadata_len)?;
+                Ok(Some(text))
+            }
+            Err(e) if e.is_limits_exceeded() => Err(e),
+            Err(e) => {
+                error!("Cannot parse {} chunk: {:?}", name, e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// PNG file made of `chunks`, as (name, data), with valid CRCs
+#[cfg(test)]
+pub(super) fn build_png(chunks: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut out = PNG_SIGNATURE.to_vec();
+    for (name, data) in chunks {
+        let mut hasher = Crc32::new();
+        hasher.update(name.as_bytes());
+        hasher.update(data);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+        out.extend_from_slice(&hasher.finalise().to_be_bytes());
+    }
+    out
+}
+
+#[test]
+fn test_scale_to_u8() {
+    assert_eq!(scale_to_u8(1, 1), 255);
+    assert_eq!(scale_to_u8(0, 1), 0);
+    assert_eq!(scale_to_u8(2, 2), 170);
+    assert_eq!(scale_to_u8(0xf, 4), 255);
+    assert_eq!(scale_to_u8(0x7, 4), 119);
+    assert_eq!(scale_to_u8(0x80, 8), 0x80);
+    assert_eq!(scale_to_u8(0xffff, 16), 255);
+    assert_eq!(scale_to_u8(0x8080, 16), 0x80);
+}
+
+#[test]
+fn test_bit_depths() {
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+
+    let decode = |width: u8, height: u8, bit_depth: u8, color_type: u8, plte: &[u8], raw: &[u8]| {
+        let ihdr = [0, 0, 0, width, 0, 0, 0, height, bit_depth, color_type, 0, 0, 0];
+        let idat = compress_to_vec_zlib(raw, 6);
+        let mut chunks: Vec<(&str, &[u8])> = vec!(("IHDR", &ihdr));
+        if ! plte.is_empty() {
+            chunks.push(("PLTE", plte));
+        }
+        chunks.extend_from_slice(&[("IDAT", &idat), ("IEND", &[])]);
+        PngImage::read_image(&build_png(&chunks)[..]).unwrap()
+    };
+    let gray_to_rgb = |gray: &[u8]| gray.iter().flat_map(|&g| vec!(g, g, g)).collect::<Vec<u8>>();
+
+    // 3 pixels wide, the padding bits of the last byte are set and ignored
+    let image = decode(3, 2, 1, 0, &[], &[0, 0b1010_0000, 0, 0b0101_1111]);
+    assert_eq!(image.samples().unwrap(), vec!(1, 0, 1, 0, 1, 0));
+    assert_eq!(image.to_rgb().unwrap().data, gray_to_rgb(&[255, 0, 255, 0, 255, 0]));
+    let image = decode(3, 2, 2, 0, &[], &[0, 0b0001_1011, 0, 0b1110_0100]);
+    assert_eq!(image.samples().unwrap(), vec!(0, 1, 2, 3, 2, 1));
+    assert_eq!(image.to_rgb().unwrap().data, gray_to_rgb(&[0, 85, 170, 255, 170, 85]));
+    let image = decode(3, 1, 4, 0, &[], &[0, 0x07, 0xfa]);
+    assert_eq!(image.to_rgb().unwrap().data, gray_to_rgb(&[0, 119, 255]));
+
+    let plte: &[u8] = &[10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120];
+    let image = decode(3, 2, 1, 3, plte, &[0, 0b1010_0000, 0, 0b0101_1111]);
+    assert_eq!(image.to_rgb().unwrap().data, vec!(40, 50, 60, 10, 20, 30, 40, 50, 60, 10, 20, 30, 40, 50, 60, 10, 20, 30));
+    let image = decode(3, 1, 2, 3, plte, &[0, 0b1110_0100]);
+    assert_eq!(image.to_rgb().unwrap().data, vec!(100, 110, 120, 70, 80, 90, 40, 50, 60));
+    let image = decode(3, 1, 4, 3, plte, &[0, 0x30, 0x2f]);
+    assert_eq!(image.samples().unwrap(), vec!(3, 0, 2));
+    assert_eq!(image.to_rgb().unwrap().data, vec!(100, 110, 120, 10, 20, 30, 70, 80, 90));
+
+    // 16 bits samples are kept whole, and rounded when converted
+    let image = decode(2, 1, 16, 0, &[], &[0, 0x00, 0x00, 0x80, 0x80]);
+    assert_eq!(image.samples().unwrap(), vec!(0, 0x8080));
+    assert_eq!(image.to_rgb().unwrap().data, gray_to_rgb(&[0, 128]));
+    let image = decode(2, 1, 16, 2, &[], &[0, 0xff, 0xff, 0x80, 0x80, 0x00, 0x00, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+    assert_eq!(image.samples().unwrap(), vec!(0xffff, 0x8080, 0, 0x1234, 0x5678, 0x9abc));
+    assert_eq!(image.to_rgb().unwrap().data, vec!(255, 128, 0, 18, 86, 154));
+}
+
+#[test]
+fn test_adam7_pass_size() {
+    let sizes: Vec<(u32, u32)> = ADAM7_PASSES.iter()
+        .map(|&(x0, y0, dx, dy)| (adam7_pass_size(8, x0, dx), adam7_pass_size(8, y0, dy)))
+        .collect();
+    assert_eq!(sizes, vec!((1, 1), (1, 1), (2, 1), (2, 2), (4, 2), (4, 4), (8, 4)));
+
+    assert_eq!(adam7_pass_size(1, 4, 8), 0);
+    assert_eq!(adam7_pass_size(5, 4, 8), 1);
+    assert_eq!(adam7_pass_size(13, 1, 2), 6);
+}
+
+#[test]
+fn test_adam7() {
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+
+    // 3x3 gray image, only passes 1, 4, 5, 6 and 7 hold pixels
+    let ihdr: &[u8] = &[0, 0, 0, 3, 0, 0, 0, 3, 8, 0, 0, 0, 1];
+    let raw: &[u8] = &[
+        0, 10,
+        0, 30,
+        0, 70, 90,
+        0, 20, 0, 80,
+        0, 40, 50, 60,
+    ];
+    let idat = compress_to_vec_zlib(raw, 6);
+    let image = PngImage::read_image(&build_png(&[("IHDR", ihdr), ("IDAT", &idat), ("IEND", &[])])[..]).unwrap();
+    assert_eq!(image.samples().unwrap(), vec!(10, 20, 30, 40, 50, 60, 70, 80, 90));
+    assert_eq!(image.to_rgb().unwrap().data[..9], [10, 10, 10, 20, 20, 20, 30, 30, 30]);
+}
+
+#[test]
+fn test_conversions() {
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+
+    let decode = |color_type: u8, plte: &[u8], raw: &[u8]| {
+        let ihdr = [0, 0, 0, 1, 0, 0, 0, 1, 8, color_type, 0, 0, 0];
+        let idat = compress_to_vec_zlib(raw, 6);
+        let mut chunks: Vec<(&str, &[u8])> = vec!(("IHDR", &ihdr));
+        if ! plte.is_empty() {
+            chunks.push(("PLTE", plte));
+        }
+        chunks.extend_from_slice(&[("IDAT", &idat), ("IEND", &[])]);
+        PngImage::read_image(&build_png(&chunks)[..]).unwrap()
+    };
+    let drop = ConvertOptions { alpha: AlphaMode::Drop, ..ConvertOptions::default() };
+
+    let image = decode(0, &[], &[0, 200]);
+    assert_eq!(image.to_rgba().unwrap().data, vec!(200, 200, 200, 255));
+    assert_eq!(image.to_g().unwrap().data, vec!(200));
+
+    let image = decode(2, &[], &[0, 255, 0, 0]);
+    assert_eq!(image.to_rgba().unwrap().data, vec!(255, 0, 0, 255));
+    assert_eq!(image.to_g().unwrap().data, vec!(77));
+
+    let image = decode(3, &[0, 0, 255], &[0, 0]);
+    assert_eq!(image.to_rgba().unwrap().data, vec!(0, 0, 255, 255));
+    assert_eq!(image.to_g().unwrap().data, vec!(29));
+
+    // translucent pixels, blended against white unless the alpha is dropped
+    let image = decode(4, &[], &[0, 100, 128]);
+    assert_eq!(image.to_rgba().unwrap().data, vec!(100, 100, 100, 128));
+    assert_eq!(image.to_g().unwrap().data, vec!(177));
+    assert_eq!(image.to_g_with(&drop).unwrap().data, vec!(100));
+    assert_eq!(image.to_rgb().unwrap().data, vec!(177, 177, 177));
+    assert_eq!(image.to_rgb_with(&drop).unwrap().data, vec!(100, 100, 100));
+
+    let image = decode(6, &[], &[0, 255, 0, 0, 128]);
+    assert_eq!(image.to_rgba().unwrap().data, vec!(255, 0, 0, 128));
+    assert_eq!(image.to_g().unwrap().data, vec!(165));
+    assert_eq!(image.to_g_with(&drop).unwrap().data, vec!(77));
+    assert_eq!(image.to_rgb().unwrap().data, vec!(255, 126, 126));
+    assert_eq!(image.to_rgb_with(&drop).unwrap().data, vec!(255, 0, 0));
+}
+
+#[cfg(test)]
+fn kind<T>(r: Result<T, ImageError>) -> Option<DecodingErrorKind> {
+    r.err().and_then(|e| e.decoding_kind().cloned())
+}
+
+#[test]
+fn test_malformed_chunks() {
+    let chunk = |name: &'static str, data: &'static [u8]| Chunk { len: data.len() as u32, name, data, crc: 0 };
+
+    assert_eq!(kind(parse_ihdr(chunk("IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0]))), Some(DecodingErrorKind::InvalidChunkLength(12)));
+    assert_eq!(kind(parse_ihdr(chunk("IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 5, 0, 0, 0]))), Some(DecodingErrorKind::InvalidHeaderField { field: "color type", value: 5 }));
+    assert_eq!(kind(parse_ihdr(chunk("IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 1, 0, 0]))), Some(DecodingErrorKind::Unsupported("compression method 1".to_string())));
+    assert_eq!(kind(parse_ihdr(chunk("IHDR", &[0, 0, 0, 0, 0, 0, 0, 1, 8, 2, 0, 0, 0]))), Some(DecodingErrorKind::InvalidHeaderField { field: "width", value: 0 }));
+    assert_eq!(kind(parse_ihdr(chunk("IHDR", &[0, 0, 0, 1, 0, 0, 0, 0, 8, 2, 0, 0, 0]))), Some(DecodingErrorKind::InvalidHeaderField { field: "height", value: 0 }));
+    assert!(parse_ihdr(chunk("IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0])).is_ok());
+
+    assert_eq!(kind(parse_iend(chunk("IEND", &[0]))), Some(DecodingErrorKind::InvalidChunkLength(1)));
+    assert_eq!(kind(parse_phys(chunk("pHYs", &[0, 0, 0, 1, 0, 0, 0, 1, 2]))), Some(DecodingErrorKind::UnknownUnit(2)));
+    assert_eq!(kind(parse_time(chunk("tIME", &[7, 230, 1, 1]))), Some(DecodingErrorKind::InvalidChunkLength(4)));
+    assert_eq!(kind(parse_time(chunk("tIME", &[7, 230, 13, 1, 0, 0, 0]))), Some(DecodingErrorKind::InvalidChunk("invalid time 2022-13-1 0:0:0".to_string())));
+    assert_eq!(kind(parse_plte(chunk("PLTE", &[1, 2, 3, 4]))), Some(DecodingErrorKind::InvalidChunkLength(4)));
+    assert_eq!(kind(parse_ztxt(chunk("zTXt", b"key\0\x01"), u64::MAX)), Some(DecodingErrorKind::Unsupported("compression method 1".to_string())));
+
+    let hello = [b'k', b'e', b'y', 0, 0, 120, 218, 243, 72, 205, 201, 201, 87, 240, 64, 39, 21, 1, 105, 85, 8, 82];
+    let ztxt = Chunk { len: hello.len() as u32, name: "zTXt", data: &hello, crc: 0 };
+    assert_eq!(kind(parse_ztxt(ztxt, 23)), Some(DecodingErrorKind::LimitsExceeded { limit: "decompressed text size", max: 23 }));
+
+    assert_eq!(kind(FilterType::from_u8(5)), Some(DecodingErrorKind::UnknownFilterType(5)));
+    assert_eq!(kind(ColorType::from_u8(1)), Some(DecodingErrorKind::InvalidHeaderField { field: "color type", value: 1 }));
+    assert_eq!(kind(map_indexed_color(&vec!((0, 0, 0)), &vec!(0, 1), 6)), Some(DecodingErrorKind::InvalidPaletteIndex(1)));
+
+    assert_eq!(kind(parse_itxt(chunk("iTXt", b"key\0\x02\0\0\0text"), u64::MAX)), Some(DecodingErrorKind::InvalidChunk("compression flag 2".to_string())));
+    assert_eq!(kind(parse_itxt(chunk("iTXt", b"key\0\x01\x01\0\0"), u64::MAX)), Some(DecodingErrorKind::Unsupported("compression method 1".to_string())));
+    assert!(parse_itxt(chunk("iTXt", b"key\0\0\0en\0\xff\0"), u64::MAX).is_err());
+    assert!(parse_itxt(chunk("iTXt", b"key\0\0\0en"), u64::MAX).is_err());
+
+    assert_eq!(kind(parse_gama(chunk("gAMA", &[0, 0, 0, 0]))), Some(DecodingErrorKind::InvalidChunk("gamma of 0".to_string())));
+    assert_eq!(kind(parse_chrm(chunk("cHRM", &[0; 31]))), Some(DecodingErrorKind::InvalidChunkLength(31)));
+    assert_eq!(kind(parse_srgb(chunk("sRGB", &[4]))), Some(DecodingErrorKind::InvalidChunk("rendering intent 4".to_string())));
+    assert_eq!(kind(parse_iccp(chunk("iCCP", b"icc\0\x01"), u64::MAX)), Some(DecodingErrorKind::Unsupported("compression method 1".to_string())));
+
+    let mut image = PngImage::new();
+    assert_eq!(kind(image.handle_chunk(chunk("bKGD", &[0, 0]), 33)), Some(DecodingErrorKind::ChunkBeforeIHDR));
+    assert_eq!(kind(image.handle_chunk(chunk("tRNS", &[0, 0]), 33)), Some(DecodingErrorKind::ChunkBeforeIHDR));
+    assert_eq!(kind(PngImage::read_image(&PNG_SIGNATURE[..])), Some(DecodingErrorKind::MissingIHDR));
+}
+
+#[test]
+fn test_text_chunks() {
+    let chunk = |name: &'static str, data: &'static [u8]| Chunk { len: data.len() as u32, name, data, crc: 0 };
+
+    // tEXt and zTXt are Latin-1, not UTF-8
+    assert_eq!(parse_text(chunk("tEXt", b"Title\0Caf\xe9")).unwrap(), TextEntry::new("Title", "Caf\u{e9}"));
+    assert_eq!(parse_text(chunk("tEXt", b"Gr\xfc\xdfe")).unwrap(), TextEntry::new("Gr\u{fc}\u{df}e", ""));
+    let hello = [b'k', b'e', b'y', 0, 0, 120, 218, 243, 72, 205, 201, 201, 87, 240, 64, 39, 21, 1, 105, 85, 8, 82];
+    let ztxt = Chunk { len: hello.len() as u32, name: "zTXt", data: &hello, crc: 0 };
+    let hello_text = parse_ztxt(ztxt, u64::MAX).unwrap();
+    assert_eq!((hello_text.keyword.as_str(), hello_text.compressed, hello_text.language), ("key", true, None));
+
+    let itxt = parse_itxt(chunk("iTXt", "Title\0\0\0fr-CA\0Titre\0\u{c9}t\u{e9} \u{2600}".as_bytes()), u64::MAX).unwrap();
+    assert_eq!(itxt, TextEntry {
+        keyword: "Title".to_string(),
+        text: "\u{c9}t\u{e9} \u{2600}".to_string(),
+        language: Some("fr-CA".to_string()),
+        translated_keyword: Some("Titre".to_string()),
+        compressed: false,
+    });
+    assert_eq!(parse_itxt(chunk("iTXt", b"Comment\0\0\0\0\0"), u64::MAX).unwrap(), TextEntry::new("Comment", ""));
+
+    let mut compressed = b"key\0\x01\0en\0\0".to_vec();
+    compressed.extend_from_slice(&hello[5..]);
+    let itxt = Chunk { len: compressed.len() as u32, name: "iTXt", data: &compressed, crc: 0 };
+    let text = parse_itxt(itxt, u64::MAX).unwrap();
+    assert_eq!(text.text, hello_text.text);
+    assert_eq!((text.compressed, text.language.as_deref(), text.translated_keyword), (true, Some("en"), None));
+    let itxt = Chunk { len: compressed.len() as u32, name: "iTXt", data: &compressed, crc: 0 };
+    assert_eq!(kind(parse_itxt(itxt, 5)), Some(DecodingErrorKind::LimitsExceeded { limit: "decompressed text size", max: 5 }));
+}
+
+#[test]
+fn test_exif_chunk() {
+    use crate::exif::{build_exif, tags, ByteOrder, Orientation};
+
+    let gray: &[u8] = &[0, 0, 0, 2, 0, 0, 0, 1, 8, 0, 0, 0, 0];
+    let idat = zlib::compress(&[0, 10, 20], 6);
+    let exif = build_exif(ByteOrder::LittleEndian, &[(tags::ORIENTATION, 3, 1, vec!(0, 8))], &[], &[]);
+
+    let png = build_png(&[("IHDR", gray), ("eXIf", &exif), ("IDAT", &idat), ("IEND", &[])]);
+    let image = PngImage::read_image(&png[..]).unwrap();
+    assert_eq!(image.metadata().exif.as_ref().unwrap().orientation(), Some(Orientation::Rotate270));
+
+    // a malformed eXIf chunk is skipped
+    let png = build_png(&[("IHDR", gray), ("eXIf", b"MM\0*\0\0\0\xff"), ("IDAT", &idat), ("IEND", &[])]);
+    let image = PngImage::read_image(&png[..]).unwrap();
+    assert_eq!(image.metadata().exif, None);
+    assert_eq!(image.to_g().unwrap().data, vec!(10, 20));
+
+    let png = build_png(&[("IHDR", gray), ("eXIf", &exif), ("IDAT", &idat), ("IEND", &[])]);
+    let limits = Limits { max_metadata_bytes: Some(exif.len() as u64 - 1), ..Limits::default() };
+    let error = PngImage::read_image_with_limits(&png[..], &limits).unwrap_err();
+    assert!(error.is_limits_exceeded());
+    assert_eq!(error.chunk(), Some("eXIf"));
+}
+
+#[test]
+fn test_color_chunks() {
+    use crate::color::{D65, RenderingIntent};
+
+    let rgb: &[u8] = &[0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0];
+    let idat = zlib::compress(&[0, 0, 255, 0, 128, 128, 128], 6);
+    let chrm: Vec<u8> = [31270u32, 32900, 64000, 33000, 21000, 71000, 15000, 6000].iter().flat_map(|v| v.to_be_bytes().to_vec()).collect();
+    let mut iccp = b"Adobe RGB\0\0".to_vec();
+    iccp.extend(zlib::compress(&[7; 300], 6));
+
+    let png = build_png(&[("IHDR", rgb), ("gAMA", &[0, 0, 0xdb, 0xd0]), ("cHRM", &chrm), ("iCCP", &iccp), ("IDAT", &idat), ("IEND", &[])]);
+    let image = PngImage::read_image(&png[..]).unwrap();
+    let color_space = image.color_space();
+    assert_eq!(color_space.gamma, Some(0.56272));
+    assert_eq!(color_space.chromaticities.unwrap().white, D65);
+    assert_eq!(color_space.chromaticities.unwrap().green, (0.21, 0.71));
+    assert_eq!(color_space.srgb, None);
+    let profile = color_space.icc_profile.as_ref().unwrap();
+    assert_eq!((profile.name.as_str(), profile.data.len()), ("Adobe RGB", 300));
+
+    // the stored values by default, sRGB when asked
+    assert_eq!(image.to_rgb().unwrap().data, vec!(0, 255, 0, 128, 128, 128));
+    let options = ConvertOptions { to_srgb: true, ..ConvertOptions::default() };
+    assert_eq!(image.to_rgb_with(&options).unwrap().data, vec!(0, 255, 0, 147, 147, 147));
+    let mut rows = image.rows_with(GenericImageColors::RGB, &options).unwrap();
+    assert_eq!(rows.next_row().unwrap().unwrap().1, &[0, 255, 0, 147, 147, 147][..]);
+
+    let png = build_png(&[("IHDR", rgb), ("sRGB", &[1]), ("gAMA", &[0, 0, 0xb1, 0x8f]), ("IDAT", &idat), ("IEND", &[])]);
+    let image = PngImage::read_image(&png[..]).unwrap();
+    assert_eq!(image.color_space().srgb, Some(RenderingIntent::RelativeColorimetric));
+    assert_eq!(image.to_rgb_with(&options).unwrap().data, vec!(0, 255, 0, 128, 128, 128));
+
+    // gray images only use the gamma
+    let gray: &[u8] = &[0, 0, 0, 2, 0, 0, 0, 1, 8, 0, 0, 0, 0];
+    let gray_idat = zlib::compress(&[0, 128, 255], 6);
+    let png = build_png(&[("IHDR", gray), ("gAMA", &[0, 0, 0xdb, 0xd0]), ("cHRM", &chrm), ("IDAT", &gray_idat), ("IEND", &[])]);
+    let image = PngImage::read_image(&png[..]).unwrap();
+    assert_eq!(image.to_g_with(&options).unwrap().data, vec!(147, 255));
+    assert_eq!(image.to_rgb_with(&options).unwrap().data, vec!(147, 147, 147, 255, 255, 255));
+
+    // a valid profile takes precedence over gAMA
+    let mut iccp = b"sRGB\0\0".to_vec();
+    iccp.extend(zlib::compress(&crate::icc::srgb_profile(), 6));
+    let png = build_png(&[("IHDR", rgb), ("gAMA", &[0, 0, 0xdb, 0xd0]), ("iCCP", &iccp), ("IDAT", &idat), ("IEND", &[])]);
+    let image = PngImage::read_image(&png[..]).unwrap();
+    assert_eq!(image.to_rgb_with(&options).unwrap().data, vec!(0, 255, 0, 128, 128, 128));
+
+    let limits = Limits { max_metadata_bytes: Some(200), ..Limits::default() };
+    let png = build_png(&[("IHDR", rgb), ("iCCP", &iccp), ("IDAT", &idat), ("IEND", &[])]);
+    let error = PngImage::read_image_with_limits(&png[..], &limits).unwrap_err();
+    assert_eq!(error.decoding_kind(), Some(&DecodingErrorKind::LimitsExceeded { limit: "decompressed profile size", max: 200 }));
+}
+
+#[test]
+fn test_transparency() {
+    let decode = |chunks: &[(&str, &[u8])]| PngImage::read_image(&build_png(chunks)[..]);
+
+    // palette alpha, entries past the tRNS values are opaque
+    let indexed: &[u8] = &[0, 0, 0, 3, 0, 0, 0, 1, 8, 3, 0, 0, 0];
+    let plte: &[u8] = &[255, 0, 0, 0, 255, 0, 0, 0, 255];
+    let idat = zlib::compress(&[0, 0, 1, 2], 6);
+    let image = decode(&[("IHDR", indexed), ("PLTE", plte), ("tRNS", &[0, 128]), ("IDAT", &idat), ("IEND", &[])]).unwrap();
+    assert_eq!(image.to_rgba().unwrap().data, vec!(255, 0, 0, 0, 0, 255, 0, 128, 0, 0, 255, 255));
+    assert_eq!(image.to_rgb().unwrap().data, vec!(255, 255, 255, 126, 255, 126, 0, 0, 255));
+    let image = decode(&[("IHDR", indexed), ("PLTE", plte), ("tRNS", &[0, 128]), ("bKGD", &[2]), ("IDAT", &idat), ("IEND", &[])]).unwrap();
+    assert_eq!(image.to_rgb().unwrap().data, vec!(0, 0, 255, 0, 128, 126, 0, 0, 255));
+
+    let e = decode(&[("IHDR", indexed), ("PLTE", plte), ("tRNS", &[0, 0, 0, 0]), ("IDAT", &idat), ("IEND", &[])]).unwrap_err();
+    assert_eq!(e.decoding_kind(), Some(&DecodingErrorKind::InvalidChunkLength(4)));
+
+    // gray color key
+    let gray: &[u8] = &[0, 0, 0, 3, 0, 0, 0, 1, 8, 0, 0, 0, 0];
+    let idat = zlib::compress(&[0, 50, 100, 50], 6);
+    let image = decode(&[("IHDR", gray), ("tRNS", &[0, 50]), ("IDAT", &idat), ("IEND", &[])]).unwrap();
+    assert_eq!(image.to_rgba().unwrap().data, vec!(50, 50, 50, 0, 100, 100, 100, 255, 50, 50, 50, 0));
+    assert_eq!(image.to_rgb().unwrap().data, vec!(255, 255, 255, 100, 100, 100, 255, 255, 255));
+    let image = decode(&[("IHDR", gray), ("tRNS", &[0, 50]), ("bKGD", &[0, 0]), ("IDAT", &idat), ("IEND", &[])]).unwrap();
+    assert_eq!(image.to_rgb().unwrap().data, vec!(0, 0, 0, 100, 100, 100, 0, 0, 0));
+
+    // truecolor color key
+    let rgb: &[u8] = &[0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0];
+    let idat = zlib::compress(&[0, 1, 2, 3, 4, 5, 6], 6);
+    let image = decode(&[("IHDR", rgb), ("tRNS", &[0, 1, 0, 2, 0, 3]), ("IDAT", &idat), ("IEND", &[])]).unwrap();
+    assert_eq!(image.to_rgba().unwrap().data, vec!(1, 2, 3, 0, 4, 5, 6, 255));
+    assert_eq!(image.to_rgb().unwrap().data, vec!(255, 255, 255, 4, 5, 6));
+    let image = decode(&[("IHDR", rgb), ("tRNS", &[0, 1, 0, 2, 0, 3]), ("bKGD", &[0, 10, 0, 20, 0, 30]), ("IDAT", &idat), ("IEND", &[])]).unwrap();
+    assert_eq!(image.to_rgb().unwrap().data, vec!(10, 20, 30, 4, 5, 6));
+}
+
+#[test]
+fn test_checked_read_decodes_once() {
+    let gray: &[u8] = &[0, 0, 0, 2, 0, 0, 0, 1, 8, 0, 0, 0, 0];
+    let idat = zlib::compress(&[0, 10, 20], 6);
+    let png = build_png(&[("IHDR", gray), ("IDAT", &idat), ("IEND", &[])]);
+
+    let (mut lenient, _) = PngImage::read_image_lenient(&png[..], &Limits::default()).unwrap();
+    let mut strict = PngImage::read_image_strict(&png[..], &Limits::default()).unwrap();
+    for image in [&mut lenient, &mut strict] {
+        image.idat.clear();
+        assert_eq!(image.samples().unwrap(), vec!(10, 20));
+        assert_eq!(image.to_g().unwrap().data, vec!(10, 20));
     }
-    debug!("End of parsing");
-    Ok((r, image))
 }
@@ -0,0 +1,941 @@
+use std::io;
+use std::io::Read;
+
+use crate::error::*;
+use crate::image::{GenericImageColors, ConvertOptions, ImageInfo, Limits};
+use crate::color::Transform;
+use crate::compress::zlib::ZlibDecoder;
+use crate::hashs::crc32::Crc32;
+use super::png::{PngImage, FilterType, PNG_SIGNATURE, ADAM7_PASSES, adam7_pass_size, unfilter_scanline, parse_chunk};
+
+/// Chunk length and name
+pub(super) type ChunkHeader = (u32, [u8; 4]);
+
+pub(super) fn read_signature<R: Read>(reader: &mut R) -> Result<(), ImageError> {
+    let mut signature = [0u8; 8];
+    reader.read_exact(&mut signature)?;
+    if signature != PNG_SIGNATURE {
+        return Err(ImageError::decoding(DecodingErrorKind::BadSignature));
+    }
+    Ok(())
+}
+
+/// Number of bytes taken by a chunk in the file
+pub(super) fn chunk_size(header: ChunkHeader) -> u64 {
+    header.0 as u64 + 12
+}
+
+pub(super) fn chunk_name(header: ChunkHeader) -> String {
+    String::from_utf8_lossy(&header.1).into_owned()
+}
+
+/// Next chunk length and name, None at the end of the stream. `offset` is
+/// the position of the header, only used for errors.
+pub(super) fn read_chunk_header<R: Read>(reader: &mut R, offset: u64) -> Result<Option<ChunkHeader>, ImageError> {
+    let mut header = [0u8; 8];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => {
+                let mut e = DecodingError::new(DecodingErrorKind::TruncatedChunk);
+                e.offset = Some(offset);
+                return Err(ImageError::Decoding(e));
+            }
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let name = [header[4], header[5], header[6], header[7]];
+    Ok(Some((len, name)))
+}
+
+/// Read the data and crc of a chunk whose header was just read, the returned
+/// buffer holds the whole chunk as expected by `parse_chunk`
+pub(super) fn read_raw_chunk<R: Read>(reader: &mut R, header: ChunkHeader) -> Result<Vec<u8>, ImageError> {
+    match read_partial_chunk(reader, header)? {
+        (raw, true) => Ok(raw),
+        (_, false) => Err(ImageError::decoding(DecodingErrorKind::TruncatedChunk)),
+    }
+}
+
+/// Same as `read_raw_chunk`, a truncated chunk is returned as is along with
+/// false
+pub(super) fn read_partial_chunk<R: Read>(reader: &mut R, header: ChunkHeader) -> Result<(Vec<u8>, bool), ImageError> {
+    let (len, name) = header;
+    if len > i32::MAX as u32 {
+        return Err(ImageError::decoding(DecodingErrorKind::InvalidChunkLength(len)));
+    }
+
+    let mut raw: Vec<u8> = Vec::with_capacity(len as usize + 12);
+    raw.extend_from_slice(&len.to_be_bytes());
+    raw.extend_from_slice(&name);
+    reader.take(len as u64 + 4).read_to_end(&mut raw)?;
+
+    let complete = raw.len() == len as usize + 12;
+    Ok((raw, complete))
+}
+
+/// Read, check and add to `image` the chunk whose header was just read at
+/// `offset`, errors are located in that chunk
+pub(super) fn read_chunk<R: Read>(reader: &mut R, header: ChunkHeader, offset: u64, image: &mut PngImage) -> Result<(), ImageError> {
+    image.limits().check_chunk_size(header.0)
+        .and_then(|_| read_raw_chunk(reader, header))
+        .and_then(|raw| {
+            let (_, chunk) = parse_chunk(&raw)?;
+            image.handle_chunk(chunk, offset)
+        })
+        .map_err(|e| e.in_chunk(&chunk_name(header), offset))
+}
+
+/// Reads the data of consecutive IDAT chunks as one stream, checking the crc
+/// of each chunk. Ends at the first chunk that is not an IDAT, whose header is
+/// kept in `next`.
+struct IdatReader<R: Read> {
+    reader: R,
+    /// Offset of the current chunk
+    offset: u64,
+    len: u32,
+    remaining: u32,
+    hasher: Crc32,
+    next: Option<ChunkHeader>,
+    done: bool,
+}
+
+impl<R: Read> IdatReader<R> {
+    fn new(reader: R, header: ChunkHeader, offset: u64) -> Self {
+        let mut hasher = Crc32::new();
+        hasher.update(&header.1);
+
+        IdatReader {
+            reader,
+            offset,
+            len: header.0,
+            remaining: header.0,
+            hasher,
+            next: None,
+            done: false,
+        }
+    }
+
+    /// Offset of the chunk after the current one
+    fn next_offset(&self) -> u64 {
+        self.offset + self.len as u64 + 12
+    }
+
+    /// Error in the current chunk, carried through io::Error
+    fn error(&self, kind: DecodingErrorKind) -> io::Error {
+        let mut e = DecodingError::new(kind);
+        e.chunk = Some("IDAT".to_string());
+        e.offset = Some(self.offset);
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+
+    fn end_chunk(&mut self) -> io::Result<()> {
+        let mut crc = [0u8; 4];
+        if self.reader.read_exact(&mut crc).is_err() {
+            return Err(self.error(DecodingErrorKind::TruncatedChunk));
+        }
+        let expected = u32::from_be_bytes(crc);
+        let computed = self.hasher.finalise();
+        if expected != computed {
+            return Err(self.error(DecodingErrorKind::CrcMismatch { expected, computed }));
+        }
+
+        let next_offset = self.next_offset();
+        match read_chunk_header(&mut self.reader, next_offset) {
+            Ok(Some((len, name))) if &name == b"IDAT" => {
+                self.offset = next_offset;
+                self.len = len;
+                self.remaining = len;
+                self.hasher = Crc32::new();
+                self.hasher.update(&name);
+            }
+            Ok(header) => {
+                self.next = header;
+                self.done = true;
+            }
+            Err(ImageError::IO(e)) => return Err(e),
+            Err(ImageError::Decoding(e)) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for IdatReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+            if self.remaining == 0 {
+                self.end_chunk()?;
+                continue;
+            }
+
+            let len = buf.len().min(self.remaining as usize);
+            let n = self.reader.read(&mut buf[..len])?;
+            if n == 0 {
+                return Err(self.error(DecodingErrorKind::TruncatedChunk));
+            }
+            self.hasher.update(&buf[..n]);
+            self.remaining -= n as u32;
+            return Ok(n);
+        }
+    }
+}
+
+/// An unfiltered scanline
+#[derive(Debug)]
+pub struct Row<'a> {
+    /// Adam7 pass from 1 to 7, 0 for non interlaced images
+    pub pass: u8,
+    /// Position of the row in the full image
+    pub y: u32,
+    /// Number of pixels, smaller than the image width in Adam7 passes
+    pub width: u32,
+    /// Samples packed at the image bit depth, without the filter type byte
+    pub data: &'a [u8],
+}
+
+/// Inflates and unfilters the scanlines of a zlib stream one row at a time,
+/// only the current and previous rows are kept
+pub(super) struct RowDecoder<R: Read> {
+    zlib: ZlibDecoder<R>,
+    bpp: usize,
+    bits_per_pixel: usize,
+    width: u32,
+    height: u32,
+    interlaced: bool,
+    /// Index in ADAM7_PASSES, always 0 for non interlaced images
+    pass: usize,
+    pass_row: u32,
+    /// Rows with their filter type byte
+    prev: Vec<u8>,
+    current: Vec<u8>,
+}
+
+impl<R: Read> RowDecoder<R> {
+    pub(super) fn new(zlib: ZlibDecoder<R>, image: &PngImage) -> Self {
+        RowDecoder {
+            zlib,
+            bpp: image.bits_per_pixel().div_ceil(8).max(1),
+            bits_per_pixel: image.bits_per_pixel(),
+            width: image.width(),
+            height: image.height(),
+            interlaced: image.is_interlaced(),
+            pass: 0,
+            pass_row: 0,
+            prev: Vec::new(),
+            current: Vec::new(),
+        }
+    }
+
+    fn nb_passes(&self) -> usize {
+        if self.interlaced { ADAM7_PASSES.len() } else { 1 }
+    }
+
+    /// Pass origin and size in pixels, with its row step
+    fn pass_geometry(&self) -> (u32, u32, u32, u32) {
+        if ! self.interlaced {
+            return (0, self.width, self.height, 1);
+        }
+        let (x0, y0, dx, dy) = ADAM7_PASSES[self.pass];
+        (y0, adam7_pass_size(self.width, x0, dx), adam7_pass_size(self.height, y0, dy), dy)
+    }
+
+    /// Move to the next non empty pass once the current one is complete
+    fn advance(&mut self) {
+        while self.pass < self.nb_passes() {
+            let (_, width, height, _) = self.pass_geometry();
+            if self.pass_row < height && width > 0 {
+                if self.pass_row == 0 {
+                    // the first row of a pass is unfiltered against zeros
+                    let scanline_len = (width as usize * self.bits_per_pixel).div_ceil(8);
+                    self.prev.clear();
+                    self.prev.resize(scanline_len + 1, 0);
+                    self.current.resize(scanline_len + 1, 0);
+                }
+                return;
+            }
+            self.pass += 1;
+            self.pass_row = 0;
+        }
+    }
+
+    pub(super) fn get_ref(&self) -> &R {
+        self.zlib.get_ref()
+    }
+
+    pub(super) fn is_done(&mut self) -> bool {
+        self.advance();
+        self.pass >= self.nb_passes()
+    }
+
+    /// Next unfiltered row, None once every row has been decoded
+    pub(super) fn next_row(&mut self) -> Result<Option<Row<'_>>, ImageError> {
+        if self.is_done() {
+            return Ok(None);
+        }
+        if self.pass_row > 0 {
+            std::mem::swap(&mut self.prev, &mut self.current);
+        }
+
+        let mut filled = 0;
+        while filled < self.current.len() {
+            let n = self.zlib.decompress_into(&mut self.current[filled..])?;
+            if n == 0 {
+                return Err(ImageError::decoding(DecodingErrorKind::TruncatedImageData));
+            }
+            filled += n;
+        }
+
+        let filter_type = FilterType::from_u8(self.current[0])?;
+        unfilter_scanline(self.bpp, &self.prev[1..], &mut self.current[1..], filter_type);
+
+        let (y0, width, _, dy) = self.pass_geometry();
+        let row = Row {
+            pass: if self.interlaced { self.pass as u8 + 1 } else { 0 },
+            y: y0 + self.pass_row * dy,
+            width,
+            data: &self.current[1..],
+        };
+        self.pass_row += 1;
+
+        Ok(Some(row))
+    }
+
+    /// Read the end of the zlib stream, checking its checksum
+    pub(super) fn finish(mut self) -> Result<R, ImageError> {
+        let mut buf = [0u8; 512];
+        loop {
+            let n = self.zlib.decompress_into(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            warn!("{} bytes of image data after the last scanline", n);
+        }
+        Ok(self.zlib.into_inner())
+    }
+}
+
+/// PNG decoder reading chunks straight from a `Read`. The image data is
+/// inflated incrementally and handed out one unfiltered row at a time, so
+/// memory stays proportional to a couple of rows whatever the image size.
+pub struct PngStreamDecoder<R: Read> {
+    image: PngImage,
+    rows: Option<RowDecoder<IdatReader<R>>>,
+}
+
+impl<R: Read> PngStreamDecoder<R> {
+    /// Read the chunks before the image data
+    pub fn new(reader: R) -> Result<Self, ImageError> {
+        PngStreamDecoder::with_limits(reader, &Limits::default())
+    }
+
+    /// Read the chunks before the image data, the image must fit in `limits`
+    pub fn with_limits(mut reader: R, limits: &Limits) -> Result<Self, ImageError> {
+        read_signature(&mut reader)?;
+        let mut image = PngImage::with_limits(limits);
+        let mut offset = PNG_SIGNATURE.len() as u64;
+
+        let idat = loop {
+            let header = match read_chunk_header(&mut reader, offset)? {
+                Some(header) => header,
+                None => return Err(ImageError::decoding(DecodingErrorKind::MissingImageData)),
+            };
+            if &header.1 == b"IDAT" {
+                break header;
+            }
+
+            read_chunk(&mut reader, header, offset, &mut image)?;
+            offset += chunk_size(header);
+        };
+
+        if ! image.has_header() {
+            return Err(ImageError::decoding(DecodingErrorKind::ChunkBeforeIHDR).in_chunk("IDAT", offset));
+        }
+
+        let zlib = ZlibDecoder::new(IdatReader::new(reader, idat, offset)).map_err(|e| e.in_chunk("IDAT", offset))?;
+        let rows = RowDecoder::new(zlib, &image);
+
+        Ok(PngStreamDecoder {
+            image,
+            rows: Some(rows),
+        })
+    }
+
+    /// Header and ancillary chunks read so far, without image data
+    pub fn image(&self) -> &PngImage {
+        &self.image
+    }
+
+    /// Next unfiltered row, rows of interlaced images come pass by pass.
+    /// Once the last row is read, the chunks after the image data are read
+    /// up to IEND and None is returned.
+    pub fn next_row(&mut self) -> Result<Option<Row<'_>>, ImageError> {
+        let done = match self.rows.as_mut() {
+            None => return Ok(None),
+            Some(rows) => rows.is_done(),
+        };
+        if done {
+            self.finish()?;
+            return Ok(None);
+        }
+
+        match self.rows.as_mut() {
+            Some(rows) => {
+                let offset = rows.get_ref().offset;
+                rows.next_row().map_err(|e| e.in_chunk("IDAT", offset))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), ImageError> {
+        let rows = match self.rows.take() {
+            Some(rows) => rows,
+            None => return Ok(()),
+        };
+
+        // skip what is left of the IDAT chunks
+        let offset = rows.get_ref().offset;
+        let mut idat = rows.finish().map_err(|e| e.in_chunk("IDAT", offset))?;
+        io::copy(&mut idat, &mut io::sink())?;
+        let mut offset = idat.next_offset();
+        let mut reader = idat.reader;
+        let mut next = idat.next;
+
+        while let Some(header) = next {
+            if &header.1 == b"IDAT" {
+                return Err(ImageError::decoding(DecodingErrorKind::NonConsecutiveImageData).in_chunk("IDAT", offset));
+            }
+
+            read_chunk(&mut reader, header, offset, &mut self.image)?;
+            if &header.1 == b"IEND" {
+                return Ok(());
+            }
+            offset += chunk_size(header);
+            next = read_chunk_header(&mut reader, offset)?;
+        }
+        Err(ImageError::decoding(DecodingErrorKind::MissingIEND).in_chunk("IEND", offset))
+    }
+}
+
+enum RowSource<'a> {
+    Stream(Box<RowDecoder<&'a [u8]>>),
+    /// Interlaced and leniently read images are decoded as a whole first
+    Decoded(Vec<u8>),
+}
+
+/// Rows of a PNG image converted to 8 bits per channel, see `PngImage::rows`
+pub struct PngRows<'a> {
+    image: &'a PngImage,
+    colors: GenericImageColors,
+    options: ConvertOptions,
+    /// Built once for all the rows
+    transform: Option<Transform>,
+    source: RowSource<'a>,
+    samples: Vec<u16>,
+    row: Vec<u8>,
+    y: u32,
+}
+
+impl<'a> PngRows<'a> {
+    pub(super) fn new(image: &'a PngImage, colors: GenericImageColors, options: &ConvertOptions) -> Result<Self, ImageError> {
+        let transform = image.conversion_transform(options);
+        let source = if image.is_interlaced() || image.is_lenient() {
+            RowSource::Decoded(image.convert_samples(&image.samples()?, colors, options, transform.as_ref())?)
+        } else {
+            let zlib = ZlibDecoder::new(image.idat()).map_err(|e| e.in_chunk("IDAT", image.idat_offset()))?;
+            RowSource::Stream(Box::new(RowDecoder::new(zlib, image)))
+        };
+
+        Ok(PngRows {
+            image,
+            colors,
+            options: *options,
+            transform,
+            source,
+            samples: Vec::new(),
+            row: Vec::new(),
+            y: 0,
+        })
+    }
+
+    /// Next row and its y position, None after the last one
+    pub fn next_row(&mut self) -> Result<Option<(u32, &[u8])>, ImageError> {
+        let y = self.y;
+        let width = self.image.width() as usize;
+        let idat_offset = self.image.idat_offset();
+
+        match &mut self.source {
+            RowSource::Decoded(data) => {
+                if y >= self.image.height() {
+                    return Ok(None);
+                }
+                self.y += 1;
+
+                let row_len = data.len() / self.image.height() as usize;
+                let start = y as usize * row_len;
+                Ok(Some((y, &data[start..start + row_len])))
+            }
+            RowSource::Stream(rows) => {
+                let row = match rows.next_row().map_err(|e| e.in_chunk("IDAT", idat_offset))? {
+                    Some(row) => row,
+                    None => return Ok(None),
+                };
+                self.y += 1;
+
+                self.samples.clear();
+                self.image.unpack_scanline(row.data, width as u32, &mut self.samples);
+                self.row = self.image.convert_samples(&self.samples, self.colors, &self.options, self.transform.as_ref())?;
+                Ok(Some((row.y, &self.row)))
+            }
+        }
+    }
+}
+
+/// Read the PNG header, and with `with_metadata` the metadata chunks stored
+/// before the image data. The image data itself is never read.
+pub fn probe<R: Read>(mut reader: R, with_metadata: bool) -> Result<ImageInfo, ImageError> {
+    read_signature(&mut reader)?;
+    let mut image = PngImage::new();
+    let mut offset = PNG_SIGNATURE.len() as u64;
+
+    match read_chunk_header(&mut reader, offset)? {
+        Some(header) if &header.1 == b"IHDR" => {
+            read_chunk(&mut reader, header, offset, &mut image)?;
+            offset += chunk_size(header);
+        }
+        Some(header) => return Err(ImageError::decoding(DecodingErrorKind::ChunkBeforeIHDR).in_chunk(&chunk_name(header), offset)),
+        None => return Err(ImageError::decoding(DecodingErrorKind::MissingIHDR)),
+    }
+
+    let mut info = image.info();
+    if ! with_metadata {
+        return Ok(info);
+    }
+
+    while let Some(header) = read_chunk_header(&mut reader, offset)? {
+        if &header.1 == b"IDAT" || &header.1 == b"IEND" {
+            break;
+        }
+        read_chunk(&mut reader, header, offset, &mut image)?;
+        offset += chunk_size(header);
+    }
+
+    info.metadata = Some(image.metadata().clone());
+    Ok(info)
+}
+
+/// PNG with `raw` compressed into IDAT chunks of `idat_size` bytes, and a
+/// text chunk after them
+#[cfg(test)]
+fn build_split_png(ihdr: &[u8], raw: &[u8], idat_size: usize) -> Vec<u8> {
+    let idat = crate::compress::zlib::compress(raw, 6);
+    let mut chunks: Vec<(&str, &[u8])> = vec!(("IHDR", ihdr));
+    chunks.extend(idat.chunks(idat_size).map(|part| ("IDAT", part)));
+    chunks.push(("tEXt", b"Comment\0after the data"));
+    chunks.push(("IEND", &[]));
+    super::png::build_png(&chunks)
+}
+
+#[cfg(test)]
+struct ByteReader<'a>(&'a [u8]);
+
+#[cfg(test)]
+impl Read for ByteReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.0.len()).min(1);
+        buf[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_stream_decoder_rows() {
+    use crate::image::{GenericImage, GenericImageColors};
+    use super::PngEncoder;
+
+    let (width, height) = (37u32, 23u32);
+    let data: Vec<u8> = (0..width * height * 3).map(|i| (i * 7 % 251) as u8).collect();
+    let img = GenericImage { width, height, colors: GenericImageColors::RGB, data };
+
+    let mut png: Vec<u8> = Vec::new();
+    PngEncoder::new().encode(&mut png, &img).unwrap();
+
+    let mut decoder = PngStreamDecoder::new(ByteReader(&png)).unwrap();
+    assert_eq!(decoder.image().width(), width);
+    let mut y = 0;
+    while let Some(row) = decoder.next_row().unwrap() {
+        assert_eq!((row.pass, row.y, row.width), (0, y, width));
+        let start = (y * width * 3) as usize;
+        assert_eq!(row.data, &img.data[start..start + (width * 3) as usize]);
+        y += 1;
+    }
+    assert_eq!(y, height);
+    assert!(decoder.next_row().unwrap().is_none());
+}
+
+#[test]
+fn test_stream_decoder_interlaced() {
+    use crate::image::ReadImage;
+
+    let (width, height) = (13u32, 11u32);
+    let pixel = |x: u32, y: u32| ((x * 7 + y * 13) % 256) as u8;
+
+    // gray 8 bits, each pass row filtered with Up
+    let mut raw: Vec<u8> = Vec::new();
+    for &(x0, y0, dx, dy) in ADAM7_PASSES.iter() {
+        let pass_width = adam7_pass_size(width, x0, dx);
+        let mut prev = vec!(0u8; pass_width as usize);
+        for j in 0..adam7_pass_size(height, y0, dy) {
+            if pass_width == 0 {
+                break;
+            }
+            raw.push(FilterType::Up as u8);
+            let row: Vec<u8> = (0..pass_width).map(|i| pixel(x0 + i * dx, y0 + j * dy)).collect();
+            raw.extend(row.iter().zip(&prev).map(|(a, b)| a.wrapping_sub(*b)));
+            prev = row;
+        }
+    }
+    let mut ihdr: Vec<u8> = Vec::new();
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 0, 0, 0, 1]);
+    let png = build_split_png(&ihdr, &raw, 7);
+
+    let mut decoder = PngStreamDecoder::new(&png[..]).unwrap();
+    let mut img = vec!(0u8; (width * height) as usize);
+    let mut nb_rows = 0;
+    while let Some(row) = decoder.next_row().unwrap() {
+        let (x0, _, dx, _) = ADAM7_PASSES[row.pass as usize - 1];
+        for (i, v) in row.data.iter().enumerate() {
+            img[(row.y * width + x0 + i as u32 * dx) as usize] = *v;
+        }
+        nb_rows += 1;
+    }
+    assert_eq!(nb_rows, 2 + 2 + 1 + 3 + 3 + 6 + 5);
+
+    let expected: Vec<u8> = (0..height).flat_map(|y| (0..width).map(move |x| pixel(x, y))).collect();
+    assert_eq!(img, expected);
+
+    let full = PngImage::read_image(&png[..]).unwrap();
+    assert_eq!(full.samples().unwrap(), expected.iter().map(|v| *v as u16).collect::<Vec<u16>>());
+}
+
+#[test]
+fn test_stream_decoder_errors() {
+    let ihdr = [0, 0, 0, 4, 0, 0, 0, 2, 8, 0, 0, 0, 0];
+    let raw = [0, 1, 2, 3, 4, 0, 5, 6, 7, 8];
+
+    let mut png = build_split_png(&ihdr, &raw, 5);
+    assert!(PngStreamDecoder::new(&png[..]).is_ok());
+
+    // missing rows
+    let truncated = build_split_png(&ihdr, &raw[..5], 5);
+    let mut decoder = PngStreamDecoder::new(&truncated[..]).unwrap();
+    assert!(decoder.next_row().unwrap().is_some());
+    assert!(decoder.next_row().is_err());
+
+    // unknown filter type
+    let bad_filter = build_split_png(&ihdr, &[9, 1, 2, 3, 4, 0, 5, 6, 7, 8], 5);
+    let mut decoder = PngStreamDecoder::new(&bad_filter[..]).unwrap();
+    assert!(decoder.next_row().is_err());
+
+    // corrupted second IDAT chunk
+    let second_idat = 8 + 25 + 17 + 8;
+    png[second_idat] ^= 0xff;
+    let mut decoder = PngStreamDecoder::new(&png[..]).unwrap();
+    assert!((0..3).try_for_each(|_| decoder.next_row().map(|_| ())).is_err());
+
+    assert!(PngStreamDecoder::new(&PNG_SIGNATURE[..]).is_err());
+
+    // end of file instead of IEND
+    let png = build_split_png(&ihdr, &raw, 5);
+    let no_iend = &png[..png.len() - 12];
+    let mut decoder = PngStreamDecoder::new(no_iend).unwrap();
+    assert!(decoder.next_row().unwrap().is_some() && decoder.next_row().unwrap().is_some());
+    let e = decoder.next_row().unwrap_err();
+    assert_eq!(e.decoding_kind(), Some(&DecodingErrorKind::MissingIEND));
+    assert_eq!((e.chunk(), e.offset()), (Some("IEND"), Some(no_iend.len() as u64)));
+}
+
+#[test]
+fn test_error_context() {
+    use std::error::Error;
+    use crate::image::ReadImage;
+
+    let ihdr = [0, 0, 0, 4, 0, 0, 0, 2, 8, 0, 0, 0, 0];
+    let raw = [0, 1, 2, 3, 4, 0, 5, 6, 7, 8];
+    let png = build_split_png(&ihdr, &raw, 5);
+    let fix_crc = |png: &mut Vec<u8>, offset: usize| {
+        let len = u32::from_be_bytes([png[offset], png[offset + 1], png[offset + 2], png[offset + 3]]) as usize;
+        let mut hasher = Crc32::new();
+        hasher.update(&png[offset + 4..offset + 8 + len]);
+        png[offset + 8 + len..offset + 12 + len].copy_from_slice(&hasher.finalise().to_be_bytes());
+    };
+
+    // data of the second IDAT chunk, which starts after the signature, IHDR
+    // and a first IDAT chunk of 5 bytes
+    let mut bad_crc = png.clone();
+    bad_crc[8 + 25 + 17 + 8] ^= 0xff;
+    let e = PngStreamDecoder::new(&bad_crc[..]).and_then(|mut d| { while d.next_row()?.is_some() {} Ok(()) }).unwrap_err();
+    assert!(matches!(e.decoding_kind(), Some(DecodingErrorKind::CrcMismatch { .. })));
+    assert_eq!((e.chunk(), e.offset()), (Some("IDAT"), Some(50)));
+    assert!(e.is_corrupt() && ! e.is_unsupported());
+
+    let e = PngImage::read_image(&bad_crc[..]).unwrap_err();
+    assert_eq!((e.chunk(), e.offset()), (Some("IDAT"), Some(50)));
+
+    // unknown critical chunk in place of the tEXt chunk after the data
+    let mut unknown = png.clone();
+    let text = png.len() - 12 - (12 + 22);
+    unknown[text + 4..text + 8].copy_from_slice(b"ABCD");
+    fix_crc(&mut unknown, text);
+    let e = PngImage::read_image(&unknown[..]).unwrap_err();
+    assert!(e.is_unsupported() && ! e.is_corrupt());
+    assert_eq!((e.chunk(), e.offset()), (Some("ABCD"), Some(text as u64)));
+
+    // invalid zlib data keeps the inflater error as source
+    let mut bad_zlib = build_split_png(&ihdr, &raw, 100);
+    bad_zlib[8 + 25 + 8 + 2] = 0xff;
+    fix_crc(&mut bad_zlib, 33);
+
+    let image = PngImage::read_image(&bad_zlib[..]).unwrap();
+    let e = image.samples().unwrap_err();
+    assert_eq!(e.decoding_kind(), Some(&DecodingErrorKind::Compression));
+    assert_eq!((e.chunk(), e.offset()), (Some("IDAT"), Some(33)));
+    assert!(e.source().is_some());
+
+    let e = PngStreamDecoder::new(&bad_zlib[..]).and_then(|mut d| d.next_row().map(|_| ())).unwrap_err();
+    assert_eq!(e.decoding_kind(), Some(&DecodingErrorKind::Compression));
+    assert!(e.source().is_some());
+
+    let e = PngStreamDecoder::new(&png[..20]).err().unwrap();
+    assert_eq!((e.decoding_kind(), e.offset()), (Some(&DecodingErrorKind::TruncatedChunk), Some(8)));
+    assert_eq!(PngStreamDecoder::new(&png[1..]).err().unwrap().decoding_kind(), Some(&DecodingErrorKind::BadSignature));
+}
+
+#[test]
+fn test_limits() {
+    use crate::image::ReadImage;
+
+    let ihdr = [0, 0, 0, 4, 0, 0, 0, 2, 8, 0, 0, 0, 0];
+    let raw = [0, 1, 2, 3, 4, 0, 5, 6, 7, 8];
+    let png = build_split_png(&ihdr, &raw, 5);
+    assert!(PngImage::read_image_with_limits(&png[..], &Limits { max_width: Some(4), max_pixels: Some(8), ..Limits::none() }).is_ok());
+
+    // 100000x100000 header
+    let huge = build_split_png(&[0, 1, 134, 160, 0, 1, 134, 160, 8, 6, 0, 0, 0], &raw, 5);
+    for e in &[PngImage::read_image(&huge[..]).unwrap_err(), PngStreamDecoder::new(&huge[..]).err().unwrap(), probe(&huge[..], false).unwrap_err()] {
+        assert_eq!(e.decoding_kind(), Some(&DecodingErrorKind::LimitsExceeded { limit: "pixel count", max: 1 << 28 }));
+        assert_eq!((e.chunk(), e.offset()), (Some("IHDR"), Some(8)));
+        assert!(! e.is_corrupt());
+    }
+    let limits = Limits { max_height: Some(1), ..Limits::none() };
+    assert!(PngStreamDecoder::with_limits(&png[..], &limits).err().unwrap().is_limits_exceeded());
+    let limits = Limits { max_decoded_bytes: Some(9), ..Limits::none() };
+    assert!(PngImage::read_image_with_limits(&png[..], &limits).unwrap_err().is_limits_exceeded());
+
+    // image data inflating to much more than the header announces
+    let bomb = build_split_png(&ihdr, &vec!(0; 1 << 20), 1 << 16);
+    let limits = Limits { max_decoded_bytes: Some(1000), ..Limits::none() };
+    let image = PngImage::read_image_with_limits(&bomb[..], &limits).unwrap();
+    let e = image.samples().unwrap_err();
+    assert_eq!(e.decoding_kind(), Some(&DecodingErrorKind::LimitsExceeded { limit: "decoded size", max: 1000 }));
+    assert_eq!(e.chunk(), Some("IDAT"));
+    assert!(PngImage::read_image(&bomb[..]).unwrap().samples().is_ok());
+
+    // the tEXt chunk after the image data holds 21 bytes of text
+    let limits = Limits { max_chunk_size: Some(21), ..Limits::none() };
+    assert_eq!(PngImage::read_image_with_limits(&png[..], &limits).unwrap_err().chunk(), Some("tEXt"));
+    let limits = Limits { max_metadata_bytes: Some(20), ..Limits::none() };
+    assert_eq!(PngImage::read_image_with_limits(&png[..], &limits).unwrap_err().chunk(), Some("tEXt"));
+    let limits = Limits { max_metadata_bytes: Some(21), ..Limits::none() };
+    assert!(PngImage::read_image_with_limits(&png[..], &limits).is_ok());
+}
+
+#[test]
+fn test_lenient() {
+    use crate::image::{GenericImageTo, ReadImage};
+
+    let kinds = |diagnostics: &[DecodingError]| diagnostics.iter().map(|d| d.kind.clone()).collect::<Vec<DecodingErrorKind>>();
+
+    // 64x32 gray image whose data hardly compresses, so that the image data
+    // chunks hold rows in order
+    let (width, height) = (64usize, 32usize);
+    let ihdr = [0, 0, 0, width as u8, 0, 0, 0, height as u8, 8, 0, 0, 0, 0];
+    let pixels: Vec<u8> = (0..width * height).map(|i| (i * 7919 % 251) as u8).collect();
+    let raw: Vec<u8> = pixels.chunks(width).flat_map(|row| std::iter::once(0).chain(row.iter().copied())).collect();
+    let png = build_split_png(&ihdr, &raw, 256);
+
+    let (image, diagnostics) = PngImage::read_image_lenient(&png[..], &Limits::default()).unwrap();
+    assert!(diagnostics.is_empty());
+    assert_eq!(image.samples().unwrap(), pixels.iter().map(|p| *p as u16).collect::<Vec<u16>>());
+
+    // bad crc of the tEXt chunk after the image data
+    let mut bad_crc = png.clone();
+    let end = bad_crc.len() - 13;
+    bad_crc[end] ^= 0xff;
+    assert!(PngImage::read_image(&bad_crc[..]).is_err());
+    let (_, diagnostics) = PngImage::read_image_lenient(&bad_crc[..], &Limits::default()).unwrap();
+    assert!(matches!(kinds(&diagnostics)[..], [DecodingErrorKind::CrcMismatch { .. }]));
+    assert_eq!(diagnostics[0].chunk.as_deref(), Some("tEXt"));
+
+    // trailing data and missing IEND
+    let mut trailing = png.clone();
+    trailing.extend_from_slice(b"garbage");
+    assert!(PngImage::read_image(&trailing[..]).is_err());
+    let (_, diagnostics) = PngImage::read_image_lenient(&trailing[..], &Limits::default()).unwrap();
+    assert_eq!(kinds(&diagnostics), vec!(DecodingErrorKind::TrailingData));
+    assert_eq!(diagnostics[0].offset, Some(png.len() as u64));
+
+    let (_, diagnostics) = PngImage::read_image_lenient(&png[..png.len() - 12], &Limits::default()).unwrap();
+    assert_eq!(kinds(&diagnostics), vec!(DecodingErrorKind::MissingIEND));
+
+    // stream cut in the middle of the image data, the rows decoded are kept
+    // and the others are white
+    let truncated = &png[..png.len() / 2];
+    assert!(PngImage::read_image(truncated).is_err());
+    let (image, diagnostics) = PngImage::read_image_lenient(truncated, &Limits::default()).unwrap();
+    let found = kinds(&diagnostics);
+    assert_eq!(found[..2], [DecodingErrorKind::TruncatedChunk, DecodingErrorKind::MissingIEND]);
+    assert!(found.contains(&DecodingErrorKind::TruncatedImageData));
+
+    let gray = image.to_g().unwrap().data;
+    let decoded = gray.chunks(width).zip(pixels.chunks(width)).take_while(|(a, b)| a == b).count();
+    assert!(decoded > 0 && decoded < height);
+    assert!(gray[width * decoded..].iter().all(|p| *p == 255));
+
+    // same rows when streamed
+    let mut rows = image.rows_with(GenericImageColors::G, &ConvertOptions::default()).unwrap();
+    let mut streamed: Vec<u8> = Vec::new();
+    while let Some((_, row)) = rows.next_row().unwrap() {
+        streamed.extend_from_slice(row);
+    }
+    assert_eq!(streamed, gray);
+
+    // unknown filter type on the third row
+    let mut bad_filter = raw.clone();
+    bad_filter[2 * (width + 1)] = 9;
+    let png = build_split_png(&ihdr, &bad_filter, 256);
+    let (image, diagnostics) = PngImage::read_image_lenient(&png[..], &Limits::default()).unwrap();
+    assert_eq!(kinds(&diagnostics), vec!(DecodingErrorKind::UnknownFilterType(9)));
+    let gray = image.to_g().unwrap().data;
+    assert_eq!(gray[..width * 2], pixels[..width * 2]);
+    assert!(gray[width * 2..].iter().all(|p| *p == 255));
+}
+
+#[test]
+fn test_lenient_interlaced() {
+    let (width, height) = (32u32, 16u32);
+    let ihdr = [0, 0, 0, width as u8, 0, 0, 0, height as u8, 8, 2, 0, 0, 1];
+    let mut raw: Vec<u8> = Vec::new();
+    for &(x0, y0, dx, dy) in ADAM7_PASSES.iter() {
+        let pass_width = adam7_pass_size(width, x0, dx);
+        for _ in 0..adam7_pass_size(height, y0, dy) {
+            raw.push(0);
+            let start = raw.len() as u32;
+            raw.extend((0..pass_width * 3).map(|i| ((start + i) * 7919 % 251) as u8));
+        }
+    }
+    let png = build_split_png(&ihdr, &raw, 100);
+
+    let (image, diagnostics) = PngImage::read_image_lenient(&png[..png.len() / 2], &Limits::default()).unwrap();
+    assert!(diagnostics.iter().any(|d| d.kind == DecodingErrorKind::TruncatedImageData));
+    let samples = image.samples().unwrap();
+    // first pixel from the first pass, second row only in the last pass
+    assert_eq!(samples[..3], [raw[1] as u16, raw[2] as u16, raw[3] as u16]);
+    assert_eq!(samples[width as usize * 3..width as usize * 3 + 3], [255, 255, 255]);
+}
+
+#[test]
+fn test_png_rows() {
+    use crate::image::{GenericImage, GenericImageTo, ReadImage};
+    use super::PngEncoder;
+
+    let (width, height) = (19u32, 7u32);
+    let data: Vec<u8> = (0..width * height * 4).map(|i| if i % 4 == 3 { (i * 37 % 256) as u8 } else { (i * 11 % 253) as u8 }).collect();
+    let img = GenericImage { width, height, colors: GenericImageColors::RGBA, data };
+
+    let mut png: Vec<u8> = Vec::new();
+    PngEncoder::new().encode(&mut png, &img).unwrap();
+    let image = PngImage::read_image(&png[..]).unwrap();
+
+    let conversions = [
+        (GenericImageColors::RGB, image.to_rgb().unwrap().data),
+        (GenericImageColors::RGBA, image.to_rgba().unwrap().data),
+        (GenericImageColors::G, image.to_g().unwrap().data),
+    ];
+    for (colors, expected) in conversions.iter() {
+        let mut rows = image.rows_with(*colors, &ConvertOptions::default()).unwrap();
+        let mut decoded: Vec<u8> = Vec::new();
+        let mut nb_rows = 0;
+        while let Some((y, row)) = rows.next_row().unwrap() {
+            assert_eq!(y, nb_rows);
+            decoded.extend_from_slice(row);
+            nb_rows += 1;
+        }
+        assert_eq!(nb_rows, height);
+        assert_eq!(&decoded, expected);
+    }
+}
+
+#[test]
+fn test_png_rows_interlaced() {
+    use crate::image::{GenericImageTo, ReadImage};
+
+    // 3x3 gray 8 bits, passes 2 and 3 are empty
+    let raw = [0, 10, 0, 20, 0, 30, 40, 0, 50, 0, 60, 0, 70, 80, 90];
+    let png = build_split_png(&[0, 0, 0, 3, 0, 0, 0, 3, 8, 0, 0, 0, 1], &raw, 100);
+    let image = PngImage::read_image(&png[..]).unwrap();
+
+    let mut rows = image.rows().unwrap();
+    let mut decoded: Vec<u8> = Vec::new();
+    while let Some((_, row)) = rows.next_row().unwrap() {
+        decoded.extend_from_slice(row);
+    }
+    assert_eq!(decoded, image.to_rgb().unwrap().data);
+    let gray: Vec<u8> = decoded.chunks(3).map(|p| p[0]).collect();
+    assert_eq!(gray, vec!(10, 50, 20, 70, 80, 90, 30, 60, 40));
+}
+
+#[test]
+fn test_probe() {
+    use crate::image::{probe, GenericImage, ImageFormat, ImageColorType};
+    use super::PngEncoder;
+
+    let img = GenericImage { width: 5, height: 3, colors: GenericImageColors::G, data: vec!(7; 15) };
+    let mut png: Vec<u8> = Vec::new();
+    PngEncoder::new()
+        .colors(GenericImageColors::G)
+        .text("Title", "probe")
+        .compressed_text("Comment", "not decoded")
+        .time(2020, 2, 29, 12, 30, 0)
+        .encode(&mut png, &img)
+        .unwrap();
+
+    let info = probe(&png[..], false).unwrap();
+    assert_eq!((info.format, info.width, info.height), (ImageFormat::Png, 5, 3));
+    assert_eq!((info.color_type, info.bit_depth, info.interlaced), (ImageColorType::Gray, 8, false));
+    assert_eq!(info.metadata, None);
+
+    let info = probe(&png[..], true).unwrap();
+    let texts: Vec<(&str, &str)> = info.metadata.as_ref().unwrap().texts.iter().map(|t| (t.keyword.as_str(), t.text.as_str())).collect();
+    assert_eq!(texts, vec!(("Title", "probe"), ("Comment", "not decoded")));
+    assert_eq!(info.metadata.unwrap().time.unwrap().to_string(), "2020-02-29T12:30:00Z");
+
+    // only the header is needed
+    assert_eq!(probe(&png[..33], false).unwrap().width, 5);
+
+    assert!(probe(&b"P6\n5 3\n255\n"[..], false).is_err());
+    assert!(probe(&b"GIF89a"[..], false).is_err());
+    assert!(probe(&png[..20], false).is_err());
+}
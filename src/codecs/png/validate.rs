@@ -0,0 +1,186 @@
+use std::io::Read;
+
+use crate::error::*;
+use crate::image::Limits;
+use super::png::PngImage;
+
+/// Chunks that may appear at most once
+const SINGLE_CHUNKS: [&str; 14] = [
+    "IHDR", "PLTE", "IEND", "cHRM", "gAMA", "iCCP", "sBIT", "sRGB", "bKGD", "hIST", "tRNS", "pHYs", "tIME", "eXIf",
+];
+
+/// Checks the chunk ordering rules of the PNG specification, one chunk at a
+/// time. IHDR coming first is left to the decoder, which refuses anything
+/// else.
+pub(super) struct ChunkValidator {
+    /// Color type and bit depth
+    header: Option<(u8, u8)>,
+    seen: Vec<String>,
+    in_idat: bool,
+}
+
+impl ChunkValidator {
+    pub(super) fn new() -> Self {
+        ChunkValidator {
+            header: None,
+            seen: Vec::new(),
+            in_idat: false,
+        }
+    }
+
+    fn has_seen(&self, name: &str) -> bool {
+        self.seen.iter().any(|s| s == name)
+    }
+
+    fn is_indexed(&self) -> bool {
+        matches!(self.header, Some((3, _)))
+    }
+
+    /// Rules broken by the next chunk
+    pub(super) fn check(&mut self, name: &str, data: &[u8]) -> Vec<DecodingErrorKind> {
+        let mut problems: Vec<DecodingErrorKind> = Vec::new();
+        let misplaced = |rule: &'static str| DecodingErrorKind::MisplacedChunk { chunk: name.to_string(), rule };
+
+        if SINGLE_CHUNKS.contains(&name) && self.has_seen(name) {
+            problems.push(DecodingErrorKind::DuplicateChunk(name.to_string()));
+        }
+        let idat_seen = self.has_seen("IDAT");
+
+        match name {
+            "IHDR" if data.len() >= 10 && self.header.is_none() => {
+                self.header = Some((data[9], data[8]));
+            }
+            "PLTE" => {
+                if idat_seen {
+                    problems.push(misplaced("must come before IDAT"));
+                }
+                match self.header {
+                    Some((0, _)) | Some((4, _)) => problems.push(misplaced("is not allowed in gray images")),
+                    Some((color_type, bit_depth)) => {
+                        let entries = (data.len() / 3) as u32;
+                        let max = if color_type == 3 { 1u32 << bit_depth.min(8) } else { 256 };
+                        if entries == 0 || entries > max {
+                            problems.push(DecodingErrorKind::InvalidPaletteSize { entries, max });
+                        }
+                    }
+                    None => {}
+                }
+            }
+            "IDAT" => {
+                if idat_seen && ! self.in_idat {
+                    problems.push(DecodingErrorKind::NonConsecutiveImageData);
+                }
+                if ! idat_seen && self.is_indexed() && ! self.has_seen("PLTE") {
+                    problems.push(DecodingErrorKind::MissingPalette);
+                }
+            }
+            "cHRM" | "gAMA" | "iCCP" | "sBIT" | "sRGB" => {
+                if idat_seen || self.has_seen("PLTE") {
+                    problems.push(misplaced("must come before PLTE and IDAT"));
+                }
+                if name == "sRGB" && self.has_seen("iCCP") {
+                    problems.push(misplaced("is not allowed with iCCP"));
+                }
+                if name == "iCCP" && self.has_seen("sRGB") {
+                    problems.push(misplaced("is not allowed with sRGB"));
+                }
+            }
+            "bKGD" | "hIST" | "tRNS" => {
+                if idat_seen {
+                    problems.push(misplaced("must come before IDAT"));
+                }
+                if (name == "hIST" || self.is_indexed()) && ! self.has_seen("PLTE") {
+                    problems.push(misplaced("must come after PLTE"));
+                }
+            }
+            "pHYs" | "sPLT" | "eXIf" if idat_seen => {
+                problems.push(misplaced("must come before IDAT"));
+            }
+            _ => {}
+        }
+
+        self.in_idat = name == "IDAT";
+        if ! self.has_seen(name) {
+            self.seen.push(name.to_string());
+        }
+        problems
+    }
+
+    /// Rules broken by the whole image once every chunk is read
+    pub(super) fn finish(&self) -> Vec<DecodingErrorKind> {
+        let mut problems: Vec<DecodingErrorKind> = Vec::new();
+        if self.is_indexed() && ! self.has_seen("PLTE") && ! self.has_seen("IDAT") {
+            problems.push(DecodingErrorKind::MissingPalette);
+        }
+        problems
+    }
+}
+
+/// Check that a PNG file follows the specification: chunk ordering,
+/// critical chunks, palette, crcs, and image data that decodes completely.
+/// Returns every problem found, none for a conformant file; a problem that
+/// stops the decoding ends the list. Only I/O errors and limits exceeded
+/// are returned as errors.
+pub fn validate<R: Read>(reader: R, limits: &Limits) -> Result<Vec<DecodingError>, ImageError> {
+    let mut problems: Vec<DecodingError> = Vec::new();
+
+    match PngImage::read_checked(reader, limits, Some(ChunkValidator::new()), &mut problems) {
+        Ok(_) => {}
+        Err(ImageError::Decoding(e)) if ! e.kind.is_limits_exceeded() => problems.push(e),
+        Err(e) => return Err(e),
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+fn problems(chunks: &[(&str, &[u8])]) -> Vec<DecodingErrorKind> {
+    use super::png::build_png;
+
+    validate(&build_png(chunks)[..], &Limits::default()).unwrap().into_iter().map(|e| e.kind).collect()
+}
+
+#[test]
+fn test_validate() {
+    use super::png::build_png;
+    use crate::compress::zlib;
+
+    let indexed: &[u8] = &[0, 0, 0, 2, 0, 0, 0, 1, 1, 3, 0, 0, 0];
+    let gray: &[u8] = &[0, 0, 0, 2, 0, 0, 0, 1, 8, 0, 0, 0, 0];
+    let idat = zlib::compress(&[0, 0x40], 6);
+    let gray_idat = zlib::compress(&[0, 10, 20], 6);
+    let plte: &[u8] = &[0, 0, 0, 255, 255, 255];
+    let misplaced = |chunk: &str, rule: &'static str| DecodingErrorKind::MisplacedChunk { chunk: chunk.to_string(), rule };
+
+    let valid = [("IHDR", indexed), ("gAMA", &[0, 0, 177, 143][..]), ("PLTE", plte), ("tRNS", &[0][..]), ("IDAT", &idat[..3]), ("IDAT", &idat[3..]), ("tEXt", b"a\0b"), ("IEND", &[][..])];
+    assert_eq!(problems(&valid), vec!());
+    assert!(PngImage::read_image_strict(&build_png(&valid)[..], &Limits::default()).is_ok());
+
+    assert_eq!(problems(&[("IHDR", indexed), ("IDAT", &idat), ("PLTE", plte), ("IEND", &[])]),
+               vec!(DecodingErrorKind::MissingPalette, misplaced("PLTE", "must come before IDAT")));
+    assert_eq!(problems(&[("IHDR", indexed), ("PLTE", &[0; 9]), ("IDAT", &idat), ("IEND", &[])]),
+               vec!(DecodingErrorKind::InvalidPaletteSize { entries: 3, max: 2 }));
+    assert_eq!(problems(&[("IHDR", indexed), ("tRNS", &[0]), ("PLTE", plte), ("IDAT", &idat), ("IEND", &[])]),
+               vec!(misplaced("tRNS", "must come after PLTE")));
+    assert_eq!(problems(&[("IHDR", indexed), ("IEND", &[])]),
+               vec!(DecodingErrorKind::MissingPalette, DecodingErrorKind::MissingImageData));
+
+    assert_eq!(problems(&[("IHDR", gray), ("PLTE", plte), ("IDAT", &gray_idat), ("IEND", &[])]),
+               vec!(misplaced("PLTE", "is not allowed in gray images")));
+    assert_eq!(problems(&[("IHDR", gray), ("gAMA", &[0, 0, 177, 143]), ("gAMA", &[0, 0, 177, 143]), ("IDAT", &gray_idat), ("IEND", &[])]),
+               vec!(DecodingErrorKind::DuplicateChunk("gAMA".to_string())));
+    assert_eq!(problems(&[("IHDR", gray), ("sRGB", &[0]), ("iCCP", b"p\0\0"), ("IDAT", &gray_idat), ("IEND", &[])])[0],
+               misplaced("iCCP", "is not allowed with sRGB"));
+    assert_eq!(problems(&[("IHDR", gray), ("IDAT", &gray_idat), ("pHYs", &[0, 0, 0, 1, 0, 0, 0, 1, 0]), ("IEND", &[])]),
+               vec!(misplaced("pHYs", "must come before IDAT")));
+
+    let split = [("IHDR", gray), ("IDAT", &gray_idat[..4]), ("tEXt", b"a\0b"), ("IDAT", &gray_idat[4..]), ("IEND", &[])];
+    assert_eq!(problems(&split), vec!(DecodingErrorKind::NonConsecutiveImageData));
+    assert!(PngImage::read_image_lenient(&build_png(&split)[..], &Limits::default()).unwrap().1.is_empty());
+    let e = PngImage::read_image_strict(&build_png(&split)[..], &Limits::default()).unwrap_err();
+    assert_eq!((e.chunk(), e.offset()), (Some("IDAT"), Some(8 + 25 + 16 + 15)));
+
+    assert_eq!(problems(&[("IHDR", gray), ("IDAT", &gray_idat)]), vec!(DecodingErrorKind::MissingIEND));
+    assert_eq!(problems(&[("IHDR", gray), ("IDAT", &gray_idat), ("IEND", &[]), ("tEXt", b"a\0b")]), vec!(DecodingErrorKind::TrailingData));
+    assert_eq!(problems(&[("tEXt", b"a\0b"), ("IHDR", gray), ("IDAT", &gray_idat), ("IEND", &[])]), vec!(DecodingErrorKind::ChunkBeforeIHDR));
+    assert_eq!(problems(&[("IDAT", &gray_idat), ("IHDR", gray), ("IEND", &[])]), vec!(DecodingErrorKind::ChunkBeforeIHDR));
+}
@@ -0,0 +1,303 @@
+//! Color spaces declared by images, and conversion of their pixels to sRGB
+
+use crate::icc::Profile;
+
+/// 3x3 matrix, row major
+pub type Matrix3 = [[f64; 3]; 3];
+
+pub(crate) fn mul(a: &Matrix3, b: &Matrix3) -> Matrix3 {
+    let mut ret = [[0.0; 3]; 3];
+    for (i, row) in ret.iter_mut().enumerate() {
+        for (j, cell) in row.iter_mut().enumerate() {
+            *cell = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    ret
+}
+
+pub(crate) fn mul_vec(m: &Matrix3, v: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2])
+}
+
+/// None for a singular matrix
+pub(crate) fn invert(m: &Matrix3) -> Option<Matrix3> {
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+        let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let det = m[0][0] * cofactor(0, 0) + m[0][1] * cofactor(0, 1) + m[0][2] * cofactor(0, 2);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    // transposed cofactors over the determinant
+    let mut ret = [[0.0; 3]; 3];
+    for (i, row) in ret.iter_mut().enumerate() {
+        for (j, cell) in row.iter_mut().enumerate() {
+            *cell = cofactor(j, i) / det;
+        }
+    }
+    Some(ret)
+}
+
+/// XYZ of a white point with Y = 1
+pub(crate) fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+/// Bradford chromatic adaptation of XYZ from one white point to another
+pub(crate) fn adaptation(from: [f64; 3], to: [f64; 3]) -> Matrix3 {
+    const BRADFORD: Matrix3 = [
+        [0.8951, 0.2664, -0.1614],
+        [-0.7502, 1.7135, 0.0367],
+        [0.0389, -0.0685, 1.0296],
+    ];
+    let (from, to) = (mul_vec(&BRADFORD, from), mul_vec(&BRADFORD, to));
+    let scale = [[to[0] / from[0], 0.0, 0.0], [0.0, to[1] / from[1], 0.0], [0.0, 0.0, to[2] / from[2]]];
+    mul(&invert(&BRADFORD).unwrap(), &mul(&scale, &BRADFORD))
+}
+
+/// D65, the white point of sRGB
+pub const D65: (f64, f64) = (0.3127, 0.3290);
+
+/// Linear sRGB to XYZ, D65 white
+pub(crate) const SRGB_TO_XYZ: Matrix3 = [
+    [0.4124, 0.3576, 0.1805],
+    [0.2126, 0.7152, 0.0722],
+    [0.0193, 0.1192, 0.9505],
+];
+
+/// sRGB transfer function, from a linear value in 0..1 to 8 bits
+pub(crate) fn srgb_encode(linear: f64) -> u8 {
+    let v = linear.clamp(0.0, 1.0);
+    let v = if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 };
+    (v * 255.0).round() as u8
+}
+
+/// Inverse of the sRGB transfer function, from 0..1 to linear
+pub(crate) fn srgb_decode(v: f64) -> f64 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+/// CIE xy chromaticities of the white point and the primaries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chromaticities {
+    pub white: (f64, f64),
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+}
+
+impl Chromaticities {
+    pub const SRGB: Chromaticities = Chromaticities {
+        white: D65,
+        red: (0.64, 0.33),
+        green: (0.30, 0.60),
+        blue: (0.15, 0.06),
+    };
+
+    /// Linear RGB to XYZ, with the white of the chromaticities
+    pub fn rgb_to_xyz(&self) -> Option<Matrix3> {
+        let [r, g, b] = [self.red, self.green, self.blue].map(xy_to_xyz);
+        let primaries = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+        // scale each primary so that full RGB gives the white point
+        let s = mul_vec(&invert(&primaries)?, xy_to_xyz(self.white));
+        Some(primaries.map(|row| [row[0] * s[0], row[1] * s[1], row[2] * s[2]]))
+    }
+}
+
+/// How the color space should be mapped to another, from the ICC intents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderingIntent {
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    AbsoluteColorimetric,
+}
+
+impl RenderingIntent {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(RenderingIntent::Perceptual),
+            1 => Some(RenderingIntent::RelativeColorimetric),
+            2 => Some(RenderingIntent::Saturation),
+            3 => Some(RenderingIntent::AbsoluteColorimetric),
+            _ => None,
+        }
+    }
+}
+
+/// Embedded ICC profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IccProfile {
+    pub name: String,
+    /// Uncompressed profile
+    pub data: Vec<u8>,
+}
+
+/// Color space information declared by the image, all optional
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColorSpace {
+    /// Exponent applied to the linear light values when encoding, 1/2.2 is
+    /// stored as 0.45455
+    pub gamma: Option<f64>,
+    pub chromaticities: Option<Chromaticities>,
+    /// The samples are sRGB, to be rendered with this intent
+    pub srgb: Option<RenderingIntent>,
+    pub icc_profile: Option<IccProfile>,
+}
+
+impl ColorSpace {
+    /// Transform to sRGB, None when the samples are already sRGB or the
+    /// image does not declare its color space. An embedded profile takes
+    /// precedence, gAMA and cHRM are used when it cannot be handled.
+    pub fn srgb_transform(&self) -> Option<Transform> {
+        if self.srgb.is_some() {
+            return None;
+        }
+        if let Some(icc_profile) = &self.icc_profile {
+            match Profile::parse(&icc_profile.data) {
+                Ok(profile) => match profile.to_srgb() {
+                    Some(transform) => return Some(transform),
+                    None => warn!("ICC profile {} is not a matrix/TRC profile", icc_profile.name),
+                },
+                Err(e) => error!("Cannot parse ICC profile {}: {}", icc_profile.name, e),
+            }
+        }
+        if self.gamma.is_none() && self.chromaticities.is_none() {
+            return None;
+        }
+
+        let decode: Vec<f64> = match self.gamma {
+            Some(gamma) if gamma > 0.0 => (0..256).map(|v| (v as f64 / 255.0).powf(1.0 / gamma)).collect(),
+            _ => (0..256).map(|v| srgb_decode(v as f64 / 255.0)).collect(),
+        };
+        let matrix = self.chromaticities
+            .filter(|c| *c != Chromaticities::SRGB)
+            .and_then(|c| {
+                let to_xyz = mul(&adaptation(xy_to_xyz(c.white), xy_to_xyz(D65)), &c.rgb_to_xyz()?);
+                Some(mul(&invert(&SRGB_TO_XYZ)?, &to_xyz))
+            });
+
+        Some(Transform { decode: [decode.clone(), decode.clone(), decode], matrix, encode: [Encoding::Srgb, Encoding::Srgb, Encoding::Srgb] })
+    }
+}
+
+/// Transfer function from linear values to 8 bits samples
+#[derive(Debug, Clone, PartialEq)]
+pub enum Encoding {
+    Srgb,
+    /// Linear value of evenly spaced samples from 0 to 1, increasing, and
+    /// inverted by search
+    Table(Vec<f64>),
+}
+
+impl Encoding {
+    pub fn encode(&self, linear: f64) -> u8 {
+        match self {
+            Encoding::Srgb => srgb_encode(linear),
+            Encoding::Table(table) if table.len() >= 2 => {
+                let i = table.partition_point(|&v| v < linear).clamp(1, table.len() - 1);
+                let (low, high) = (table[i - 1], table[i]);
+                let frac = if high > low { ((linear - low) / (high - low)).clamp(0.0, 1.0) } else { 0.0 };
+                let v = (i - 1) as f64 + frac;
+                (v / (table.len() - 1) as f64 * 255.0).round() as u8
+            }
+            Encoding::Table(_) => (linear.clamp(0.0, 1.0) * 255.0).round() as u8,
+        }
+    }
+}
+
+/// Conversion of 8 bits samples between color spaces: each channel is made
+/// linear through its curve, then mixed by the matrix and encoded again
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transform {
+    /// Linear value of the 256 sample values, for each channel
+    pub decode: [Vec<f64>; 3],
+    /// Linear input RGB to linear output RGB, None when the primaries are
+    /// the same
+    pub matrix: Option<Matrix3>,
+    pub encode: [Encoding; 3],
+}
+
+impl Transform {
+    /// Convert in place the RGB pixels, `channels` apart to skip alpha
+    pub fn apply_rgb(&self, data: &mut [u8], channels: usize) {
+        for p in data.chunks_exact_mut(channels) {
+            let linear = [0, 1, 2].map(|c| self.decode[c][p[c] as usize]);
+            let linear = match &self.matrix {
+                Some(m) => mul_vec(m, linear),
+                None => linear,
+            };
+            for (c, encoding) in self.encode.iter().enumerate() {
+                p[c] = encoding.encode(linear[c]);
+            }
+        }
+    }
+
+    /// Convert in place gray samples, `channels` apart to skip alpha
+    pub fn apply_gray(&self, data: &mut [u8], channels: usize) {
+        let lut: Vec<u8> = self.decode[0].iter().map(|&v| self.encode[0].encode(v)).collect();
+        for p in data.chunks_exact_mut(channels) {
+            p[0] = lut[p[0] as usize];
+        }
+    }
+}
+
+#[test]
+fn test_matrices() {
+    let m = [[2.0, 0.0, 1.0], [1.0, 3.0, 0.0], [0.0, 1.0, 4.0]];
+    let identity = mul(&m, &invert(&m).unwrap());
+    for (i, row) in identity.iter().enumerate() {
+        for (j, v) in row.iter().enumerate() {
+            assert!((v - if i == j { 1.0 } else { 0.0 }).abs() < 1e-12);
+        }
+    }
+    assert_eq!(invert(&[[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 0.0, 1.0]]), None);
+
+    // the sRGB chromaticities give back the sRGB matrix
+    let to_xyz = Chromaticities::SRGB.rgb_to_xyz().unwrap();
+    for (row, expected) in to_xyz.iter().zip(SRGB_TO_XYZ.iter()) {
+        for (v, e) in row.iter().zip(expected.iter()) {
+            assert!((v - e).abs() < 1e-3, "{:?}", to_xyz);
+        }
+    }
+
+    // adapting a white to another gives the other
+    let d50 = xy_to_xyz((0.3457, 0.3585));
+    let adapted = mul_vec(&adaptation(d50, xy_to_xyz(D65)), d50);
+    for (a, e) in adapted.iter().zip(xy_to_xyz(D65).iter()) {
+        assert!((a - e).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn test_srgb_transform() {
+    assert_eq!(ColorSpace::default().srgb_transform(), None);
+    let srgb = ColorSpace { srgb: Some(RenderingIntent::Perceptual), gamma: Some(0.45455), ..ColorSpace::default() };
+    assert_eq!(srgb.srgb_transform(), None);
+
+    for v in 0..=255u8 {
+        assert_eq!(srgb_encode(srgb_decode(v as f64 / 255.0)), v);
+    }
+
+    // linear samples get brighter, extremes are kept
+    let linear = ColorSpace { gamma: Some(1.0), ..ColorSpace::default() }.srgb_transform().unwrap();
+    let mut rgb = [0, 0, 0, 255, 255, 255, 50, 128, 200];
+    linear.apply_rgb(&mut rgb, 3);
+    assert_eq!(rgb, [0, 0, 0, 255, 255, 255, 122, 188, 229]);
+
+    let mut gray_alpha = [128, 7];
+    linear.apply_gray(&mut gray_alpha, 2);
+    assert_eq!(gray_alpha, [188, 7]);
+
+    // pure green of a wider gamut is out of the sRGB gamut
+    let wide = ColorSpace {
+        chromaticities: Some(Chromaticities { white: D65, red: (0.64, 0.33), green: (0.21, 0.71), blue: (0.15, 0.06) }),
+        ..ColorSpace::default()
+    };
+    let mut rgb = [0, 255, 0, 255, 255, 255, 10, 10, 10];
+    wide.srgb_transform().unwrap().apply_rgb(&mut rgb, 3);
+    assert_eq!(rgb[..3], [0, 255, 0]);
+    assert_eq!(rgb[3..], [255, 255, 255, 10, 10, 10]);
+}
@@ -0,0 +1,406 @@
+use super::huffman::{canonical_codes, code_lengths, reverse_bits};
+use super::inflate::{LENGTH_BASE, LENGTH_EXTRA, DISTANCE_BASE, DISTANCE_EXTRA, CODE_LENGTH_ORDER, fixed_lengths};
+use super::lz77::{tokenize, Token, MatchParams};
+
+const WINDOW_SIZE: usize = 32768;
+const MAX_STORED_BLOCK: usize = 65535;
+/// Blocks are first cut at this many tokens, then split further if it helps
+const MAX_BLOCK_TOKENS: usize = 16384;
+const MIN_SPLIT_TOKENS: usize = 1024;
+const END_OF_BLOCK: usize = 256;
+
+/// Block split depth for each compression level
+const SPLIT_DEPTHS: [u32; 10] = [0, 0, 0, 1, 1, 2, 2, 3, 3, 4];
+
+struct BitWriter {
+    out: Vec<u8>,
+    bit_buf: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            out: Vec::new(),
+            bit_buf: 0,
+            bit_count: 0,
+        }
+    }
+
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bit_buf |= (value as u64) << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.out.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    fn write_code(&mut self, code: u16, len: u8) {
+        self.write_bits(reverse_bits(code, len) as u32, len as u32);
+    }
+
+    fn align(&mut self) {
+        if self.bit_count > 0 {
+            self.write_bits(0, 8 - self.bit_count);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.out
+    }
+}
+
+fn length_symbol(length: u16) -> usize {
+    LENGTH_BASE.partition_point(|base| *base <= length) - 1
+}
+
+fn distance_symbol(distance: u16) -> usize {
+    DISTANCE_BASE.partition_point(|base| *base <= distance) - 1
+}
+
+/// Make sure at least two symbols are used, so that the Huffman code is complete
+fn ensure_two_symbols(freqs: &mut [u32]) {
+    let mut i = 0;
+    while freqs.iter().filter(|f| **f > 0).count() < 2 {
+        if freqs[i] == 0 {
+            freqs[i] = 1;
+        }
+        i += 1;
+    }
+}
+
+/// Code length alphabet encoding of the literal/length and distance code
+/// lengths, as (symbol, extra bits value, extra bits count)
+fn run_length_encode(lengths: &[u8]) -> Vec<(u8, u8, u8)> {
+    let mut ret: Vec<(u8, u8, u8)> = Vec::new();
+    let mut i = 0;
+
+    while i < lengths.len() {
+        let l = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|x| **x == l).count();
+        i += run;
+
+        if l == 0 {
+            while run >= 11 {
+                let r = run.min(138);
+                ret.push((18, (r - 11) as u8, 7));
+                run -= r;
+            }
+            if run >= 3 {
+                ret.push((17, (run - 3) as u8, 3));
+                run = 0;
+            }
+        } else {
+            ret.push((l, 0, 0));
+            run -= 1;
+            while run >= 3 {
+                let r = run.min(6);
+                ret.push((16, (r - 3) as u8, 2));
+                run -= r;
+            }
+        }
+        for _ in 0..run {
+            ret.push((l, 0, 0));
+        }
+    }
+    ret
+}
+
+/// Literal/length and distance code lengths of a block
+struct BlockCodes {
+    lit_lengths: Vec<u8>,
+    dist_lengths: Vec<u8>,
+}
+
+impl BlockCodes {
+    fn fixed() -> Self {
+        let (lit_lengths, dist_lengths) = fixed_lengths();
+        BlockCodes { lit_lengths, dist_lengths }
+    }
+
+    fn dynamic(tokens: &[Token]) -> Self {
+        let (mut lit_freqs, mut dist_freqs) = frequencies(tokens);
+        ensure_two_symbols(&mut lit_freqs);
+        ensure_two_symbols(&mut dist_freqs);
+
+        BlockCodes {
+            lit_lengths: code_lengths(&lit_freqs, 15),
+            dist_lengths: code_lengths(&dist_freqs, 15),
+        }
+    }
+
+    fn hlit(&self) -> usize {
+        self.lit_lengths.iter().rposition(|l| *l > 0).unwrap_or(0).max(END_OF_BLOCK) + 1
+    }
+
+    fn hdist(&self) -> usize {
+        self.dist_lengths.iter().rposition(|l| *l > 0).unwrap_or(0) + 1
+    }
+
+    /// Code length code lengths, the encoded lengths and the number of code
+    /// length codes written in the header
+    fn header(&self) -> (Vec<u8>, Vec<(u8, u8, u8)>, usize) {
+        let mut all: Vec<u8> = self.lit_lengths[..self.hlit()].to_vec();
+        all.extend_from_slice(&self.dist_lengths[..self.hdist()]);
+        let rle = run_length_encode(&all);
+
+        let mut freqs = [0u32; 19];
+        for (symbol, _, _) in &rle {
+            freqs[*symbol as usize] += 1;
+        }
+        ensure_two_symbols(&mut freqs);
+        let cl_lengths = code_lengths(&freqs, 7);
+
+        let hclen = CODE_LENGTH_ORDER.iter().rposition(|i| cl_lengths[*i] > 0).unwrap_or(0).max(3) + 1;
+        (cl_lengths, rle, hclen)
+    }
+
+    fn header_cost(&self) -> usize {
+        let (cl_lengths, rle, hclen) = self.header();
+        let rle_cost: usize = rle.iter().map(|(s, _, extra)| cl_lengths[*s as usize] as usize + *extra as usize).sum();
+        5 + 5 + 4 + 3 * hclen + rle_cost
+    }
+
+    fn data_cost(&self, tokens: &[Token]) -> usize {
+        let (lit_freqs, dist_freqs) = frequencies(tokens);
+        let lit: usize = lit_freqs.iter().enumerate()
+            .map(|(s, f)| {
+                let extra = if s > END_OF_BLOCK { LENGTH_EXTRA[s - 257] as usize } else { 0 };
+                *f as usize * (self.lit_lengths[s] as usize + extra)
+            })
+            .sum();
+        let dist: usize = dist_freqs.iter().enumerate()
+            .map(|(s, f)| *f as usize * (self.dist_lengths[s] as usize + DISTANCE_EXTRA[s] as usize))
+            .sum();
+        lit + dist
+    }
+}
+
+fn frequencies(tokens: &[Token]) -> (Vec<u32>, Vec<u32>) {
+    let mut lit_freqs: Vec<u32> = vec!(0; 286);
+    let mut dist_freqs: Vec<u32> = vec!(0; 30);
+
+    for token in tokens {
+        match token {
+            Token::Literal(b) => lit_freqs[*b as usize] += 1,
+            Token::Match { distance, length } => {
+                lit_freqs[257 + length_symbol(*length)] += 1;
+                dist_freqs[distance_symbol(*distance)] += 1;
+            }
+        }
+    }
+    lit_freqs[END_OF_BLOCK] = 1;
+    (lit_freqs, dist_freqs)
+}
+
+#[derive(Debug, PartialEq)]
+enum BlockType {
+    Stored,
+    Fixed,
+    Dynamic,
+}
+
+/// Cheapest encoding of a block and its size in bits
+fn block_cost(tokens: &[Token], raw_len: usize) -> (BlockType, usize) {
+    let nb_stored = raw_len.div_ceil(MAX_STORED_BLOCK).max(1);
+    let stored = (raw_len + 5 * nb_stored) * 8;
+
+    let fixed = 3 + BlockCodes::fixed().data_cost(tokens);
+
+    let codes = BlockCodes::dynamic(tokens);
+    let dynamic = 3 + codes.header_cost() + codes.data_cost(tokens);
+
+    if stored <= fixed && stored <= dynamic {
+        (BlockType::Stored, stored)
+    } else if fixed <= dynamic {
+        (BlockType::Fixed, fixed)
+    } else {
+        (BlockType::Dynamic, dynamic)
+    }
+}
+
+fn raw_length(tokens: &[Token]) -> usize {
+    tokens.iter()
+        .map(|t| match t {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => *length as usize,
+        })
+        .sum()
+}
+
+/// Split a block in halves while it makes the output smaller
+fn split_block(tokens: &[Token], depth: u32, blocks: &mut Vec<usize>) {
+    if depth > 0 && tokens.len() >= 2 * MIN_SPLIT_TOKENS {
+        let (left, right) = tokens.split_at(tokens.len() / 2);
+        let whole = block_cost(tokens, raw_length(tokens)).1;
+        let halves = block_cost(left, raw_length(left)).1 + block_cost(right, raw_length(right)).1;
+
+        if halves < whole {
+            split_block(left, depth - 1, blocks);
+            split_block(right, depth - 1, blocks);
+            return;
+        }
+    }
+    blocks.push(tokens.len());
+}
+
+fn write_tokens(w: &mut BitWriter, tokens: &[Token], codes: &BlockCodes) {
+    let lit_codes = canonical_codes(&codes.lit_lengths);
+    let dist_codes = canonical_codes(&codes.dist_lengths);
+
+    for token in tokens {
+        match token {
+            Token::Literal(b) => w.write_code(lit_codes[*b as usize], codes.lit_lengths[*b as usize]),
+            Token::Match { distance, length } => {
+                let l = length_symbol(*length);
+                w.write_code(lit_codes[257 + l], codes.lit_lengths[257 + l]);
+                w.write_bits((*length - LENGTH_BASE[l]) as u32, LENGTH_EXTRA[l] as u32);
+
+                let d = distance_symbol(*distance);
+                w.write_code(dist_codes[d], codes.dist_lengths[d]);
+                w.write_bits((*distance - DISTANCE_BASE[d]) as u32, DISTANCE_EXTRA[d] as u32);
+            }
+        }
+    }
+    w.write_code(lit_codes[END_OF_BLOCK], codes.lit_lengths[END_OF_BLOCK]);
+}
+
+fn write_stored(w: &mut BitWriter, raw: &[u8], last: bool) {
+    let mut chunks: Vec<&[u8]> = raw.chunks(MAX_STORED_BLOCK).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    let nb_chunks = chunks.len();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        w.write_bits((last && i + 1 == nb_chunks) as u32, 1);
+        w.write_bits(0, 2);
+        w.align();
+        let len = chunk.len() as u16;
+        w.write_bits(len as u32, 16);
+        w.write_bits(! len as u32, 16);
+        for b in chunk {
+            w.write_bits(*b as u32, 8);
+        }
+    }
+}
+
+fn write_block(w: &mut BitWriter, tokens: &[Token], raw: &[u8], last: bool) {
+    let (block_type, _) = block_cost(tokens, raw.len());
+    debug!("deflate block: {:?}, {} tokens, {} bytes", block_type, tokens.len(), raw.len());
+
+    match block_type {
+        BlockType::Stored => write_stored(w, raw, last),
+        BlockType::Fixed => {
+            w.write_bits(last as u32, 1);
+            w.write_bits(1, 2);
+            write_tokens(w, tokens, &BlockCodes::fixed());
+        }
+        BlockType::Dynamic => {
+            let codes = BlockCodes::dynamic(tokens);
+            let (cl_lengths, rle, hclen) = codes.header();
+            let cl_codes = canonical_codes(&cl_lengths);
+
+            w.write_bits(last as u32, 1);
+            w.write_bits(2, 2);
+            w.write_bits((codes.hlit() - 257) as u32, 5);
+            w.write_bits((codes.hdist() - 1) as u32, 5);
+            w.write_bits((hclen - 4) as u32, 4);
+            for i in CODE_LENGTH_ORDER.iter().take(hclen) {
+                w.write_bits(cl_lengths[*i] as u32, 3);
+            }
+            for (symbol, extra, extra_bits) in rle {
+                w.write_code(cl_codes[symbol as usize], cl_lengths[symbol as usize]);
+                w.write_bits(extra as u32, extra_bits as u32);
+            }
+            write_tokens(w, tokens, &codes);
+        }
+    }
+}
+
+/// Compress `data` as a raw RFC 1951 DEFLATE stream, `level` goes from 0
+/// (stored blocks only) to 9
+pub fn deflate(data: &[u8], level: u8) -> Vec<u8> {
+    let level = level.min(9);
+    let mut w = BitWriter::new();
+
+    if level == 0 {
+        write_stored(&mut w, data, true);
+        return w.finish();
+    }
+
+    let tokens = tokenize(data, WINDOW_SIZE, &MatchParams::for_level(level));
+    let split_depth = SPLIT_DEPTHS[level as usize];
+
+    let mut blocks: Vec<usize> = Vec::new();
+    for chunk in tokens.chunks(MAX_BLOCK_TOKENS) {
+        split_block(chunk, split_depth, &mut blocks);
+    }
+    if blocks.is_empty() {
+        blocks.push(0);
+    }
+
+    let mut pos = 0;
+    let mut raw_pos = 0;
+    let nb_blocks = blocks.len();
+    for (i, len) in blocks.into_iter().enumerate() {
+        let block = &tokens[pos..pos + len];
+        let raw_len = raw_length(block);
+        write_block(&mut w, block, &data[raw_pos..raw_pos + raw_len], i + 1 == nb_blocks);
+        pos += len;
+        raw_pos += raw_len;
+    }
+
+    w.finish()
+}
+
+#[test]
+fn test_length_distance_symbols() {
+    assert_eq!(length_symbol(3), 0);
+    assert_eq!(length_symbol(10), 7);
+    assert_eq!(length_symbol(12), 8);
+    assert_eq!(length_symbol(257), 27);
+    assert_eq!(length_symbol(258), 28);
+    assert_eq!(distance_symbol(1), 0);
+    assert_eq!(distance_symbol(6), 4);
+    assert_eq!(distance_symbol(32768), 29);
+}
+
+#[test]
+fn test_run_length_encode() {
+    assert_eq!(run_length_encode(&[0, 0]), vec!((0, 0, 0), (0, 0, 0)));
+    assert_eq!(run_length_encode(&[0; 10]), vec!((17, 7, 3)));
+    assert_eq!(run_length_encode(&[0; 150]), vec!((18, 127, 7), (18, 1, 7)));
+    assert_eq!(run_length_encode(&[0; 140]), vec!((18, 127, 7), (0, 0, 0), (0, 0, 0)));
+    assert_eq!(run_length_encode(&[5; 8]), vec!((5, 0, 0), (16, 3, 2), (5, 0, 0)));
+    assert_eq!(run_length_encode(&[4, 4, 4, 0, 0, 0]), vec!((4, 0, 0), (4, 0, 0), (4, 0, 0), (17, 0, 3)));
+}
+
+#[test]
+fn test_deflate_roundtrip() {
+    use super::inflate::inflate;
+
+    let text: Vec<u8> = b"Blah blah blah blah blah! Hello friend, Hello world! ".iter().cycle().take(20000).copied().collect();
+    let noise: Vec<u8> = (0..20000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    let inputs: Vec<&[u8]> = vec!(b"", b"a", b"AABCBBABC", &text, &noise);
+
+    for data in inputs {
+        for level in 0..=9 {
+            let compressed = deflate(data, level);
+            assert_eq!(inflate(&compressed).unwrap(), data, "level {}", level);
+            assert_eq!(miniz_oxide::inflate::decompress_to_vec(&compressed).unwrap(), data, "level {}", level);
+        }
+    }
+}
+
+#[test]
+fn test_deflate_ratio() {
+    let text: Vec<u8> = b"Blah blah blah blah blah! Hello friend, Hello world! ".iter().cycle().take(20000).copied().collect();
+    assert!(deflate(&text, 6).len() < 200);
+    // incompressible data falls back to stored blocks
+    let noise: Vec<u8> = (0..20000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    assert!(deflate(&noise, 6).len() <= noise.len() + 5);
+}
@@ -0,0 +1,202 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::error::*;
+
+pub const MAX_CODE_LENGTH: usize = 15;
+
+/// Canonical Huffman codes (RFC 1951 3.2.2) for the given code lengths,
+/// a length of 0 means the symbol is not used.
+pub fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut bl_count = [0u16; MAX_CODE_LENGTH + 1];
+    for l in lengths {
+        bl_count[*l as usize] += 1;
+    }
+    bl_count[0] = 0;
+
+    let mut next_code = [0u16; MAX_CODE_LENGTH + 1];
+    let mut code: u16 = 0;
+    for bits in 1..=MAX_CODE_LENGTH {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+
+    lengths.iter()
+        .map(|l| {
+            if *l == 0 {
+                return 0;
+            }
+            let c = next_code[*l as usize];
+            next_code[*l as usize] += 1;
+            c
+        })
+        .collect()
+}
+
+/// Huffman code lengths for the given symbol frequencies, no code is longer
+/// than `max_length`. Unused symbols get a length of 0.
+pub fn code_lengths(freqs: &[u32], max_length: u8) -> Vec<u8> {
+    let mut freqs: Vec<u32> = freqs.to_vec();
+
+    loop {
+        let lengths = tree_depths(&freqs);
+        if lengths.iter().all(|l| *l <= max_length) {
+            return lengths;
+        }
+        // flatten the distribution until the tree is shallow enough
+        for f in freqs.iter_mut().filter(|f| **f > 0) {
+            *f = (*f >> 1).max(1);
+        }
+    }
+}
+
+fn tree_depths(freqs: &[u32]) -> Vec<u8> {
+    let mut lengths: Vec<u8> = vec!(0; freqs.len());
+    let used: Vec<usize> = (0..freqs.len()).filter(|i| freqs[*i] > 0).collect();
+
+    if used.len() == 1 {
+        lengths[used[0]] = 1;
+    }
+    if used.len() < 2 {
+        return lengths;
+    }
+
+    // leaves are 0..used.len(), internal nodes are appended to `parents`
+    let mut parents: Vec<usize> = vec!(usize::MAX; used.len());
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = used.iter().enumerate()
+        .map(|(node, symbol)| Reverse((freqs[*symbol] as u64, node)))
+        .collect();
+
+    while heap.len() > 1 {
+        let Reverse((fa, a)) = heap.pop().unwrap();
+        let Reverse((fb, b)) = heap.pop().unwrap();
+        let node = parents.len();
+        parents.push(usize::MAX);
+        parents[a] = node;
+        parents[b] = node;
+        heap.push(Reverse((fa + fb, node)));
+    }
+
+    for (leaf, symbol) in used.iter().enumerate() {
+        let mut depth: usize = 0;
+        let mut node = leaf;
+        while parents[node] != usize::MAX {
+            node = parents[node];
+            depth += 1;
+        }
+        lengths[*symbol] = depth.min(u8::MAX as usize) as u8;
+    }
+    lengths
+}
+
+/// Reverse the `len` low bits of `code`, Huffman codes are packed starting
+/// with their most significant bit
+pub fn reverse_bits(code: u16, len: u8) -> u16 {
+    code.reverse_bits() >> (16 - len as u32)
+}
+
+/// Single level decoding table indexed by the next `bits` bits of the stream
+#[derive(Debug)]
+pub struct HuffmanTable {
+    /// (symbol, code length), a code length of 0 marks an unused code
+    entries: Vec<(u16, u8)>,
+    bits: u8,
+}
+
+impl HuffmanTable {
+    pub fn new(lengths: &[u8]) -> Result<HuffmanTable, ImageError> {
+        let bits = lengths.iter().copied().max().unwrap_or(0);
+        if bits as usize > MAX_CODE_LENGTH {
+            return Err(ImageError::Inflate(InflateError::new("Huffman code too long")));
+        }
+
+        // Kraft inequality, an over-subscribed set of lengths is not a prefix code
+        let mut left: i32 = 1;
+        let mut bl_count = [0i32; MAX_CODE_LENGTH + 1];
+        for l in lengths.iter().filter(|l| **l > 0) {
+            bl_count[*l as usize] += 1;
+        }
+        for count in bl_count.iter().skip(1) {
+            left = (left << 1) - count;
+            if left < 0 {
+                return Err(ImageError::Inflate(InflateError::new("Over-subscribed Huffman code")));
+            }
+        }
+
+        let mut entries: Vec<(u16, u8)> = vec!((0, 0); 1 << bits);
+        let codes = canonical_codes(lengths);
+        for (symbol, (code, len)) in codes.iter().zip(lengths).enumerate() {
+            if *len == 0 {
+                continue;
+            }
+            let mut i = reverse_bits(*code, *len) as usize;
+            while i < entries.len() {
+                entries[i] = (symbol as u16, *len);
+                i += 1 << len;
+            }
+        }
+
+        Ok(HuffmanTable { entries, bits })
+    }
+
+    /// Number of bits to peek before calling `decode`
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Symbol and code length for the peeked bits
+    pub fn decode(&self, peeked: u32) -> Result<(u16, u8), ImageError> {
+        let entry = self.entries[(peeked as usize) & (self.entries.len() - 1)];
+        if entry.1 == 0 {
+            return Err(ImageError::Inflate(InflateError::new("Invalid Huffman code")));
+        }
+        Ok(entry)
+    }
+}
+
+#[test]
+fn test_canonical_codes() {
+    // RFC 1951 3.2.2 example
+    assert_eq!(canonical_codes(&[3, 3, 3, 3, 3, 2, 4, 4]),
+               vec!(0b010, 0b011, 0b100, 0b101, 0b110, 0b00, 0b1110, 0b1111));
+    assert_eq!(canonical_codes(&[2, 1, 3, 3]), vec!(0b10, 0b0, 0b110, 0b111));
+    assert_eq!(canonical_codes(&[0, 1, 0, 1]), vec!(0, 0, 0, 1));
+}
+
+#[test]
+fn test_huffman_table() {
+    let table = HuffmanTable::new(&[2, 1, 3, 3]).unwrap();
+    assert_eq!(table.bits(), 3);
+    // codes are read least significant bit first
+    assert_eq!(table.decode(0b000).unwrap(), (1, 1));
+    assert_eq!(table.decode(0b110).unwrap(), (1, 1));
+    assert_eq!(table.decode(0b001).unwrap(), (0, 2));
+    assert_eq!(table.decode(0b011).unwrap(), (2, 3));
+    assert_eq!(table.decode(0b111).unwrap(), (3, 3));
+
+    assert!(HuffmanTable::new(&[1, 1, 1]).is_err());
+
+    let incomplete = HuffmanTable::new(&[0, 1]).unwrap();
+    assert_eq!(incomplete.decode(0).unwrap(), (1, 1));
+    assert!(incomplete.decode(1).is_err());
+}
+
+#[test]
+fn test_code_lengths() {
+    assert_eq!(code_lengths(&[0, 0, 0], 15), vec!(0, 0, 0));
+    assert_eq!(code_lengths(&[0, 5, 0], 15), vec!(0, 1, 0));
+    assert_eq!(code_lengths(&[1, 1], 15), vec!(1, 1));
+    assert_eq!(code_lengths(&[10, 1, 1, 0, 2], 15), vec!(1, 3, 3, 0, 2));
+
+    // fibonacci frequencies give the deepest possible tree
+    let mut fib: Vec<u32> = vec!(1, 1);
+    for i in 2..30 {
+        fib.push(fib[i - 1] + fib[i - 2]);
+    }
+    let lengths = code_lengths(&fib, 15);
+    assert!(lengths.iter().all(|l| *l > 0 && *l <= 15));
+    // still a complete prefix code
+    let kraft: f64 = lengths.iter().map(|l| 0.5f64.powi(*l as i32)).sum();
+    assert_eq!(kraft, 1.0);
+    assert!(HuffmanTable::new(&lengths).is_ok());
+}
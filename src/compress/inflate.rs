@@ -0,0 +1,396 @@
+use std::io;
+use std::io::Read;
+
+use crate::error::*;
+use super::huffman::HuffmanTable;
+
+const WINDOW_SIZE: usize = 32768;
+const INPUT_BUFFER_SIZE: usize = 4096;
+
+pub const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+pub const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+pub const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+pub const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Order of the code length code lengths in a dynamic block header
+pub const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Code lengths of the fixed Huffman literal/length and distance codes
+pub fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut lit: Vec<u8> = vec!(8; 288);
+    for l in lit.iter_mut().take(256).skip(144) {
+        *l = 9;
+    }
+    for l in lit.iter_mut().take(280).skip(256) {
+        *l = 7;
+    }
+    (lit, vec!(5; 30))
+}
+
+#[derive(Debug)]
+enum State {
+    BlockHeader,
+    Stored(usize),
+    Huffman(HuffmanTable, HuffmanTable),
+    Done,
+}
+
+/// RFC 1951 DEFLATE decoder reading compressed data from `R`
+pub struct Inflater<R: Read> {
+    reader: R,
+    input: Vec<u8>,
+    input_pos: usize,
+    bit_buf: u64,
+    bit_count: u32,
+    window: Vec<u8>,
+    total_out: usize,
+    state: State,
+    final_block: bool,
+    copy_len: usize,
+    copy_dist: usize,
+}
+
+impl<R: Read> Inflater<R> {
+    pub fn new(reader: R) -> Self {
+        Inflater {
+            reader,
+            input: Vec::with_capacity(INPUT_BUFFER_SIZE),
+            input_pos: 0,
+            bit_buf: 0,
+            bit_count: 0,
+            window: vec!(0; WINDOW_SIZE),
+            total_out: 0,
+            state: State::BlockHeader,
+            final_block: false,
+            copy_len: 0,
+            copy_dist: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Give back the underlying reader, input already buffered is lost
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Whether the final block has been fully decoded
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done) && self.copy_len == 0
+    }
+
+    fn fill_input(&mut self) -> Result<bool, ImageError> {
+        self.input.resize(INPUT_BUFFER_SIZE, 0);
+        let n = loop {
+            match self.reader.read(&mut self.input) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        self.input.truncate(n);
+        self.input_pos = 0;
+        Ok(n > 0)
+    }
+
+    /// Try to have at least `count` bits in the bit buffer
+    fn refill(&mut self, count: u32) -> Result<(), ImageError> {
+        while self.bit_count < count {
+            if self.input_pos >= self.input.len() && ! self.fill_input()? {
+                return Ok(());
+            }
+            self.bit_buf |= (self.input[self.input_pos] as u64) << self.bit_count;
+            self.input_pos += 1;
+            self.bit_count += 8;
+        }
+        Ok(())
+    }
+
+    fn peek_bits(&mut self, count: u32) -> Result<u32, ImageError> {
+        self.refill(count)?;
+        Ok((self.bit_buf & ((1u64 << count) - 1)) as u32)
+    }
+
+    fn consume_bits(&mut self, count: u32) -> Result<(), ImageError> {
+        if count > self.bit_count {
+            return Err(ImageError::Inflate(InflateError::new("Unexpected end of compressed stream")));
+        }
+        self.bit_buf >>= count;
+        self.bit_count -= count;
+        Ok(())
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u32, ImageError> {
+        let bits = self.peek_bits(count)?;
+        self.consume_bits(count)?;
+        Ok(bits)
+    }
+
+    fn decode_symbol(&mut self, table: &HuffmanTable) -> Result<u16, ImageError> {
+        let peeked = self.peek_bits(table.bits() as u32)?;
+        let (symbol, len) = table.decode(peeked)?;
+        self.consume_bits(len as u32)?;
+        Ok(symbol)
+    }
+
+    /// Read whole bytes, skipping the bits left in the current byte.
+    /// Used by the containers for their header and trailer.
+    pub fn read_aligned(&mut self, buf: &mut [u8]) -> Result<(), ImageError> {
+        let skip = self.bit_count % 8;
+        self.consume_bits(skip)?;
+        for b in buf.iter_mut() {
+            *b = self.read_bits(8)? as u8;
+        }
+        Ok(())
+    }
+
+    fn push_output(&mut self, byte: u8) {
+        self.window[self.total_out % WINDOW_SIZE] = byte;
+        self.total_out += 1;
+    }
+
+    fn read_dynamic_tables(&mut self) -> Result<(HuffmanTable, HuffmanTable), ImageError> {
+        let hlit = self.read_bits(5)? as usize + 257;
+        let hdist = self.read_bits(5)? as usize + 1;
+        let hclen = self.read_bits(4)? as usize + 4;
+        if hlit > 286 || hdist > 30 {
+            return Err(ImageError::Inflate(InflateError::new("Too many length or distance codes")));
+        }
+
+        let mut code_length_lengths = [0u8; 19];
+        for i in CODE_LENGTH_ORDER.iter().take(hclen) {
+            code_length_lengths[*i] = self.read_bits(3)? as u8;
+        }
+        let code_length_table = HuffmanTable::new(&code_length_lengths)?;
+
+        let mut lengths: Vec<u8> = Vec::with_capacity(hlit + hdist);
+        while lengths.len() < hlit + hdist {
+            let symbol = self.decode_symbol(&code_length_table)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    let prev = *lengths.last()
+                        .ok_or_else(|| ImageError::Inflate(InflateError::new("Repeat code with no previous length")))?;
+                    (prev, 3 + self.read_bits(2)? as usize)
+                }
+                17 => (0, 3 + self.read_bits(3)? as usize),
+                _ => (0, 11 + self.read_bits(7)? as usize),
+            };
+            if lengths.len() + repeat > hlit + hdist {
+                return Err(ImageError::Inflate(InflateError::new("Code lengths overflow")));
+            }
+            lengths.extend(std::iter::repeat_n(value, repeat));
+        }
+
+        if lengths[256] == 0 {
+            return Err(ImageError::Inflate(InflateError::new("Missing end of block code")));
+        }
+
+        let lit_table = HuffmanTable::new(&lengths[..hlit])?;
+        let dist_table = HuffmanTable::new(&lengths[hlit..])?;
+        Ok((lit_table, dist_table))
+    }
+
+    fn read_block_header(&mut self) -> Result<(), ImageError> {
+        if self.final_block {
+            self.state = State::Done;
+            return Ok(());
+        }
+
+        self.final_block = self.read_bits(1)? == 1;
+        let block_type = self.read_bits(2)?;
+        debug!("deflate block: type {}, final {}", block_type, self.final_block);
+
+        self.state = match block_type {
+            0 => {
+                let mut header = [0u8; 4];
+                self.read_aligned(&mut header)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != ! nlen {
+                    return Err(ImageError::Inflate(InflateError::new("Stored block length mismatch")));
+                }
+                State::Stored(len as usize)
+            }
+            1 => {
+                let (lit, dist) = fixed_lengths();
+                State::Huffman(HuffmanTable::new(&lit)?, HuffmanTable::new(&dist)?)
+            }
+            2 => {
+                let (lit, dist) = self.read_dynamic_tables()?;
+                State::Huffman(lit, dist)
+            }
+            _ => return Err(ImageError::Inflate(InflateError::new("Invalid block type"))),
+        };
+        Ok(())
+    }
+
+    fn read_match(&mut self, symbol: u16, dist_table: &HuffmanTable) -> Result<(), ImageError> {
+        let i = (symbol - 257) as usize;
+        if i >= LENGTH_BASE.len() {
+            return Err(ImageError::Inflate(InflateError::new("Invalid length code")));
+        }
+        let len = LENGTH_BASE[i] as usize + self.read_bits(LENGTH_EXTRA[i] as u32)? as usize;
+
+        let d = self.decode_symbol(dist_table)? as usize;
+        if d >= DISTANCE_BASE.len() {
+            return Err(ImageError::Inflate(InflateError::new("Invalid distance code")));
+        }
+        let dist = DISTANCE_BASE[d] as usize + self.read_bits(DISTANCE_EXTRA[d] as u32)? as usize;
+        if dist > self.total_out {
+            return Err(ImageError::Inflate(InflateError::new("Distance too far back")));
+        }
+
+        self.copy_len = len;
+        self.copy_dist = dist;
+        Ok(())
+    }
+
+    /// Decompress into `buf`, returns the number of bytes written, 0 once the
+    /// stream is over
+    pub fn inflate_into(&mut self, buf: &mut [u8]) -> Result<usize, ImageError> {
+        let mut written = 0;
+
+        while written < buf.len() {
+            if self.copy_len > 0 {
+                let n = self.copy_len.min(buf.len() - written);
+                for b in buf[written..written + n].iter_mut() {
+                    let byte = self.window[(self.total_out - self.copy_dist) % WINDOW_SIZE];
+                    self.push_output(byte);
+                    *b = byte;
+                }
+                self.copy_len -= n;
+                written += n;
+                continue;
+            }
+
+            match std::mem::replace(&mut self.state, State::Done) {
+                State::Done => break,
+                State::BlockHeader => self.read_block_header()?,
+                State::Stored(0) => self.state = State::BlockHeader,
+                State::Stored(remaining) => {
+                    let n = remaining.min(buf.len() - written);
+                    for i in 0..n {
+                        let byte = self.read_bits(8)? as u8;
+                        self.push_output(byte);
+                        buf[written + i] = byte;
+                    }
+                    written += n;
+                    self.state = State::Stored(remaining - n);
+                }
+                State::Huffman(lit, dist) => {
+                    let symbol = self.decode_symbol(&lit)?;
+                    match symbol {
+                        0..=255 => {
+                            self.push_output(symbol as u8);
+                            buf[written] = symbol as u8;
+                            written += 1;
+                            self.state = State::Huffman(lit, dist);
+                        }
+                        256 => self.state = State::BlockHeader,
+                        _ => {
+                            self.read_match(symbol, &dist)?;
+                            self.state = State::Huffman(lit, dist);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(written)
+    }
+}
+
+impl<R: Read> Read for Inflater<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inflate_into(buf).map_err(|e| match e {
+            ImageError::IO(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)),
+        })
+    }
+}
+
+/// Decompress a whole raw DEFLATE stream
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    let mut inflater = Inflater::new(data);
+    let mut ret: Vec<u8> = Vec::with_capacity(data.len() * 2);
+    let mut buf = [0u8; 16384];
+
+    loop {
+        let n = inflater.inflate_into(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        ret.extend_from_slice(&buf[..n]);
+    }
+    Ok(ret)
+}
+
+#[test]
+fn test_inflate_stored() {
+    let data = [0x01, 0x05, 0x00, 0xfa, 0xff, b'H', b'e', b'l', b'l', b'o'];
+    assert_eq!(inflate(&data).unwrap(), b"Hello");
+
+    let bad_nlen = [0x01, 0x05, 0x00, 0xfa, 0xfe, b'H', b'e', b'l', b'l', b'o'];
+    assert!(inflate(&bad_nlen).is_err());
+}
+
+#[test]
+fn test_inflate_fixed() {
+    // "abcabcabcabcabcabcabcabc", fixed Huffman block with an overlapping match
+    let data = [75, 76, 74, 78, 196, 134, 0];
+    assert_eq!(inflate(&data).unwrap(), b"abcabcabcabcabcabcabcabc");
+
+    let empty = [3, 0];
+    assert_eq!(inflate(&empty).unwrap(), b"");
+}
+
+#[test]
+fn test_inflate_dynamic() {
+    use miniz_oxide::deflate::compress_to_vec;
+
+    let data: Vec<u8> = (0..100000u32).map(|i| ((i % 1000) * (i % 1000) % 251) as u8 ^ (i / 1000) as u8).collect();
+    for level in 0..=10 {
+        let compressed = compress_to_vec(&data, level);
+        assert_eq!(inflate(&compressed).unwrap(), data, "level {}", level);
+    }
+}
+
+#[test]
+fn test_inflate_truncated() {
+    use miniz_oxide::deflate::compress_to_vec;
+
+    let compressed = compress_to_vec(b"Hello friend, Hello world!", 6);
+    assert!(inflate(&compressed[..compressed.len() - 2]).is_err());
+    assert!(inflate(&[]).is_err());
+}
+
+#[test]
+fn test_inflate_small_reads() {
+    use miniz_oxide::deflate::compress_to_vec;
+
+    let data: Vec<u8> = b"Blah blah blah blah blah! ".iter().cycle().take(5000).copied().collect();
+    let compressed = compress_to_vec(&data, 6);
+    let mut inflater = Inflater::new(compressed.as_slice());
+    let mut ret: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 7];
+    loop {
+        let n = inflater.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        ret.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(ret, data);
+    assert!(inflater.is_done());
+}
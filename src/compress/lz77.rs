@@ -1,163 +1,263 @@
-use std::collections::vec_deque::VecDeque;
+/// Shortest and longest matches DEFLATE can encode
+pub const MIN_MATCH: usize = 3;
+pub const MAX_MATCH: usize = 258;
 
-fn posl_substr_overlap(d: &[u8], w: &[u8]) -> Option<(usize, usize)> {
-    let mut pos: usize = 0;
-    let mut l: usize = 0;
-    let mut found: bool = false;
+const NO_POS: usize = usize::MAX;
 
-    let wlenght = w.len();
-    let dlenght = d.len();
-    if wlenght == 0 || dlenght == 0 {
-        return None;
-    }
+/// Speed/ratio tradeoff of the match finder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchParams {
+    /// Maximum number of hash chain entries to look at for one match
+    pub max_chain: usize,
+    /// Stop searching once a match this long is found
+    pub nice_length: usize,
+    /// Look for a longer match at the next byte when the match found is
+    /// shorter than this, 0 disables lazy matching
+    pub max_lazy: usize,
+}
 
-    let mut i = 0;
-    while i < wlenght {
-        if w[i] == d[0] {
-            let mut j = 0;
-            found = true;
-            while j < dlenght {
-                if w[i + (j % (wlenght - i))] != d[j] {
-                    break;
-                }
-                j += 1;
-            }
-            if j > l {
-                l = j;
-                pos = i;
-            }
+impl MatchParams {
+    pub const FASTEST: MatchParams = MatchParams { max_chain: 4, nice_length: 8, max_lazy: 0 };
+    pub const FAST: MatchParams = MatchParams { max_chain: 16, nice_length: 32, max_lazy: 0 };
+    pub const DEFAULT: MatchParams = MatchParams { max_chain: 128, nice_length: 128, max_lazy: 16 };
+    pub const BEST: MatchParams = MatchParams { max_chain: 4096, nice_length: MAX_MATCH, max_lazy: MAX_MATCH };
+
+    /// zlib like parameters for a compression level from 1 to 9
+    pub fn for_level(level: u8) -> MatchParams {
+        match level {
+            0 | 1 => MatchParams::FASTEST,
+            2 => MatchParams { max_chain: 8, nice_length: 16, max_lazy: 0 },
+            3 => MatchParams::FAST,
+            4 => MatchParams { max_chain: 16, nice_length: 16, max_lazy: 4 },
+            5 => MatchParams { max_chain: 32, nice_length: 32, max_lazy: 16 },
+            6 => MatchParams::DEFAULT,
+            7 => MatchParams { max_chain: 256, nice_length: 128, max_lazy: 32 },
+            8 => MatchParams { max_chain: 1024, nice_length: MAX_MATCH, max_lazy: 128 },
+            _ => MatchParams::BEST,
         }
-        i += 1;
     }
+}
+
+/// Hash chain match finder. Positions are chained by the hash of their
+/// first `hash_len` bytes, matches are searched from the closest one.
+pub struct MatchFinder<'a> {
+    data: &'a [u8],
+    hash_len: usize,
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    mask: usize,
+    next_insert: usize,
+}
+
+impl<'a> MatchFinder<'a> {
+    /// `history` is the farthest distance matches will be looked for
+    pub fn new(data: &'a [u8], hash_len: usize, history: usize) -> Self {
+        let hash_size = if hash_len == 1 { 256 } else { 1 << 15 };
+        let history = history.max(1).next_power_of_two();
 
-    if ! found {
-        return None;
+        MatchFinder {
+            data,
+            hash_len,
+            head: vec!(NO_POS; hash_size),
+            prev: vec!(NO_POS; history),
+            mask: history - 1,
+            next_insert: 0,
+        }
     }
 
-    return Some((pos, l));
-}
+    fn hash(&self, pos: usize) -> usize {
+        let d = &self.data[pos..pos + self.hash_len];
+        if self.hash_len == 1 {
+            return d[0] as usize;
+        }
+        d.iter().fold(0, |h, b| (h << 5) ^ *b as usize) & (self.head.len() - 1)
+    }
 
-fn compress(data: &[u8], window_size: usize) -> Vec<(usize, usize, u8)> {
-    assert!(window_size >= 2);
-    let mut d = &data[..];
-    let mut window: VecDeque<u8> = VecDeque::with_capacity(window_size);
-    let mut ret: Vec<(usize, usize, u8)> = Vec::new();
-
-    while ! d.is_empty() {
-        let start_of_match: usize;
-        let lenght_of_match: usize;
-        let c: u8;
-
-        let sw = window.make_contiguous();
-        if let Some(mat) = posl_substr_overlap(d, sw) {
-            start_of_match = mat.0;
-            if mat.1 < d.len() {
-                lenght_of_match = mat.1;
-                c = d[lenght_of_match];
-            } else { // last byte included
-                lenght_of_match = mat.1 - 1;
-                c = d[lenght_of_match];
+    fn insert_until(&mut self, pos: usize) {
+        while self.next_insert < pos {
+            let p = self.next_insert;
+            if p + self.hash_len <= self.data.len() {
+                let h = self.hash(p);
+                self.prev[p & self.mask] = self.head[h];
+                self.head[h] = p;
             }
-        } else {
-            start_of_match = 0;
-            lenght_of_match = 0;
-            c = d[0];
+            self.next_insert += 1;
         }
+    }
 
-        if lenght_of_match > 0 {
-            ret.push((window.len() - start_of_match, lenght_of_match, c));
-        } else {
-            assert!(start_of_match == 0);
-            ret.push((start_of_match, lenght_of_match, c));
+    /// Longest match for `pos` as (distance, length), matches can overlap
+    /// `pos` and are at most `max_length` long
+    pub fn longest_match(&mut self, pos: usize, max_distance: usize, max_length: usize, params: &MatchParams) -> Option<(usize, usize)> {
+        self.insert_until(pos);
+        if pos + self.hash_len > self.data.len() {
+            return None;
         }
 
-        //
+        let target = &self.data[pos..(pos + max_length).min(self.data.len())];
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[self.hash(pos)];
+        let mut chain = 0;
 
-        let split_point = (lenght_of_match + 1) as usize;
-        if window.len() + split_point > window_size {
-            for _ in 0..split_point {
-                window.pop_front();
+        while candidate != NO_POS && chain < params.max_chain {
+            let distance = pos - candidate;
+            if distance > max_distance || distance > self.mask + 1 {
+                break;
             }
-        }
 
-        let n = &d[.. split_point];
-        for c in n {
-            window.push_back(*c);
-        }
+            let length = self.data[candidate..].iter().zip(target).take_while(|(a, b)| a == b).count();
+            if length > best.map_or(0, |(_, best_length)| best_length) {
+                best = Some((distance, length));
+                if length >= params.nice_length || length == target.len() {
+                    break;
+                }
+            }
 
-        d = &d[split_point ..];
+            let next = self.prev[candidate & self.mask];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+            chain += 1;
+        }
+        best
     }
-    return ret;
 }
 
-fn decompress(compressed_data: Vec<(usize, usize, u8)>) -> Vec<u8> {
-    let mut ret: Vec<u8> = Vec::with_capacity(compressed_data.len());
-    let mut curr = 0;
+/// LZ77 output as used by DEFLATE: either a literal byte or a back
+/// reference of `length` bytes starting `distance` bytes before
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Token {
+    Literal(u8),
+    Match { distance: u16, length: u16 },
+}
 
-    for (pos, len, d) in compressed_data {
-        if len > 0 {
-            for i in 0..len {
-                ret.push(ret[curr - pos + i]);
-            }
-        }
+/// Split `data` into literals and DEFLATE sized matches, looking back at
+/// most `window_size` bytes
+pub fn tokenize(data: &[u8], window_size: usize, params: &MatchParams) -> Vec<Token> {
+    let mut ret: Vec<Token> = Vec::with_capacity(data.len() / 2);
+    let mut finder = MatchFinder::new(data, MIN_MATCH, window_size);
+    let mut lookahead: Option<Option<(usize, usize)>> = None;
+    let mut pos = 0;
 
-        ret.push(d);
+    while pos < data.len() {
+        let current = match lookahead.take() {
+            Some(m) => m,
+            None => finder.longest_match(pos, window_size, MAX_MATCH, params),
+        };
 
-        curr += len + 1;
+        match current {
+            Some((distance, length)) if length >= MIN_MATCH => {
+                if length < params.max_lazy && pos + 1 < data.len() {
+                    let next = finder.longest_match(pos + 1, window_size, MAX_MATCH, params);
+                    if next.is_some_and(|(_, l)| l > length) {
+                        ret.push(Token::Literal(data[pos]));
+                        pos += 1;
+                        lookahead = Some(next);
+                        continue;
+                    }
+                }
+                ret.push(Token::Match { distance: distance as u16, length: length as u16 });
+                pos += length;
+            }
+            _ => {
+                ret.push(Token::Literal(data[pos]));
+                pos += 1;
+            }
+        }
     }
-
-    return ret;
+    ret
 }
 
-
 #[test]
-fn test_compress_decompress() {
-    assert_eq!(decompress(compress(b"", 10)), b"");
-    assert_eq!(decompress(compress(b"AABCBBABC", 10)), b"AABCBBABC");
-    assert_eq!(decompress(compress(b"AABCBBABC", 3)), b"AABCBBABC");
-    assert_eq!(decompress(compress(b"Hello friend, Hello world!", 5)), b"Hello friend, Hello world!");
-    assert_eq!(decompress(compress(b"Hello friend, Hello world!", 100)), b"Hello friend, Hello world!");
-    assert_eq!(decompress(compress(b"Blah blah blah blah blah!", 100)), b"Blah blah blah blah blah!");
-    assert_eq!(decompress(compress(b"Blah blah blah blah blah!", 5)), b"Blah blah blah blah blah!");
+fn test_longest_match_overlap() {
+    // distance and length of the longest match of `d` right after `w`
+    let longest = |d: &[u8], w: &[u8]| {
+        let exhaustive = MatchParams { max_chain: usize::MAX, nice_length: usize::MAX, max_lazy: 0 };
+        let data = [w, d].concat();
+        MatchFinder::new(&data, 1, data.len()).longest_match(w.len(), w.len(), d.len(), &exhaustive)
+    };
+
+    assert_eq!(longest(&[1, 2], &[3, 1, 3, 1, 2]), Some((2, 2)));
+    assert_eq!(longest(&[0, 1, 2], &[3, 1, 3, 1, 2]), None);
+    assert_eq!(longest(&[1, 2, 4], &[3, 1, 3, 1, 2]), Some((2, 2)));
+    // the closest of matches of the same length
+    assert_eq!(longest(&[3, 1], &[3, 1, 3, 1, 2]), Some((3, 2)));
+    assert_eq!(longest(&[1, 1], &[3, 1, 3, 1, 2]), Some((2, 1)));
+    assert_eq!(longest(&[], &[3, 1, 3, 1, 2]), None);
+    assert_eq!(longest(&[1, 2], &[]), None);
+    // the match runs past the window, into the data being matched
+    assert_eq!(longest(&[1, 2, 1, 2], &[0, 1, 2]), Some((2, 4)));
 }
 
 #[test]
-fn test_compress_simple() {
-    assert_eq!(compress(b"AABCBBABC", 50),
-               vec!((0, 0, 65), (1, 1, 66), (0, 0, 67), (2, 1, 66), (5, 2, 67)));
-    assert_eq!(compress(b"Hello friend, Hello world!", 50),
-               vec!((0, 0, 72), (0, 0, 101), (0, 0, 108), (1, 1, 111), (0, 0, 32), (0, 0, 102), (0, 0, 114), (0, 0, 105), (8, 1, 110), (0, 0, 100), (0, 0, 44), (8, 1, 72), (14, 5, 119), (17, 1, 114), (21, 1, 100), (0, 0, 33)));
+fn test_match_finder_hash_chain() {
+    let data = b"abcdeabcdfabcde";
+    let mut finder = MatchFinder::new(data, MIN_MATCH, 32768);
+
+    // closest match first, then longer ones further back
+    assert_eq!(finder.longest_match(10, 32768, MAX_MATCH, &MatchParams::BEST), Some((10, 5)));
+    assert_eq!(finder.longest_match(10, 5, MAX_MATCH, &MatchParams::BEST), Some((5, 4)));
+    assert_eq!(finder.longest_match(10, 32768, MAX_MATCH, &MatchParams { max_chain: 1, nice_length: 258, max_lazy: 0 }), Some((5, 4)));
+    assert_eq!(finder.longest_match(13, 32768, MAX_MATCH, &MatchParams::BEST), None);
 }
 
 #[test]
-fn test_compress_overlapping() {
-    let c = compress(b"Blah blah blah blah blah!", 5);
-    assert_eq!(c, vec!((0, 0, 66), (0, 0, 108), (0, 0, 97), (0, 0, 104), (0, 0, 32), (0, 0, 98), (5, 18, 33)));
+fn test_tokenize() {
+    assert_eq!(tokenize(b"", 10, &MatchParams::DEFAULT), vec!());
+    assert_eq!(tokenize(b"abcabcabcabcx", 10, &MatchParams::DEFAULT),
+               vec!(Token::Literal(b'a'), Token::Literal(b'b'), Token::Literal(b'c'),
+                    Token::Match { distance: 3, length: 9 }, Token::Literal(b'x')));
+    assert_eq!(tokenize(b"aaaaaaaa", 10, &MatchParams::FASTEST),
+               vec!(Token::Literal(b'a'), Token::Match { distance: 1, length: 7 }));
 }
 
 #[test]
-fn test_posl_substr_overlap() {
-    assert_eq!(posl_substr_overlap(&[1, 2], &[3, 1, 3, 1, 2]),
-               Some((3, 2)));
-
-    assert_eq!(posl_substr_overlap(&[0, 1, 2], &[3, 1, 3, 1, 2]),
-               None);
-
-    assert_eq!(posl_substr_overlap(&[1, 2, 4], &[3, 1, 3, 1, 2]),
-               Some((3, 2)));
+fn test_tokenize_overlapping() {
+    // "lah blah blah blah" copies the 5 bytes before it over and over
+    let tokens = tokenize(b"Blah blah blah blah blah!", 5, &MatchParams::DEFAULT);
+    let literals: Vec<Token> = b"Blah b".iter().map(|b| Token::Literal(*b)).collect();
+    assert_eq!(tokens[..6], literals[..]);
+    assert_eq!(tokens[6..], [Token::Match { distance: 5, length: 18 }, Token::Literal(b'!')]);
+}
 
-    assert_eq!(posl_substr_overlap(&[3, 1], &[3, 1, 3, 1, 2]),
-               Some((0, 2)));
+#[test]
+fn test_tokenize_roundtrip() {
+    let expand = |tokens: &[Token]| {
+        let mut out: Vec<u8> = Vec::new();
+        for token in tokens {
+            match *token {
+                Token::Literal(b) => out.push(b),
+                Token::Match { distance, length } => {
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance as usize]);
+                    }
+                }
+            }
+        }
+        out
+    };
 
-    assert_eq!(posl_substr_overlap(&[1, 1], &[3, 1, 3, 1, 2]),
-               Some((1, 1)));
+    for data in [&b""[..], b"AABCBBABC", b"Hello friend, Hello world!", b"Blah blah blah blah blah!"] {
+        for window in [3, 5, 100, 32768] {
+            assert_eq!(expand(&tokenize(data, window, &MatchParams::DEFAULT)), data);
+        }
+    }
+}
 
-    assert_eq!(posl_substr_overlap(&[], &[3, 1, 3, 1, 2]),
-               None);
+#[test]
+fn test_tokenize_lazy() {
+    // "bcdef" is a better match one byte later than "abc"
+    let data = b"abc_bcdef_abcdef";
+    let greedy = tokenize(data, 32768, &MatchParams::FAST);
+    let lazy = tokenize(data, 32768, &MatchParams::DEFAULT);
 
-    assert_eq!(posl_substr_overlap(&[1, 2], &[]),
-               None);
+    assert_eq!(&greedy[10..], &[Token::Match { distance: 10, length: 3 }, Token::Match { distance: 7, length: 3 }]);
+    assert_eq!(&lazy[10..], &[Token::Literal(b'a'), Token::Match { distance: 7, length: 5 }]);
+}
 
-    assert_eq!(posl_substr_overlap(&[1, 2, 1, 2], &[0, 1, 2]),
-               Some((1, 4)));
+#[test]
+fn test_tokenize_large_window() {
+    let data: Vec<u8> = (0..100000u32).map(|i| ((i % 20000).wrapping_mul(2654435761) >> 13) as u8).collect();
+    let tokens = tokenize(&data, 32768, &MatchParams::DEFAULT);
+    assert!(tokens.contains(&Token::Match { distance: 20000, length: MAX_MATCH as u16 }));
 }
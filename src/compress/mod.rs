@@ -1,2 +1,6 @@
 
 mod lz77;
+pub mod huffman;
+pub mod inflate;
+pub mod deflate;
+pub mod zlib;
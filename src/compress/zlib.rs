@@ -0,0 +1,231 @@
+use std::io;
+use std::io::Read;
+
+use crate::error::*;
+use crate::hashs::adler32::Adler32;
+use super::inflate::Inflater;
+use super::deflate::deflate;
+
+/// RFC 1950 zlib decoder, the Adler-32 trailer is checked at the end of the
+/// stream
+pub struct ZlibDecoder<R: Read> {
+    inflater: Inflater<R>,
+    hasher: Adler32,
+    checked: bool,
+}
+
+impl<R: Read> ZlibDecoder<R> {
+    pub fn new(reader: R) -> Result<Self, ImageError> {
+        let mut inflater = Inflater::new(reader);
+
+        let mut header = [0u8; 2];
+        inflater.read_aligned(&mut header)?;
+        let (cmf, flg) = (header[0], header[1]);
+
+        if cmf & 0x0f != 8 {
+            return Err(ImageError::Inflate(InflateError { str: format!("Unknown zlib compression method {}", cmf & 0x0f) }));
+        }
+        if cmf >> 4 > 7 {
+            return Err(ImageError::Inflate(InflateError { str: format!("Invalid zlib window size {}", cmf >> 4) }));
+        }
+        if ! (cmf as u16 * 256 + flg as u16).is_multiple_of(31) {
+            return Err(ImageError::Inflate(InflateError::new("zlib header check failed")));
+        }
+        if flg & 0x20 != 0 {
+            return Err(ImageError::Inflate(InflateError::new("zlib preset dictionaries are not supported")));
+        }
+
+        Ok(ZlibDecoder {
+            inflater,
+            hasher: Adler32::new(),
+            checked: false,
+        })
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.inflater.get_ref()
+    }
+
+    /// Give back the underlying reader, input already buffered is lost
+    pub fn into_inner(self) -> R {
+        self.inflater.into_inner()
+    }
+
+    /// Decompress into `buf`, returns the number of bytes written, 0 once the
+    /// stream is over and its checksum verified
+    pub fn decompress_into(&mut self, buf: &mut [u8]) -> Result<usize, ImageError> {
+        let n = self.inflater.inflate_into(buf)?;
+        self.hasher.update(&buf[..n]);
+
+        if n == 0 && ! buf.is_empty() && ! self.checked {
+            let mut trailer = [0u8; 4];
+            self.inflater.read_aligned(&mut trailer)?;
+            self.checked = true;
+
+            let expected = u32::from_be_bytes(trailer);
+            let computed = self.hasher.finalise();
+            if expected != computed {
+                return Err(ImageError::Inflate(InflateError { str: format!("Adler-32 mismatch, expected {:#010x} got {:#010x}", expected, computed) }));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<R: Read> Read for ZlibDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decompress_into(buf).map_err(|e| match e {
+            ImageError::IO(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)),
+        })
+    }
+}
+
+/// Decompress a whole zlib stream with the crate own inflater
+pub fn native_decompress(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    Ok(native_decompress_with_limit(data, usize::MAX)?.unwrap_or_default())
+}
+
+/// Decompress a whole zlib stream with the crate own inflater, None when
+/// the output is larger than `limit` bytes
+pub fn native_decompress_with_limit(data: &[u8], limit: usize) -> Result<Option<Vec<u8>>, ImageError> {
+    let mut decoder = ZlibDecoder::new(data)?;
+    let mut ret: Vec<u8> = Vec::with_capacity(data.len().saturating_mul(2).min(limit));
+    let mut buf = [0u8; 16384];
+
+    loop {
+        let n = decoder.decompress_into(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if ret.len() + n > limit {
+            return Ok(None);
+        }
+        ret.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(ret))
+}
+
+/// Decompress a whole zlib stream, with miniz_oxide unless the `native-zlib`
+/// feature is enabled
+#[cfg(feature = "native-zlib")]
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    native_decompress(data)
+}
+
+/// Decompress a whole zlib stream, with miniz_oxide unless the `native-zlib`
+/// feature is enabled
+#[cfg(not(feature = "native-zlib"))]
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    Ok(miniz_oxide::inflate::decompress_to_vec_zlib(data)?)
+}
+
+/// Decompress a zlib stream whose output must fit in `limit` bytes, None
+/// when it does not
+#[cfg(feature = "native-zlib")]
+pub fn decompress_with_limit(data: &[u8], limit: usize) -> Result<Option<Vec<u8>>, ImageError> {
+    native_decompress_with_limit(data, limit)
+}
+
+/// Decompress a zlib stream whose output must fit in `limit` bytes, None
+/// when it does not
+#[cfg(not(feature = "native-zlib"))]
+pub fn decompress_with_limit(data: &[u8], limit: usize) -> Result<Option<Vec<u8>>, ImageError> {
+    match miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(data, limit) {
+        Ok(decoded) => Ok(Some(decoded)),
+        Err(miniz_oxide::inflate::TINFLStatus::HasMoreOutput) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Compress `data` as a zlib stream with the crate own deflater, `level`
+/// goes from 0 to 9
+pub fn native_compress(data: &[u8], level: u8) -> Vec<u8> {
+    let cmf: u8 = 0x78; // deflate, 32K window
+    let flevel: u8 = match level {
+        0 | 1 => 0,
+        2..=5 => 1,
+        6 => 2,
+        _ => 3,
+    };
+    let mut flg: u8 = flevel << 6;
+    flg += 31 - ((cmf as u16 * 256 + flg as u16) % 31) as u8;
+
+    let mut ret: Vec<u8> = vec!(cmf, flg);
+    ret.extend(deflate(data, level));
+
+    let mut hasher = Adler32::new();
+    hasher.update(data);
+    ret.extend_from_slice(&hasher.finalise().to_be_bytes());
+    ret
+}
+
+/// Compress `data` as a zlib stream, with miniz_oxide unless the
+/// `native-zlib` feature is enabled
+#[cfg(feature = "native-zlib")]
+pub fn compress(data: &[u8], level: u8) -> Vec<u8> {
+    native_compress(data, level.min(9))
+}
+
+/// Compress `data` as a zlib stream, with miniz_oxide unless the
+/// `native-zlib` feature is enabled
+#[cfg(not(feature = "native-zlib"))]
+pub fn compress(data: &[u8], level: u8) -> Vec<u8> {
+    miniz_oxide::deflate::compress_to_vec_zlib(data, level)
+}
+
+#[test]
+fn test_zlib_decompress() {
+    let data = [120, 218, 243, 72, 205, 201, 201, 87, 240, 64, 39, 21, 1, 105, 85, 8, 82];
+    assert_eq!(native_decompress(&data).unwrap(), b"Hello Hello Hello Hello!");
+    assert_eq!(decompress(&data).unwrap(), b"Hello Hello Hello Hello!");
+
+    let empty = [120, 218, 3, 0, 0, 0, 0, 1];
+    assert_eq!(native_decompress(&empty).unwrap(), b"");
+}
+
+#[test]
+fn test_zlib_decompress_limit() {
+    let data = [120, 218, 243, 72, 205, 201, 201, 87, 240, 64, 39, 21, 1, 105, 85, 8, 82];
+    assert_eq!(native_decompress_with_limit(&data, 24).unwrap().unwrap(), b"Hello Hello Hello Hello!");
+    assert_eq!(native_decompress_with_limit(&data, 23).unwrap(), None);
+    assert_eq!(decompress_with_limit(&data, 24).unwrap().unwrap(), b"Hello Hello Hello Hello!");
+    assert_eq!(decompress_with_limit(&data, 23).unwrap(), None);
+}
+
+#[test]
+fn test_zlib_miniz_compat() {
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+
+    let data: Vec<u8> = (0..70000u32).map(|i| (i % 7 + i / 300) as u8).collect();
+    for level in &[0, 1, 6, 10] {
+        let compressed = compress_to_vec_zlib(&data, *level);
+        assert_eq!(native_decompress(&compressed).unwrap(), data);
+    }
+}
+
+#[test]
+fn test_zlib_bad_stream() {
+    let bad_checksum = [120, 218, 243, 72, 205, 201, 201, 87, 240, 64, 39, 21, 1, 105, 85, 8, 83];
+    assert!(native_decompress(&bad_checksum).is_err());
+
+    let bad_header = [120, 219, 243, 72, 205, 201, 201, 87, 240, 64, 39, 21, 1, 105, 85, 8, 82];
+    assert!(native_decompress(&bad_header).is_err());
+
+    let dictionary = [0x78, 0xbb, 0, 0, 0, 0];
+    assert!(native_decompress(&dictionary).is_err());
+
+    let truncated_trailer = [120, 218, 243, 72, 205, 201, 201, 87, 240, 64, 39, 21, 1, 105, 85];
+    assert!(native_decompress(&truncated_trailer).is_err());
+}
+
+#[test]
+fn test_zlib_native_compress() {
+    let data: Vec<u8> = (0..30000u32).map(|i| (i % 13 + i / 500) as u8).collect();
+    for level in 0..=9 {
+        let compressed = native_compress(&data, level);
+        assert_eq!(native_decompress(&compressed).unwrap(), data);
+        assert_eq!(miniz_oxide::inflate::decompress_to_vec_zlib(&compressed).unwrap(), data);
+    }
+    assert_eq!(native_decompress(&compress(b"", 6)).unwrap(), b"");
+}
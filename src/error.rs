@@ -4,26 +4,191 @@ use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 use miniz_oxide::inflate::TINFLStatus;
 
+/// What is wrong with the decoded input
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodingErrorKind {
+    /// The input does not start with the format signature
+    BadSignature,
+    /// The input is in no format the crate can decode
+    UnknownFormat,
+    CrcMismatch { expected: u32, computed: u32 },
+    /// The input ends in the middle of a chunk
+    TruncatedChunk,
+    /// Image data ending before the last scanline
+    TruncatedImageData,
+    InvalidChunkLength(u32),
+    /// IHDR field with an invalid value
+    InvalidHeaderField { field: &'static str, value: u32 },
+    /// Scanline filter type other than 0 to 4
+    UnknownFilterType(u8),
+    /// pHYs unit other than unknown or meter
+    UnknownUnit(u8),
+    /// Chunk appearing before IHDR
+    ChunkBeforeIHDR,
+    MissingIHDR,
+    MissingImageData,
+    MissingIEND,
+    /// Bytes after the IEND chunk
+    TrailingData,
+    /// IDAT chunks separated by other chunks
+    NonConsecutiveImageData,
+    /// Second occurrence of a chunk allowed once
+    DuplicateChunk(String),
+    /// Chunk breaking an ordering or color type rule, with the rule
+    MisplacedChunk { chunk: String, rule: &'static str },
+    /// More palette entries than the bit depth can index
+    InvalidPaletteSize { entries: u32, max: u32 },
+    /// Indexed color image without PLTE chunk
+    MissingPalette,
+    /// Pixel referring to a color past the end of the palette
+    InvalidPaletteIndex(u8),
+    /// Malformed chunk content
+    InvalidChunk(String),
+    /// Malformed EXIF structure, with the reason
+    InvalidExif(&'static str),
+    /// Malformed ICC profile, with the reason
+    InvalidIcc(&'static str),
+    /// Corrupted compressed data, the source holds the inflater error
+    Compression,
+    /// Valid input using a feature the decoder does not implement
+    Unsupported(String),
+    /// The input needs more resources than the decoder `Limits` allow
+    LimitsExceeded { limit: &'static str, max: u64 },
+}
+
+impl DecodingErrorKind {
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self, DecodingErrorKind::UnknownFormat | DecodingErrorKind::Unsupported(_))
+    }
+
+    pub fn is_limits_exceeded(&self) -> bool {
+        matches!(self, DecodingErrorKind::LimitsExceeded { .. })
+    }
+}
+
+impl Display for DecodingErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodingErrorKind::BadSignature => write!(f, "wrong signature"),
+            DecodingErrorKind::UnknownFormat => write!(f, "unknown image format"),
+            DecodingErrorKind::CrcMismatch { expected, computed } => write!(f, "crc mismatch, expected {:#010x} got {:#010x}", expected, computed),
+            DecodingErrorKind::TruncatedChunk => write!(f, "truncated chunk"),
+            DecodingErrorKind::TruncatedImageData => write!(f, "image data ends before the last scanline"),
+            DecodingErrorKind::InvalidChunkLength(len) => write!(f, "invalid chunk length {}", len),
+            DecodingErrorKind::InvalidHeaderField { field, value } => write!(f, "invalid {} {}", field, value),
+            DecodingErrorKind::UnknownFilterType(t) => write!(f, "unknown filter type {}", t),
+            DecodingErrorKind::UnknownUnit(u) => write!(f, "unknown unit {}", u),
+            DecodingErrorKind::ChunkBeforeIHDR => write!(f, "chunk before IHDR"),
+            DecodingErrorKind::MissingIHDR => write!(f, "missing IHDR chunk"),
+            DecodingErrorKind::MissingImageData => write!(f, "missing IDAT chunk"),
+            DecodingErrorKind::MissingIEND => write!(f, "missing IEND chunk"),
+            DecodingErrorKind::TrailingData => write!(f, "data after the IEND chunk"),
+            DecodingErrorKind::NonConsecutiveImageData => write!(f, "IDAT chunks are not consecutive"),
+            DecodingErrorKind::DuplicateChunk(chunk) => write!(f, "duplicate {} chunk", chunk),
+            DecodingErrorKind::MisplacedChunk { chunk, rule } => write!(f, "{} chunk {}", chunk, rule),
+            DecodingErrorKind::InvalidPaletteSize { entries, max } => write!(f, "palette of {} entries, at most {} allowed", entries, max),
+            DecodingErrorKind::MissingPalette => write!(f, "indexed color image without palette"),
+            DecodingErrorKind::InvalidPaletteIndex(i) => write!(f, "palette index {} out of range", i),
+            DecodingErrorKind::InvalidChunk(reason) => write!(f, "{}", reason),
+            DecodingErrorKind::InvalidExif(reason) => write!(f, "invalid EXIF data, {}", reason),
+            DecodingErrorKind::InvalidIcc(reason) => write!(f, "invalid ICC profile, {}", reason),
+            DecodingErrorKind::Compression => write!(f, "corrupted compressed data"),
+            DecodingErrorKind::Unsupported(feature) => write!(f, "unsupported {}", feature),
+            DecodingErrorKind::LimitsExceeded { limit, max } => write!(f, "{} exceeds the limit of {}", limit, max),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DecodingError {
-    pub str: String,
+    pub kind: DecodingErrorKind,
+    /// Chunk being decoded
+    pub chunk: Option<String>,
+    /// Offset of the chunk from the start of the input
+    pub offset: Option<u64>,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
 }
 
 impl DecodingError {
-    pub fn new(s: &str) -> Self {
+    pub fn new(kind: DecodingErrorKind) -> Self {
         DecodingError {
-            str: String::from(s)
+            kind,
+            chunk: None,
+            offset: None,
+            source: None,
+        }
+    }
+
+    pub fn with_source<E: Into<Box<dyn std::error::Error + Send + Sync>>>(kind: DecodingErrorKind, source: E) -> Self {
+        DecodingError {
+            source: Some(source.into()),
+            ..DecodingError::new(kind)
         }
     }
 }
 
 impl Display for DecodingError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Decoding Error: {}", self.str)
+        write!(f, "Decoding Error: {}", self.kind)?;
+        if let Some(chunk) = &self.chunk {
+            write!(f, " in chunk {}", chunk)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        Ok(())
     }
 }
 
 impl std::error::Error for DecodingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+#[derive(Debug)]
+pub struct EncodingError {
+    pub str: String,
+}
+
+impl EncodingError {
+    pub fn new(s: &str) -> Self {
+        EncodingError {
+            str: String::from(s)
+        }
+    }
+}
+
+impl Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Encoding Error: {}", self.str)
+    }
+}
+
+impl std::error::Error for EncodingError {
+
+}
+
+#[derive(Debug)]
+pub struct InflateError {
+    pub str: String,
+}
+
+impl InflateError {
+    pub fn new(s: &str) -> Self {
+        InflateError {
+            str: String::from(s)
+        }
+    }
+}
+
+impl Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Inflate Error: {}", self.str)
+    }
+}
+
+impl std::error::Error for InflateError {
 
 }
 
@@ -31,12 +196,119 @@ impl std::error::Error for DecodingError {
 pub enum ImageError {
     IO(std::io::Error),
     Decoding(DecodingError),
+    Encoding(EncodingError),
     Compression(TINFLStatus),
+    Inflate(InflateError),
+}
+
+impl ImageError {
+    pub(crate) fn decoding(kind: DecodingErrorKind) -> Self {
+        ImageError::Decoding(DecodingError::new(kind))
+    }
+
+    pub fn decoding_kind(&self) -> Option<&DecodingErrorKind> {
+        match self {
+            ImageError::Decoding(e) => Some(&e.kind),
+            _ => None,
+        }
+    }
+
+    /// The input is valid but uses something the decoder does not implement
+    pub fn is_unsupported(&self) -> bool {
+        self.decoding_kind().is_some_and(|k| k.is_unsupported())
+    }
+
+    /// The input may be valid but is too large for the decoder limits
+    pub fn is_limits_exceeded(&self) -> bool {
+        self.decoding_kind().is_some_and(|k| k.is_limits_exceeded())
+    }
+
+    /// The input is damaged or does not follow the format
+    pub fn is_corrupt(&self) -> bool {
+        match self {
+            ImageError::Decoding(e) => ! e.kind.is_unsupported() && ! e.kind.is_limits_exceeded(),
+            ImageError::Compression(_) | ImageError::Inflate(_) => true,
+            ImageError::IO(e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+            ImageError::Encoding(_) => false,
+        }
+    }
+
+    pub fn chunk(&self) -> Option<&str> {
+        match self {
+            ImageError::Decoding(e) => e.chunk.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            ImageError::Decoding(e) => e.offset,
+            _ => None,
+        }
+    }
+
+    /// Locate an error raised while decoding the chunk at `offset`, the
+    /// location already known is kept. Compression and truncation errors
+    /// become decoding errors holding the original one as source.
+    pub(crate) fn in_chunk(self, chunk: &str, offset: u64) -> Self {
+        let mut e = match self {
+            ImageError::Decoding(e) => e,
+            ImageError::Inflate(e) => DecodingError::with_source(DecodingErrorKind::Compression, e),
+            ImageError::Compression(status) => DecodingError::with_source(DecodingErrorKind::Compression, format!("{:?}", status)),
+            ImageError::IO(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => DecodingError::with_source(DecodingErrorKind::TruncatedChunk, e),
+            e => return e,
+        };
+        if e.chunk.is_none() {
+            e.chunk = Some(chunk.to_string());
+            e.offset = Some(offset);
+        }
+        ImageError::Decoding(e)
+    }
+
+    /// Same as `in_chunk`, for errors kept as diagnostics
+    pub(crate) fn into_diagnostic(self, chunk: &str, offset: u64) -> DecodingError {
+        match self.in_chunk(chunk, offset) {
+            ImageError::Decoding(e) => e,
+            e => {
+                let mut d = DecodingError::with_source(DecodingErrorKind::InvalidChunk(e.to_string()), e);
+                d.chunk = Some(chunk.to_string());
+                d.offset = Some(offset);
+                d
+            }
+        }
+    }
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::IO(e) => write!(f, "IO Error: {}", e),
+            ImageError::Decoding(e) => e.fmt(f),
+            ImageError::Encoding(e) => e.fmt(f),
+            ImageError::Compression(e) => write!(f, "Compression Error: {:?}", e),
+            ImageError::Inflate(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::IO(e) => Some(e),
+            ImageError::Decoding(e) => std::error::Error::source(e),
+            ImageError::Encoding(_) | ImageError::Compression(_) => None,
+            ImageError::Inflate(e) => Some(e),
+        }
+    }
 }
 
 impl From<std::io::Error> for ImageError {
     fn from(e: std::io::Error) -> Self {
-        ImageError::IO(e)
+        // decoding errors of readers are carried through io::Error
+        match e.downcast::<DecodingError>() {
+            Ok(inner) => ImageError::Decoding(inner),
+            Err(e) => ImageError::IO(e),
+        }
     }
 }
 
@@ -48,24 +320,18 @@ impl From<TINFLStatus> for ImageError {
 
 impl From<nom::Err<nom::error::Error<&[u8]>>> for ImageError {
     fn from(e: nom::Err<nom::error::Error<&[u8]>>) -> Self {
-        ImageError::Decoding(DecodingError {
-            str: format!("mom error: {}", e)
-        })
+        ImageError::decoding(DecodingErrorKind::InvalidChunk(format!("malformed chunk data: {}", e)))
     }
 }
 
 impl From<Utf8Error> for ImageError {
     fn from(e: Utf8Error) -> Self {
-        ImageError::Decoding(DecodingError {
-            str: format!("utf8 error: {}", e)
-        })
+        ImageError::Decoding(DecodingError::with_source(DecodingErrorKind::InvalidChunk("invalid UTF-8 text".to_string()), e))
     }
 }
 
 impl From<FromUtf8Error> for ImageError {
     fn from(e: FromUtf8Error) -> Self {
-        ImageError::Decoding(DecodingError {
-            str: format!("from utf8 error: {}", e)
-        })
+        ImageError::Decoding(DecodingError::with_source(DecodingErrorKind::InvalidChunk("invalid UTF-8 text".to_string()), e))
     }
 }
@@ -0,0 +1,202 @@
+use crate::error::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// "II", Intel
+    LittleEndian,
+    /// "MM", Motorola
+    BigEndian,
+}
+
+/// Value of an IFD entry, one variant per TIFF field type
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Byte(Vec<u8>),
+    /// Text up to the first NUL
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    /// Numerator and denominator
+    Rational(Vec<(u32, u32)>),
+    SByte(Vec<i8>),
+    Undefined(Vec<u8>),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    SRational(Vec<(i32, i32)>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+impl Value {
+    /// First value of an unsigned integer field
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Value::Byte(v) => v.first().map(|&n| n as u32),
+            Value::Short(v) => v.first().map(|&n| n as u32),
+            Value::Long(v) => v.first().copied(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Ascii(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// All values of a numeric field, None for text and undefined bytes or
+    /// on a zero denominator
+    pub fn to_f64s(&self) -> Option<Vec<f64>> {
+        let ratio = |n: f64, d: f64| if d == 0.0 { None } else { Some(n / d) };
+        match self {
+            Value::Byte(v) => Some(v.iter().map(|&n| n as f64).collect()),
+            Value::Short(v) => Some(v.iter().map(|&n| n as f64).collect()),
+            Value::Long(v) => Some(v.iter().map(|&n| n as f64).collect()),
+            Value::Rational(v) => v.iter().map(|&(n, d)| ratio(n as f64, d as f64)).collect(),
+            Value::SByte(v) => Some(v.iter().map(|&n| n as f64).collect()),
+            Value::SShort(v) => Some(v.iter().map(|&n| n as f64).collect()),
+            Value::SLong(v) => Some(v.iter().map(|&n| n as f64).collect()),
+            Value::SRational(v) => v.iter().map(|&(n, d)| ratio(n as f64, d as f64)).collect(),
+            Value::Float(v) => Some(v.iter().map(|&n| n as f64).collect()),
+            Value::Double(v) => Some(v.clone()),
+            Value::Ascii(_) | Value::Undefined(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub tag: u16,
+    pub value: Value,
+}
+
+fn invalid(reason: &'static str) -> ImageError {
+    ImageError::decoding(DecodingErrorKind::InvalidExif(reason))
+}
+
+/// Bounds checked reads in the byte order of the TIFF header
+pub(super) struct TiffReader<'a> {
+    data: &'a [u8],
+    pub order: ByteOrder,
+}
+
+impl<'a> TiffReader<'a> {
+    /// Check the TIFF header, and return the reader with the offset of IFD0
+    pub fn new(data: &'a [u8]) -> Result<(Self, u32), ImageError> {
+        let order = match data.get(..4) {
+            Some(b"II*\0") => ByteOrder::LittleEndian,
+            Some(b"MM\0*") => ByteOrder::BigEndian,
+            _ => return Err(invalid("bad TIFF header")),
+        };
+        let reader = TiffReader { data, order };
+        let ifd0 = reader.u32(4)?;
+        Ok((reader, ifd0))
+    }
+
+    fn bytes(&self, offset: u64, len: u64) -> Result<&'a [u8], ImageError> {
+        let end = offset.checked_add(len).ok_or_else(|| invalid("offset out of range"))?;
+        if end > self.data.len() as u64 {
+            return Err(invalid("offset out of range"));
+        }
+        Ok(&self.data[offset as usize..end as usize])
+    }
+
+    fn u16_at(&self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        match self.order {
+            ByteOrder::LittleEndian => u16::from_le_bytes(b),
+            ByteOrder::BigEndian => u16::from_be_bytes(b),
+        }
+    }
+
+    fn u32_at(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        match self.order {
+            ByteOrder::LittleEndian => u32::from_le_bytes(b),
+            ByteOrder::BigEndian => u32::from_be_bytes(b),
+        }
+    }
+
+    fn u64_at(&self, b: &[u8]) -> u64 {
+        let (hi, lo) = match self.order {
+            ByteOrder::LittleEndian => (self.u32_at(&b[4..]), self.u32_at(b)),
+            ByteOrder::BigEndian => (self.u32_at(b), self.u32_at(&b[4..])),
+        };
+        (hi as u64) << 32 | lo as u64
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, ImageError> {
+        Ok(self.u16_at(self.bytes(offset, 2)?))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, ImageError> {
+        Ok(self.u32_at(self.bytes(offset, 4)?))
+    }
+
+    /// Entries of the IFD at `offset`. Entries of unknown type are skipped,
+    /// the link to the next IFD is not followed. Entries may share their
+    /// values, so the values decoded are limited to the size of the data.
+    pub fn read_ifd(&self, offset: u32) -> Result<Vec<Entry>, ImageError> {
+        let offset = offset as u64;
+        let count = self.u16(offset)? as u64;
+        if offset + 2 + count * 12 > self.data.len() as u64 {
+            return Err(invalid("IFD larger than the data"));
+        }
+        let mut entries = Vec::with_capacity(count as usize);
+        let mut values_len: u64 = 0;
+
+        for i in 0..count {
+            let entry = self.bytes(offset + 2 + i * 12, 12)?;
+            let tag = self.u16_at(entry);
+            let field_type = self.u16_at(&entry[2..]);
+            let count = self.u32_at(&entry[4..]) as u64;
+
+            let size = match field_type {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                5 | 10 | 12 => 8,
+                _ => {
+                    debug!("exif: tag {:#06x} of unknown type {}", tag, field_type);
+                    continue;
+                }
+            };
+            // values of 4 bytes or less are stored in place of the offset
+            let data = if size * count <= 4 {
+                &entry[8..8 + (size * count) as usize]
+            } else {
+                self.bytes(self.u32_at(&entry[8..]) as u64, size * count)?
+            };
+            values_len += size * count;
+            if values_len > self.data.len() as u64 {
+                return Err(invalid("values larger than the data"));
+            }
+
+            let value = self.value(field_type, data);
+            entries.push(Entry { tag, value });
+        }
+
+        Ok(entries)
+    }
+
+    fn value(&self, field_type: u16, data: &[u8]) -> Value {
+        match field_type {
+            1 => Value::Byte(data.to_vec()),
+            2 => {
+                let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                Value::Ascii(String::from_utf8_lossy(&data[..end]).into_owned())
+            }
+            3 => Value::Short(data.chunks_exact(2).map(|b| self.u16_at(b)).collect()),
+            4 => Value::Long(data.chunks_exact(4).map(|b| self.u32_at(b)).collect()),
+            5 => Value::Rational(data.chunks_exact(8).map(|b| (self.u32_at(b), self.u32_at(&b[4..]))).collect()),
+            6 => Value::SByte(data.iter().map(|&b| b as i8).collect()),
+            8 => Value::SShort(data.chunks_exact(2).map(|b| self.u16_at(b) as i16).collect()),
+            9 => Value::SLong(data.chunks_exact(4).map(|b| self.u32_at(b) as i32).collect()),
+            10 => Value::SRational(data.chunks_exact(8).map(|b| (self.u32_at(b) as i32, self.u32_at(&b[4..]) as i32)).collect()),
+            11 => Value::Float(data.chunks_exact(4).map(|b| f32::from_bits(self.u32_at(b))).collect()),
+            12 => Value::Double(data.chunks_exact(8).map(|b| f64::from_bits(self.u64_at(b))).collect()),
+            _ => Value::Undefined(data.to_vec()),
+        }
+    }
+}
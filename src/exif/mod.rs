@@ -0,0 +1,398 @@
+//! EXIF metadata: a TIFF header followed by image file directories (IFD)
+//! of tagged values
+mod ifd;
+
+use std::fmt;
+use std::fmt::Display;
+
+use crate::error::*;
+use crate::metadata::Timestamp;
+pub use ifd::{ByteOrder, Value, Entry};
+use ifd::TiffReader;
+
+pub mod tags {
+    pub const MAKE: u16 = 0x010f;
+    pub const MODEL: u16 = 0x0110;
+    pub const ORIENTATION: u16 = 0x0112;
+    pub const DATE_TIME: u16 = 0x0132;
+    pub const EXIF_IFD: u16 = 0x8769;
+    pub const GPS_IFD: u16 = 0x8825;
+    pub const DATE_TIME_ORIGINAL: u16 = 0x9003;
+    pub const OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+    pub const LENS_MODEL: u16 = 0xa434;
+
+    pub const GPS_LATITUDE_REF: u16 = 0x0001;
+    pub const GPS_LATITUDE: u16 = 0x0002;
+    pub const GPS_LONGITUDE_REF: u16 = 0x0003;
+    pub const GPS_LONGITUDE: u16 = 0x0004;
+    pub const GPS_ALTITUDE_REF: u16 = 0x0005;
+    pub const GPS_ALTITUDE: u16 = 0x0006;
+}
+
+/// How to transform the stored pixels to display them upright
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Normal,
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    /// Flip along the top-left to bottom-right diagonal
+    Transpose,
+    /// Rotate 90 degrees clockwise
+    Rotate90,
+    /// Flip along the top-right to bottom-left diagonal
+    Transverse,
+    /// Rotate 270 degrees clockwise
+    Rotate270,
+}
+
+impl Orientation {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::FlipHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::FlipVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Camera {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+}
+
+/// Local time of the camera when the picture was taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureTime {
+    /// Local date and time, not UTC
+    pub local: Timestamp,
+    /// Offset from UTC in minutes, when the camera recorded it
+    pub utc_offset: Option<i16>,
+}
+
+/// ISO 8601, like 2024-03-09T17:05:00+01:00
+impl Display for CaptureTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = &self.local;
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", t.year, t.month, t.day, t.hour, t.minute, t.second)?;
+        if let Some(offset) = self.utc_offset {
+            let sign = if offset < 0 { '-' } else { '+' };
+            write!(f, "{}{:02}:{:02}", sign, offset.abs() / 60, offset.abs() % 60)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpsPosition {
+    /// Degrees, negative south of the equator
+    pub latitude: f64,
+    /// Degrees, negative west of Greenwich
+    pub longitude: f64,
+    /// Meters, negative below sea level
+    pub altitude: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exif {
+    pub byte_order: ByteOrder,
+    /// Main image tags
+    pub ifd0: Vec<Entry>,
+    /// Camera settings, from the Exif IFD
+    pub exif: Vec<Entry>,
+    pub gps: Vec<Entry>,
+}
+
+fn find(entries: &[Entry], tag: u16) -> Option<&Value> {
+    entries.iter().find(|e| e.tag == tag).map(|e| &e.value)
+}
+
+impl Exif {
+    /// Parse a TIFF structure, as stored in a PNG eXIf chunk. The "Exif\0\0"
+    /// prefix of JPEG APP1 segments is accepted as well.
+    pub fn parse(data: &[u8]) -> Result<Exif, ImageError> {
+        let data = data.strip_prefix(b"Exif\0\0").unwrap_or(data);
+        let (reader, ifd0_offset) = TiffReader::new(data)?;
+
+        let ifd0 = reader.read_ifd(ifd0_offset)?;
+        let sub_ifd = |tag: u16| -> Result<Vec<Entry>, ImageError> {
+            match find(&ifd0, tag).and_then(Value::as_u32) {
+                Some(offset) => reader.read_ifd(offset),
+                None => Ok(Vec::new()),
+            }
+        };
+        let exif = sub_ifd(tags::EXIF_IFD)?;
+        let gps = sub_ifd(tags::GPS_IFD)?;
+
+        Ok(Exif { byte_order: reader.order, ifd0, exif, gps })
+    }
+
+    /// Value of a tag from IFD0 or the Exif IFD. GPS tags share numbers
+    /// with others, use `gps_value` for them.
+    pub fn value(&self, tag: u16) -> Option<&Value> {
+        find(&self.ifd0, tag).or_else(|| find(&self.exif, tag))
+    }
+
+    pub fn gps_value(&self, tag: u16) -> Option<&Value> {
+        find(&self.gps, tag)
+    }
+
+    fn text(&self, tag: u16) -> Option<String> {
+        self.value(tag).and_then(Value::as_str).map(|s| s.trim().to_string()).filter(|s| !s.is_empty())
+    }
+
+    pub fn orientation(&self) -> Option<Orientation> {
+        self.value(tags::ORIENTATION).and_then(Value::as_u32).and_then(Orientation::from_u32)
+    }
+
+    pub fn camera(&self) -> Option<Camera> {
+        let camera = Camera {
+            make: self.text(tags::MAKE),
+            model: self.text(tags::MODEL),
+            lens: self.text(tags::LENS_MODEL),
+        };
+        if camera == Camera::default() {
+            return None;
+        }
+        Some(camera)
+    }
+
+    /// DateTimeOriginal, or the modification time when missing
+    pub fn capture_time(&self) -> Option<CaptureTime> {
+        let local = self.text(tags::DATE_TIME_ORIGINAL).and_then(|t| parse_date_time(&t))
+            .or_else(|| self.text(tags::DATE_TIME).and_then(|t| parse_date_time(&t)))?;
+        let utc_offset = self.text(tags::OFFSET_TIME_ORIGINAL).and_then(|t| parse_utc_offset(&t));
+        Some(CaptureTime { local, utc_offset })
+    }
+
+    pub fn gps(&self) -> Option<GpsPosition> {
+        let degrees = |tag: u16, negative_ref: &str| -> Option<f64> {
+            let dms = self.gps_value(tag)?.to_f64s()?;
+            let (d, m, s) = match dms[..] {
+                [d, m, s] => (d, m, s),
+                _ => return None,
+            };
+            let value = d + m / 60.0 + s / 3600.0;
+            let reference = self.gps_value(tag - 1).and_then(Value::as_str);
+            Some(if reference == Some(negative_ref) { -value } else { value })
+        };
+
+        let latitude = degrees(tags::GPS_LATITUDE, "S")?;
+        let longitude = degrees(tags::GPS_LONGITUDE, "W")?;
+        let altitude = self.gps_value(tags::GPS_ALTITUDE).and_then(Value::to_f64s).and_then(|a| a.first().copied()).map(|a| {
+            let below_sea_level = self.gps_value(tags::GPS_ALTITUDE_REF).and_then(|r| match r {
+                Value::Byte(b) => b.first().copied(),
+                _ => None,
+            }) == Some(1);
+            if below_sea_level { -a } else { a }
+        });
+
+        Some(GpsPosition { latitude, longitude, altitude })
+    }
+}
+
+/// "YYYY:MM:DD HH:MM:SS"
+fn parse_date_time(text: &str) -> Option<Timestamp> {
+    let b = text.as_bytes();
+    if b.len() != 19 || b[4] != b':' || b[7] != b':' || b[10] != b' ' || b[13] != b':' || b[16] != b':' {
+        return None;
+    }
+    let field = |start: usize, end: usize| text.get(start..end)?.parse::<u16>().ok();
+    let small = |start: usize| field(start, start + 2).map(|n| n as u8);
+    Timestamp::new(field(0, 4)?, small(5)?, small(8)?, small(11)?, small(14)?, small(17)?)
+}
+
+/// "+HH:MM" or "-HH:MM", in minutes
+fn parse_utc_offset(text: &str) -> Option<i16> {
+    let b = text.as_bytes();
+    if b.len() != 6 || b[3] != b':' {
+        return None;
+    }
+    let hours: i16 = text.get(1..3)?.parse().ok()?;
+    let minutes: i16 = text.get(4..6)?.parse().ok()?;
+    match b[0] {
+        b'+' => Some(hours * 60 + minutes),
+        b'-' => Some(-(hours * 60 + minutes)),
+        _ => None,
+    }
+}
+
+/// TIFF structure with IFD0 holding `ifd0` and pointers to the Exif and
+/// GPS IFDs. Entries are (tag, type, count, value bytes in big endian).
+#[cfg(test)]
+pub(crate) fn build_exif(order: ByteOrder, ifd0: &[(u16, u16, u32, Vec<u8>)], exif: &[(u16, u16, u32, Vec<u8>)], gps: &[(u16, u16, u32, Vec<u8>)]) -> Vec<u8> {
+    // swap each value of `size` bytes for little endian
+    let encode = |field_type: u16, bytes: &[u8]| -> Vec<u8> {
+        let size = match field_type { 3 | 8 => 2, 4 | 9 | 11 | 10 | 5 => 4, 12 => 8, _ => 1 };
+        match order {
+            ByteOrder::BigEndian => bytes.to_vec(),
+            ByteOrder::LittleEndian => bytes.chunks(size).flat_map(|c| c.iter().rev().copied()).collect(),
+        }
+    };
+    let u16b = |n: u16| if order == ByteOrder::BigEndian { n.to_be_bytes() } else { n.to_le_bytes() };
+    let u32b = |n: u32| if order == ByteOrder::BigEndian { n.to_be_bytes() } else { n.to_le_bytes() };
+    let ifd_size = |n: usize| 2 + 12 * n as u32 + 4;
+
+    let mut ifd0 = ifd0.to_vec();
+    let exif_offset = 8 + ifd_size(ifd0.len() + 2);
+    let gps_offset = exif_offset + ifd_size(exif.len());
+    ifd0.push((tags::EXIF_IFD, 4, 1, exif_offset.to_be_bytes().to_vec()));
+    ifd0.push((tags::GPS_IFD, 4, 1, gps_offset.to_be_bytes().to_vec()));
+
+    let mut data_offset = gps_offset + ifd_size(gps.len());
+    let mut out = match order {
+        ByteOrder::LittleEndian => b"II*\0".to_vec(),
+        ByteOrder::BigEndian => b"MM\0*".to_vec(),
+    };
+    out.extend_from_slice(&u32b(8));
+    let mut data: Vec<u8> = Vec::new();
+    for ifd in [&ifd0[..], exif, gps] {
+        out.extend_from_slice(&u16b(ifd.len() as u16));
+        for (tag, field_type, count, value) in ifd {
+            out.extend_from_slice(&u16b(*tag));
+            out.extend_from_slice(&u16b(*field_type));
+            out.extend_from_slice(&u32b(*count));
+            let value = encode(*field_type, value);
+            if value.len() <= 4 {
+                let mut inline = value.clone();
+                inline.resize(4, 0);
+                out.extend_from_slice(&inline);
+            } else {
+                out.extend_from_slice(&u32b(data_offset));
+                data_offset += value.len() as u32;
+                data.extend_from_slice(&value);
+            }
+        }
+        out.extend_from_slice(&u32b(0));
+    }
+    out.extend_from_slice(&data);
+    out
+}
+
+#[cfg(test)]
+fn rationals(values: &[(u32, u32)]) -> Vec<u8> {
+    values.iter().flat_map(|(n, d)| [n.to_be_bytes(), d.to_be_bytes()].concat()).collect()
+}
+
+#[test]
+fn test_exif() {
+    let ascii = |s: &str| (s.len() as u32 + 1, s.bytes().chain(Some(0)).collect::<Vec<u8>>());
+    let entry = |tag: u16, field_type: u16, (count, value): (u32, Vec<u8>)| (tag, field_type, count, value);
+
+    let ifd0 = [
+        entry(tags::MAKE, 2, ascii("Polms")),
+        entry(tags::MODEL, 2, ascii("Camera 1 ")),
+        entry(tags::ORIENTATION, 3, (1, vec!(0, 6))),
+        entry(tags::DATE_TIME, 2, ascii("2024:05:01 08:00:00")),
+    ];
+    let exif = [
+        entry(tags::DATE_TIME_ORIGINAL, 2, ascii("2024:03:09 17:05:00")),
+        entry(tags::OFFSET_TIME_ORIGINAL, 2, ascii("-03:30")),
+        // unknown type, skipped
+        entry(0x9999, 13, (1, vec!(0, 0, 0, 0))),
+    ];
+    let gps = [
+        entry(tags::GPS_LATITUDE_REF, 2, ascii("N")),
+        entry(tags::GPS_LATITUDE, 5, (3, rationals(&[(48, 1), (51, 1), (2448, 100)]))),
+        entry(tags::GPS_LONGITUDE_REF, 2, ascii("W")),
+        entry(tags::GPS_LONGITUDE, 5, (3, rationals(&[(2, 1), (1768, 100), (0, 1)]))),
+        entry(tags::GPS_ALTITUDE_REF, 1, (1, vec!(1))),
+        entry(tags::GPS_ALTITUDE, 5, (1, rationals(&[(355, 10)]))),
+    ];
+
+    for order in [ByteOrder::BigEndian, ByteOrder::LittleEndian] {
+        let exif = Exif::parse(&build_exif(order, &ifd0, &exif, &gps)).unwrap();
+        assert_eq!(exif.byte_order, order);
+        assert_eq!((exif.ifd0.len(), exif.exif.len(), exif.gps.len()), (6, 2, 6));
+
+        assert_eq!(exif.orientation(), Some(Orientation::Rotate90));
+        assert_eq!(exif.camera(), Some(Camera { make: Some("Polms".to_string()), model: Some("Camera 1".to_string()), lens: None }));
+        assert_eq!(exif.capture_time().unwrap().to_string(), "2024-03-09T17:05:00-03:30");
+        assert_eq!(exif.value(tags::DATE_TIME), Some(&Value::Ascii("2024:05:01 08:00:00".to_string())));
+
+        let position = exif.gps().unwrap();
+        assert!((position.latitude - 48.8568).abs() < 1e-6);
+        assert!((position.longitude + 2.294666).abs() < 1e-6);
+        assert_eq!(position.altitude, Some(-35.5));
+    }
+
+    // without DateTimeOriginal nor GPS
+    let exif = Exif::parse(&build_exif(ByteOrder::LittleEndian, &ifd0, &[], &[])).unwrap();
+    assert_eq!(exif.capture_time().unwrap().to_string(), "2024-05-01T08:00:00");
+    assert_eq!(exif.gps(), None);
+    assert_eq!(Exif::parse(&build_exif(ByteOrder::BigEndian, &[], &[], &[])).unwrap().camera(), None);
+}
+
+#[test]
+fn test_exif_values() {
+    let ifd0 = [
+        (1, 6, 2, vec!(0xff, 0x80)),
+        (2, 8, 1, vec!(0xff, 0xfe)),
+        (3, 9, 1, vec!(0xff, 0xff, 0xff, 0xfd)),
+        (4, 10, 1, vec!(0xff, 0xff, 0xff, 0xff, 0, 0, 0, 2)),
+        (5, 11, 1, 1.5f32.to_be_bytes().to_vec()),
+        (6, 12, 2, [0.25f64.to_be_bytes(), (-2.0f64).to_be_bytes()].concat()),
+        (7, 7, 5, b"0230\0".to_vec()),
+        (8, 5, 1, rationals(&[(1, 0)])),
+    ];
+    for order in [ByteOrder::BigEndian, ByteOrder::LittleEndian] {
+        let exif = Exif::parse(&build_exif(order, &ifd0, &[], &[])).unwrap();
+        let value = |tag: u16| exif.value(tag).unwrap().clone();
+        assert_eq!(value(1), Value::SByte(vec!(-1, -128)));
+        assert_eq!(value(2), Value::SShort(vec!(-2)));
+        assert_eq!(value(3), Value::SLong(vec!(-3)));
+        assert_eq!(value(4).to_f64s(), Some(vec!(-0.5)));
+        assert_eq!(value(5), Value::Float(vec!(1.5)));
+        assert_eq!(value(6), Value::Double(vec!(0.25, -2.0)));
+        assert_eq!(value(7), Value::Undefined(b"0230\0".to_vec()));
+        assert_eq!(value(8).to_f64s(), None);
+    }
+}
+
+#[test]
+fn test_exif_malformed() {
+    let kind = |data: &[u8]| Exif::parse(data).err().and_then(|e| e.decoding_kind().cloned());
+    assert_eq!(kind(b"MM\0*"), Some(DecodingErrorKind::InvalidExif("offset out of range")));
+    assert_eq!(kind(b"MM\0+\0\0\0\x08"), Some(DecodingErrorKind::InvalidExif("bad TIFF header")));
+
+    let mut data = build_exif(ByteOrder::BigEndian, &[(tags::MAKE, 2, 32, vec!(b'a'; 32))], &[], &[]);
+    assert!(Exif::parse(&data).is_ok());
+    // value past the end
+    data.truncate(data.len() - 1);
+    assert_eq!(kind(&data), Some(DecodingErrorKind::InvalidExif("offset out of range")));
+    // IFD0 past the end
+    assert_eq!(kind(b"II*\0\xff\xff\xff\xff"), Some(DecodingErrorKind::InvalidExif("offset out of range")));
+    // huge count
+    let data = build_exif(ByteOrder::BigEndian, &[(tags::MAKE, 12, u32::MAX, vec!(0; 8))], &[], &[]);
+    assert_eq!(kind(&data), Some(DecodingErrorKind::InvalidExif("offset out of range")));
+
+    // more entries than the data can hold
+    assert_eq!(kind(b"MM\0*\0\0\0\x08\xff\xff\0\0\0\0"), Some(DecodingErrorKind::InvalidExif("IFD larger than the data")));
+
+    // many entries pointing at the same large value
+    let (nb_entries, value_len) = (1000u32, 4096u32);
+    let value_offset = 8 + 2 + 12 * nb_entries + 4;
+    let mut data = b"MM\0*\0\0\0\x08".to_vec();
+    data.extend_from_slice(&(nb_entries as u16).to_be_bytes());
+    for tag in 0..nb_entries {
+        data.extend_from_slice(&(tag as u16).to_be_bytes());
+        data.extend_from_slice(&7u16.to_be_bytes());
+        data.extend_from_slice(&value_len.to_be_bytes());
+        data.extend_from_slice(&value_offset.to_be_bytes());
+    }
+    data.extend_from_slice(&[0; 4]);
+    data.resize((value_offset + value_len) as usize, 0xaa);
+    assert_eq!(kind(&data), Some(DecodingErrorKind::InvalidExif("values larger than the data")));
+
+    let mut prefixed = b"Exif\0\0".to_vec();
+    prefixed.extend(build_exif(ByteOrder::LittleEndian, &[(tags::ORIENTATION, 3, 1, vec!(0, 3))], &[], &[]));
+    assert_eq!(Exif::parse(&prefixed).unwrap().orientation(), Some(Orientation::Rotate180));
+}
@@ -1,40 +1,207 @@
+use std::hash::Hasher;
+use std::io;
+
+use super::Checksum;
 
 const MOD_ADLER32: u32 = 65521;
+/// Largest number of bytes that can be summed before s2 may overflow a u32
+const NMAX: usize = 5552;
+/// Bytes summed at once in the inner loop, small enough to be unrolled and
+/// vectorised by the compiler
+const LANES: usize = 16;
 
-struct Adler32 {
+/// Adler-32 checksum (RFC 1950)
+#[derive(Debug, Clone)]
+pub struct Adler32 {
     s1: u32,
     s2: u32,
-    count: u16,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Adler32::new()
+    }
 }
 
 impl Adler32 {
-    fn new() -> Adler32 {
+    pub fn new() -> Adler32 {
         Adler32 {
             s1: 1,
             s2: 0,
-            count: 0
         }
     }
 
-    fn update(&mut self, data: &[u8]) {
-        for d in data {
-            self.s1 = self.s1 + *d as u32;
-            self.s2 = self.s2 + self.s1;
-
-            self.count += 1;
-            if self.count >= 5552 {
-                self.s1 = self.s1 % MOD_ADLER32;
-                self.s2 = self.s2 % MOD_ADLER32;
-                self.count = 0;
+    pub fn update(&mut self, data: &[u8]) {
+        let (mut s1, mut s2) = (self.s1, self.s2);
+
+        for block in data.chunks(NMAX) {
+            let mut chunks = block.chunks_exact(LANES);
+            for chunk in &mut chunks {
+                // s2 gets s1 once per byte plus each byte weighted by the
+                // number of bytes left in the chunk
+                let mut sum: u32 = 0;
+                let mut weighted: u32 = 0;
+                for (i, b) in chunk.iter().enumerate() {
+                    sum += *b as u32;
+                    weighted += (LANES - i) as u32 * *b as u32;
+                }
+                s2 += LANES as u32 * s1 + weighted;
+                s1 += sum;
             }
+            for b in chunks.remainder() {
+                s1 += *b as u32;
+                s2 += s1;
+            }
+
+            s1 %= MOD_ADLER32;
+            s2 %= MOD_ADLER32;
         }
+
+        self.s1 = s1;
+        self.s2 = s2;
+    }
+
+    pub fn finalise(&mut self) -> u32 {
+        self.checksum()
+    }
+
+    fn checksum(&self) -> u32 {
+        (self.s2 << 16) | self.s1
+    }
+}
+
+/// Adler-32 of the concatenation of two streams, from the checksum of each
+/// one and the length of the second
+pub fn adler32_combine(adler1: u32, adler2: u32, len2: u64) -> u32 {
+    let rem = (len2 % MOD_ADLER32 as u64) as u32;
+    let mut sum1 = adler1 & 0xffff;
+    let mut sum2 = rem * sum1 % MOD_ADLER32;
+
+    sum1 += (adler2 & 0xffff) + MOD_ADLER32 - 1;
+    sum2 += (adler1 >> 16) + (adler2 >> 16) + MOD_ADLER32 - rem;
+
+    if sum1 >= MOD_ADLER32 {
+        sum1 -= MOD_ADLER32;
+    }
+    if sum1 >= MOD_ADLER32 {
+        sum1 -= MOD_ADLER32;
+    }
+    if sum2 >= MOD_ADLER32 * 2 {
+        sum2 -= MOD_ADLER32 * 2;
+    }
+    if sum2 >= MOD_ADLER32 {
+        sum2 -= MOD_ADLER32;
+    }
+    (sum2 << 16) | sum1
+}
+
+impl Checksum for Adler32 {
+    fn update(&mut self, data: &[u8]) {
+        Adler32::update(self, data);
     }
 
     fn finalise(&mut self) -> u32 {
-        self.s1 = self.s1 % MOD_ADLER32;
-        self.s2 = self.s2 % MOD_ADLER32;
+        Adler32::finalise(self)
+    }
+
+    fn reset(&mut self) {
+        *self = Adler32::new();
+    }
+
+    fn combine(a: u32, b: u32, len_b: u64) -> u32 {
+        adler32_combine(a, b, len_b)
+    }
+}
+
+impl Hasher for Adler32 {
+    fn finish(&self) -> u64 {
+        self.checksum() as u64
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.update(bytes);
+    }
+}
+
+impl io::Write for Adler32 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Adler-32 over a sliding window, bytes are appended with `push` and the
+/// oldest one removed with `pop` in constant time, as rsync weak checksum.
+/// The window content is not kept, the caller gives back the byte leaving it.
+#[derive(Debug, Clone, Default)]
+pub struct RollingAdler32 {
+    hash: Adler32,
+    len: u64,
+}
+
+impl RollingAdler32 {
+    pub fn new() -> RollingAdler32 {
+        RollingAdler32 {
+            hash: Adler32::new(),
+            len: 0,
+        }
+    }
+
+    /// Checksum of an initial window
+    pub fn from_window(window: &[u8]) -> RollingAdler32 {
+        let mut h = RollingAdler32::new();
+        h.update(window);
+        h
+    }
 
-        (self.s2 << 16) | self.s1 as u32
+    /// Number of bytes in the window
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hash.update(data);
+        self.len += data.len() as u64;
+    }
+
+    /// Append a byte to the window
+    pub fn push(&mut self, byte: u8) {
+        let h = &mut self.hash;
+        h.s1 = (h.s1 + byte as u32) % MOD_ADLER32;
+        h.s2 = (h.s2 + h.s1) % MOD_ADLER32;
+        self.len += 1;
+    }
+
+    /// Remove `byte`, the oldest byte of the window
+    pub fn pop(&mut self, byte: u8) {
+        assert!(self.len > 0, "pop from an empty window");
+
+        // the oldest byte was counted once in s1 and len times in s2, along
+        // with the initial 1 of s1
+        let weight = (self.len % MOD_ADLER32 as u64) as u32 * byte as u32 % MOD_ADLER32;
+        let h = &mut self.hash;
+        h.s1 = (h.s1 + MOD_ADLER32 - byte as u32) % MOD_ADLER32;
+        h.s2 = (h.s2 + 2 * MOD_ADLER32 - weight - 1) % MOD_ADLER32;
+        self.len -= 1;
+    }
+
+    /// Slide the window one byte, `out` leaves it and `byte` enters it
+    pub fn roll(&mut self, out: u8, byte: u8) {
+        self.pop(out);
+        self.push(byte);
+    }
+
+    /// Adler-32 of the current window
+    pub fn checksum(&self) -> u32 {
+        self.hash.checksum()
     }
 }
 
@@ -95,3 +262,101 @@ fn test_alder32_big_big_update_rollover() {
     h.update(&[0xff; 25500]);
     assert_eq!(h.finalise(), 0x24b03e32);
 }
+
+#[cfg(test)]
+fn adler32_bytewise(data: &[u8]) -> u32 {
+    let (mut s1, mut s2) = (1u32, 0u32);
+    for d in data {
+        s1 = (s1 + *d as u32) % MOD_ADLER32;
+        s2 = (s2 + s1) % MOD_ADLER32;
+    }
+    (s2 << 16) | s1
+}
+
+#[test]
+fn test_alder32_chunked_update() {
+    let data: Vec<u8> = (0..100000u32).map(|i| (i * 131 % 256) as u8).collect();
+    for len in &[0, 1, 15, 16, 17, 5551, 5552, 5553, 20000, 100000] {
+        let mut h = Adler32::new();
+        h.update(&data[..*len]);
+        assert_eq!(h.finalise(), adler32_bytewise(&data[..*len]));
+    }
+
+    let mut h = Adler32::new();
+    for chunk in data.chunks(1000) {
+        h.update(chunk);
+    }
+    assert_eq!(h.finalise(), adler32_bytewise(&data));
+}
+
+#[test]
+fn test_alder32_combine() {
+    let data: Vec<u8> = (0..70000u32).map(|i| (i * 7 % 251) as u8).collect();
+    for split in &[0, 1, 100, 65521, 65522, 70000] {
+        let (a, b) = data.split_at(*split);
+        let (ha, hb) = (adler32_bytewise(a), adler32_bytewise(b));
+        assert_eq!(adler32_combine(ha, hb, b.len() as u64), adler32_bytewise(&data));
+        assert_eq!(<Adler32 as Checksum>::combine(ha, hb, b.len() as u64), adler32_bytewise(&data));
+    }
+}
+
+#[test]
+fn test_alder32_hasher_write() {
+    use std::io::Write;
+
+    let mut h = Adler32::new();
+    Hasher::write(&mut h, b"Hello ");
+    h.write_all(b"World!").unwrap();
+    assert_eq!(h.finish(), 0x1c49043e);
+
+    let mut h = Adler32::new();
+    io::copy(&mut &[0xffu8; 25500][..], &mut h).unwrap();
+    assert_eq!(h.finish(), 0x24b03e32);
+}
+
+#[test]
+fn test_rolling_adler32() {
+    let data: Vec<u8> = (0..20000u32).map(|i| (i * 37 % 253) as u8).collect();
+    let window = 700;
+
+    let mut h = RollingAdler32::from_window(&data[..window]);
+    assert_eq!(h.len(), window as u64);
+    for start in 1..(data.len() - window) {
+        h.roll(data[start - 1], data[start + window - 1]);
+        if start % 997 == 0 {
+            assert_eq!(h.checksum(), adler32_bytewise(&data[start..start + window]));
+        }
+    }
+
+    let mut h = RollingAdler32::new();
+    assert_eq!(h.checksum(), 1);
+    h.push(0xff);
+    h.push(0x10);
+    h.pop(0xff);
+    assert_eq!(h.checksum(), adler32_bytewise(&[0x10]));
+    h.pop(0x10);
+    assert!(h.is_empty());
+    assert_eq!(h.checksum(), 1);
+}
+
+#[test]
+fn test_rolling_adler32_find_block() {
+    let old: Vec<u8> = (0..4096u32).map(|i| (i * 13 % 256) as u8).collect();
+    let mut new = vec!(0xaa; 1234);
+    new.extend_from_slice(&old[1024..2048]);
+    new.extend_from_slice(&[0x55; 100]);
+
+    let target = adler32_bytewise(&old[1024..2048]);
+    let mut h = RollingAdler32::from_window(&new[..1024]);
+    let mut found = None;
+    for start in 0..=(new.len() - 1024) {
+        if start > 0 {
+            h.roll(new[start - 1], new[start + 1023]);
+        }
+        if h.checksum() == target && new[start..start + 1024] == old[1024..2048] {
+            found = Some(start);
+            break;
+        }
+    }
+    assert_eq!(found, Some(1234));
+}
@@ -0,0 +1,189 @@
+use super::Checksum;
+
+/// Reversed CRC-32 polynomial (ISO 3309, as used by PNG, gzip and zip)
+const POLY: u32 = 0xedb88320;
+
+const fn make_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0u32; 256]; 8];
+
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { POLY ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        tables[0][n] = c;
+        n += 1;
+    }
+
+    // tables[k][n] is the CRC of byte n followed by k zero bytes
+    let mut k = 1;
+    while k < 8 {
+        let mut n = 0;
+        while n < 256 {
+            let prev = tables[k - 1][n];
+            tables[k][n] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+            n += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
+static TABLES: [[u32; 256]; 8] = make_tables();
+
+/// a * b modulo the CRC polynomial, with a != 0
+fn multmodp(a: u32, mut b: u32) -> u32 {
+    let mut m: u32 = 1 << 31;
+    let mut p: u32 = 0;
+
+    loop {
+        if a & m != 0 {
+            p ^= b;
+            if a & (m - 1) == 0 {
+                break;
+            }
+        }
+        m >>= 1;
+        b = if b & 1 != 0 { (b >> 1) ^ POLY } else { b >> 1 };
+    }
+    p
+}
+
+/// x^(n * 2^k) modulo the CRC polynomial
+fn x2nmodp(mut n: u64, k: u32) -> u32 {
+    // x^(2^k) for k = 0
+    let mut x2k: u32 = 1 << 30;
+    for _ in 0..k {
+        x2k = multmodp(x2k, x2k);
+    }
+
+    let mut p: u32 = 1 << 31; // x^0
+    while n != 0 {
+        if n & 1 != 0 {
+            p = multmodp(x2k, p);
+        }
+        n >>= 1;
+        x2k = multmodp(x2k, x2k);
+    }
+    p
+}
+
+/// CRC-32 of the concatenation of two streams, from the CRC of each one and
+/// the length of the second
+pub fn crc32_combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    // crc1 is shifted by len2 bytes, x^(8 * len2)
+    multmodp(x2nmodp(len2, 3), crc1) ^ crc2
+}
+
+/// Slicing-by-8 CRC-32
+#[derive(Debug, Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 {
+            crc: 0xffffffff,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let t = &TABLES;
+        let mut crc = self.crc;
+
+        let mut chunks = data.chunks_exact(8);
+        for c in &mut chunks {
+            let lo = u32::from_le_bytes([c[0], c[1], c[2], c[3]]) ^ crc;
+            let hi = u32::from_le_bytes([c[4], c[5], c[6], c[7]]);
+
+            crc = t[7][(lo & 0xff) as usize] ^ t[6][((lo >> 8) & 0xff) as usize]
+                ^ t[5][((lo >> 16) & 0xff) as usize] ^ t[4][(lo >> 24) as usize]
+                ^ t[3][(hi & 0xff) as usize] ^ t[2][((hi >> 8) & 0xff) as usize]
+                ^ t[1][((hi >> 16) & 0xff) as usize] ^ t[0][(hi >> 24) as usize];
+        }
+        for b in chunks.remainder() {
+            crc = t[0][((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+
+        self.crc = crc;
+    }
+
+    pub fn finalise(&mut self) -> u32 {
+        self.crc ^ 0xffffffff
+    }
+}
+
+impl Checksum for Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        Crc32::update(self, data);
+    }
+
+    fn finalise(&mut self) -> u32 {
+        Crc32::finalise(self)
+    }
+
+    fn reset(&mut self) {
+        *self = Crc32::new();
+    }
+
+    fn combine(a: u32, b: u32, len_b: u64) -> u32 {
+        crc32_combine(a, b, len_b)
+    }
+}
+
+#[cfg(test)]
+fn crc32(data: &[u8]) -> u32 {
+    let mut h = Crc32::new();
+    h.update(data);
+    h.finalise()
+}
+
+#[test]
+fn test_crc32_simple() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414fa339);
+    assert_eq!(crc32(b"IEND"), 0xae426082);
+}
+
+#[test]
+fn test_crc32_multi_update() {
+    let data: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 256) as u8).collect();
+    let mut h = Crc32::new();
+    for chunk in data.chunks(13) {
+        h.update(chunk);
+    }
+    assert_eq!(h.finalise(), crc32(&data));
+    assert_eq!(h.finalise(), crc32(&data));
+
+    let bytewise = data.iter().fold(0xffffffffu32, |crc, b| TABLES[0][((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8));
+    assert_eq!(bytewise ^ 0xffffffff, crc32(&data));
+}
+
+#[test]
+fn test_crc32_combine() {
+    let data: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+    for split in &[0, 1, 7, 8, 100, 4096, 5000] {
+        let (a, b) = data.split_at(*split);
+        assert_eq!(crc32_combine(crc32(a), crc32(b), b.len() as u64), crc32(&data));
+    }
+}
+
+#[test]
+fn test_crc32_reset() {
+    let mut h = Crc32::new();
+    h.update(b"garbage");
+    Checksum::reset(&mut h);
+    h.update(b"123456789");
+    assert_eq!(h.finalise(), 0xcbf43926);
+}
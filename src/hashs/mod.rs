@@ -1,2 +1,14 @@
+pub mod adler32;
+pub mod crc32;
 
-mod adler32;
+/// Streaming 32 bits checksum, `finalise` can be called several times and
+/// does not end the stream
+pub trait Checksum {
+    fn update(&mut self, data: &[u8]);
+    fn finalise(&mut self) -> u32;
+    /// Start over as if newly created
+    fn reset(&mut self);
+    /// Checksum of the concatenation of two streams, `len_b` is the length
+    /// of the second one
+    fn combine(a: u32, b: u32, len_b: u64) -> u32 where Self: Sized;
+}
@@ -0,0 +1,83 @@
+use crate::error::*;
+use super::{invalid, u16_at, u32_at, s15fixed16_at};
+
+/// Tone reproduction curve, from encoded values to linear values, both
+/// in 0..1
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    Identity,
+    Gamma(f64),
+    /// Values at evenly spaced inputs, interpolated linearly
+    Table(Vec<f64>),
+    /// ICC parametric function of type 0 to 4, with its parameters
+    /// g, a, b, c, d, e, f
+    Parametric(u16, Vec<f64>),
+}
+
+impl Curve {
+    /// Parse a curv or para tag
+    pub(super) fn parse(data: &[u8]) -> Result<Curve, ImageError> {
+        match data.get(..4) {
+            Some(b"curv") => {
+                let count = u32_at(data, 8)? as usize;
+                match count {
+                    0 => Ok(Curve::Identity),
+                    1 => Ok(Curve::Gamma(u16_at(data, 12)? as f64 / 256.0)),
+                    _ => {
+                        if count.checked_mul(2).is_none_or(|len| data.len() - 12 < len) {
+                            return Err(invalid("truncated curve"));
+                        }
+                        let table = (0..count).map(|i| u16_at(data, 12 + i * 2).map(|v| v as f64 / 65535.0)).collect::<Result<_, _>>()?;
+                        Ok(Curve::Table(table))
+                    }
+                }
+            }
+            Some(b"para") => {
+                let function = u16_at(data, 8)?;
+                let count = match function {
+                    0 => 1,
+                    1 => 3,
+                    2 => 4,
+                    3 => 5,
+                    4 => 7,
+                    _ => return Err(ImageError::decoding(DecodingErrorKind::Unsupported(format!("parametric curve type {}", function)))),
+                };
+                let params = (0..count).map(|i| s15fixed16_at(data, 12 + i * 4)).collect::<Result<_, _>>()?;
+                Ok(Curve::Parametric(function, params))
+            }
+            _ => Err(invalid("curve of unknown type")),
+        }
+    }
+
+    /// Linear value of `x`
+    pub fn eval(&self, x: f64) -> f64 {
+        let x = x.clamp(0.0, 1.0);
+        let y = match self {
+            Curve::Identity => x,
+            Curve::Gamma(g) => x.powf(*g),
+            Curve::Table(table) => {
+                let pos = x * (table.len() - 1) as f64;
+                let i = (pos as usize).min(table.len() - 2);
+                let frac = pos - i as f64;
+                table[i] + (table[i + 1] - table[i]) * frac
+            }
+            Curve::Parametric(function, p) => {
+                let pow = |base: f64| if base > 0.0 { base.powf(p[0]) } else { 0.0 };
+                match (function, &p[..]) {
+                    (0, _) => pow(x),
+                    (1, &[_, a, b]) => if a * x + b >= 0.0 { pow(a * x + b) } else { 0.0 },
+                    (2, &[_, a, b, c]) => if a * x + b >= 0.0 { pow(a * x + b) + c } else { c },
+                    (3, &[_, a, b, c, d]) => if x >= d { pow(a * x + b) } else { c * x },
+                    (4, &[_, a, b, c, d, e, f]) => if x >= d { pow(a * x + b) + e } else { c * x + f },
+                    _ => x,
+                }
+            }
+        };
+        y.clamp(0.0, 1.0)
+    }
+
+    /// Linear values of `count` evenly spaced inputs from 0 to 1
+    pub fn sample(&self, count: usize) -> Vec<f64> {
+        (0..count).map(|i| self.eval(i as f64 / (count - 1) as f64)).collect()
+    }
+}
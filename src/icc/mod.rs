@@ -0,0 +1,382 @@
+//! ICC v2 and v4 profiles: a 128 bytes header followed by a table of tags.
+//! Only matrix/TRC RGB and gray profiles can build transforms.
+mod curve;
+
+use std::fmt;
+use std::path::Path;
+
+use crate::error::*;
+use crate::color::{Transform, Encoding, Matrix3, SRGB_TO_XYZ, D65, mul, invert, adaptation, xy_to_xyz, srgb_decode};
+pub use curve::Curve;
+
+/// Samples of a curve inverted to encode linear values, decoding only
+/// needs the 256 values of a sample
+const ENCODE_TABLE_SIZE: usize = 4096;
+
+fn invalid(reason: &'static str) -> ImageError {
+    ImageError::decoding(DecodingErrorKind::InvalidIcc(reason))
+}
+
+fn bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8], ImageError> {
+    offset.checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| invalid("offset out of range"))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ImageError> {
+    let b = bytes(data, offset, 2)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, ImageError> {
+    let b = bytes(data, offset, 4)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn s15fixed16_at(data: &[u8], offset: usize) -> Result<f64, ImageError> {
+    Ok(u32_at(data, offset)? as i32 as f64 / 65536.0)
+}
+
+fn xyz_at(data: &[u8], offset: usize) -> Result<[f64; 3], ImageError> {
+    Ok([s15fixed16_at(data, offset)?, s15fixed16_at(data, offset + 4)?, s15fixed16_at(data, offset + 8)?])
+}
+
+/// Four characters code of the header and tags
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 4]);
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl Signature {
+    pub const RGB: Signature = Signature(*b"RGB ");
+    pub const GRAY: Signature = Signature(*b"GRAY");
+    pub const XYZ: Signature = Signature(*b"XYZ ");
+    pub const LAB: Signature = Signature(*b"Lab ");
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub size: u32,
+    /// Major and minor version, like (4, 3)
+    pub version: (u8, u8),
+    /// Device class, like "mntr" for displays
+    pub class: Signature,
+    /// Color space of the samples, like "RGB " or "GRAY"
+    pub color_space: Signature,
+    /// Profile connection space, "XYZ " or "Lab "
+    pub pcs: Signature,
+    pub rendering_intent: u32,
+    /// XYZ of the white of the connection space, normally D50
+    pub illuminant: [f64; 3],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TagEntry {
+    pub signature: Signature,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub header: Header,
+    pub tags: Vec<TagEntry>,
+    /// Colorants of a matrix/TRC profile, XYZ adapted to the illuminant
+    pub red: Option<[f64; 3]>,
+    pub green: Option<[f64; 3]>,
+    pub blue: Option<[f64; 3]>,
+    pub red_trc: Option<Curve>,
+    pub green_trc: Option<Curve>,
+    pub blue_trc: Option<Curve>,
+    pub gray_trc: Option<Curve>,
+}
+
+impl Profile {
+    pub fn parse(data: &[u8]) -> Result<Profile, ImageError> {
+        if data.len() < 132 {
+            return Err(invalid("truncated header"));
+        }
+        if &data[36..40] != b"acsp" {
+            return Err(invalid("missing acsp signature"));
+        }
+        let size = u32_at(data, 0)?;
+        if size < 132 {
+            return Err(invalid("truncated header"));
+        }
+        if (size as usize) > data.len() {
+            return Err(invalid("truncated profile"));
+        }
+        let data = &data[..size as usize];
+
+        let version = bytes(data, 8, 2)?;
+        let version = (version[0], version[1] >> 4);
+        if !(2..=4).contains(&version.0) {
+            return Err(ImageError::decoding(DecodingErrorKind::Unsupported(format!("ICC version {}.{}", version.0, version.1))));
+        }
+        let signature = |offset: usize| -> Result<Signature, ImageError> {
+            let b = bytes(data, offset, 4)?;
+            Ok(Signature([b[0], b[1], b[2], b[3]]))
+        };
+        let header = Header {
+            size,
+            version,
+            class: signature(12)?,
+            color_space: signature(16)?,
+            pcs: signature(20)?,
+            rendering_intent: u32_at(data, 64)?,
+            illuminant: xyz_at(data, 68)?,
+        };
+
+        let count = u32_at(data, 128)? as usize;
+        let table = bytes(data, 132, count.checked_mul(12).ok_or_else(|| invalid("offset out of range"))?)?;
+        let tags: Vec<TagEntry> = table.chunks_exact(12).map(|t| TagEntry {
+            signature: Signature([t[0], t[1], t[2], t[3]]),
+            offset: u32::from_be_bytes([t[4], t[5], t[6], t[7]]),
+            size: u32::from_be_bytes([t[8], t[9], t[10], t[11]]),
+        }).collect();
+
+        let tag = |name: &[u8; 4]| -> Result<Option<&[u8]>, ImageError> {
+            match tags.iter().find(|t| &t.signature.0 == name) {
+                Some(t) => Ok(Some(bytes(data, t.offset as usize, t.size as usize)?)),
+                None => Ok(None),
+            }
+        };
+        let xyz = |name: &[u8; 4]| -> Result<Option<[f64; 3]>, ImageError> {
+            match tag(name)? {
+                Some(t) if t.starts_with(b"XYZ ") => Ok(Some(xyz_at(t, 8)?)),
+                Some(_) => Err(invalid("XYZ tag of unknown type")),
+                None => Ok(None),
+            }
+        };
+        let curve = |name: &[u8; 4]| -> Result<Option<Curve>, ImageError> {
+            tag(name)?.map(Curve::parse).transpose()
+        };
+
+        Ok(Profile {
+            red: xyz(b"rXYZ")?,
+            green: xyz(b"gXYZ")?,
+            blue: xyz(b"bXYZ")?,
+            red_trc: curve(b"rTRC")?,
+            green_trc: curve(b"gTRC")?,
+            blue_trc: curve(b"bTRC")?,
+            gray_trc: curve(b"kTRC")?,
+            header,
+            tags,
+        })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Profile, ImageError> {
+        Profile::parse(&std::fs::read(path)?)
+    }
+
+    fn rgb_trcs(&self) -> Option<[&Curve; 3]> {
+        Some([self.red_trc.as_ref()?, self.green_trc.as_ref()?, self.blue_trc.as_ref()?])
+    }
+
+    /// Linear RGB of the profile to linear sRGB, for matrix/TRC RGB profiles
+    fn rgb_to_srgb(&self) -> Option<Matrix3> {
+        if self.header.color_space != Signature::RGB || self.header.pcs != Signature::XYZ {
+            return None;
+        }
+        let (r, g, b) = (self.red?, self.green?, self.blue?);
+        let to_xyz = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+        let to_d65 = adaptation(self.header.illuminant, xy_to_xyz(D65));
+        Some(mul(&invert(&SRGB_TO_XYZ)?, &mul(&to_d65, &to_xyz)))
+    }
+
+    /// Transform of the profile samples to sRGB, None when the profile is
+    /// neither a matrix/TRC RGB profile nor a gray profile
+    pub fn to_srgb(&self) -> Option<Transform> {
+        let srgb = [Encoding::Srgb, Encoding::Srgb, Encoding::Srgb];
+        if self.header.color_space == Signature::GRAY {
+            let decode = self.gray_trc.as_ref()?.sample(256);
+            return Some(Transform { decode: [decode.clone(), decode.clone(), decode], matrix: None, encode: srgb });
+        }
+
+        let matrix = self.rgb_to_srgb()?;
+        let decode = self.rgb_trcs()?.map(|c| c.sample(256));
+        Some(Transform { decode, matrix: Some(matrix), encode: srgb })
+    }
+
+    /// Transform of sRGB samples to the profile, None when the profile is
+    /// neither a matrix/TRC RGB profile nor a gray profile
+    pub fn from_srgb(&self) -> Option<Transform> {
+        let decode: Vec<f64> = (0..256).map(|v| srgb_decode(v as f64 / 255.0)).collect();
+        let decode = [decode.clone(), decode.clone(), decode];
+        if self.header.color_space == Signature::GRAY {
+            let encode = Encoding::Table(self.gray_trc.as_ref()?.sample(ENCODE_TABLE_SIZE));
+            return Some(Transform { decode, matrix: None, encode: [encode.clone(), encode.clone(), encode] });
+        }
+
+        let matrix = invert(&self.rgb_to_srgb()?)?;
+        let encode = self.rgb_trcs()?.map(|c| Encoding::Table(c.sample(ENCODE_TABLE_SIZE)));
+        Some(Transform { decode, matrix: Some(matrix), encode })
+    }
+}
+
+/// Profile with `color_space` holding the given tags, as (signature, data)
+#[cfg(test)]
+pub(crate) fn build_profile(color_space: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut data_offset = 132 + 12 * tags.len();
+    let mut table: Vec<u8> = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data: Vec<u8> = Vec::new();
+    for (signature, tag) in tags {
+        table.extend_from_slice(&signature[..]);
+        table.extend_from_slice(&(data_offset as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        data.extend_from_slice(tag);
+        data_offset += tag.len();
+    }
+
+    let mut header = vec!(0u8; 128);
+    header[0..4].copy_from_slice(&(data_offset as u32).to_be_bytes());
+    header[8] = 4;
+    header[9] = 0x30;
+    header[12..16].copy_from_slice(b"mntr");
+    header[16..20].copy_from_slice(color_space);
+    header[20..24].copy_from_slice(b"XYZ ");
+    header[36..40].copy_from_slice(b"acsp");
+    // D50
+    header[68..80].copy_from_slice(&xyz_tag([0.9642, 1.0, 0.8249])[8..]);
+
+    [header, table, data].concat()
+}
+
+#[cfg(test)]
+pub(crate) fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    for v in xyz.iter() {
+        tag.extend_from_slice(&((v * 65536.0).round() as i32).to_be_bytes());
+    }
+    tag
+}
+
+/// Parametric curve of type 3 matching the sRGB transfer function
+#[cfg(test)]
+pub(crate) fn srgb_para_tag() -> Vec<u8> {
+    let mut tag = b"para\0\0\0\0\0\x03\0\0".to_vec();
+    for v in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045].iter() {
+        tag.extend_from_slice(&((v * 65536.0f64).round() as i32).to_be_bytes());
+    }
+    tag
+}
+
+/// sRGB as a matrix/TRC profile, with the colorants adapted to D50
+#[cfg(test)]
+pub(crate) fn srgb_profile() -> Vec<u8> {
+    build_profile(b"RGB ", &[
+        (b"rXYZ", xyz_tag([0.4361, 0.2225, 0.0139])),
+        (b"gXYZ", xyz_tag([0.3851, 0.7169, 0.0971])),
+        (b"bXYZ", xyz_tag([0.1431, 0.0606, 0.7141])),
+        (b"rTRC", srgb_para_tag()),
+        (b"gTRC", srgb_para_tag()),
+        (b"bTRC", srgb_para_tag()),
+    ])
+}
+
+#[test]
+fn test_parse_profile() {
+    let profile = Profile::parse(&srgb_profile()).unwrap();
+    assert_eq!(profile.header.version, (4, 3));
+    assert_eq!((profile.header.class, profile.header.color_space, profile.header.pcs), (Signature(*b"mntr"), Signature::RGB, Signature::XYZ));
+    assert!((profile.header.illuminant[2] - 0.8249).abs() < 1e-4);
+    assert_eq!(profile.tags.len(), 6);
+    assert_eq!(profile.tags[0], TagEntry { signature: Signature(*b"rXYZ"), offset: 132 + 6 * 12, size: 20 });
+    assert!((profile.green.unwrap()[1] - 0.7169).abs() < 1e-4);
+    assert_eq!(profile.gray_trc, None);
+    match &profile.red_trc {
+        Some(Curve::Parametric(3, params)) => assert_eq!(params.len(), 5),
+        trc => panic!("{:?}", trc),
+    }
+
+    let kind = |data: &[u8]| Profile::parse(data).err().and_then(|e| e.decoding_kind().cloned());
+    let mut data = srgb_profile();
+    assert_eq!(kind(&data[..100]), Some(DecodingErrorKind::InvalidIcc("truncated header")));
+    assert_eq!(kind(&data[..data.len() - 1]), Some(DecodingErrorKind::InvalidIcc("truncated profile")));
+    // size field shorter than the header
+    let mut short = data.clone();
+    short[0..4].copy_from_slice(&4u32.to_be_bytes());
+    assert_eq!(kind(&short), Some(DecodingErrorKind::InvalidIcc("truncated header")));
+    data[8] = 5;
+    assert_eq!(kind(&data), Some(DecodingErrorKind::Unsupported("ICC version 5.3".to_string())));
+    data[8] = 2;
+    data[36] = b'b';
+    assert_eq!(kind(&data), Some(DecodingErrorKind::InvalidIcc("missing acsp signature")));
+
+    // tag past the end
+    let mut data = build_profile(b"GRAY", &[(b"kTRC", b"curv\0\0\0\0\0\0\0\x01\x01\xcd".to_vec())]);
+    assert!(Profile::parse(&data).is_ok());
+    data[132 + 4..132 + 8].copy_from_slice(&1000u32.to_be_bytes());
+    assert_eq!(kind(&data), Some(DecodingErrorKind::InvalidIcc("offset out of range")));
+    let data = build_profile(b"GRAY", &[(b"kTRC", b"curv\0\0\0\0\0\0\0\x05\0\0".to_vec())]);
+    assert_eq!(kind(&data), Some(DecodingErrorKind::InvalidIcc("truncated curve")));
+    let data = build_profile(b"GRAY", &[(b"kTRC", b"para\0\0\0\0\0\x05\0\0".to_vec())]);
+    assert_eq!(kind(&data), Some(DecodingErrorKind::Unsupported("parametric curve type 5".to_string())));
+}
+
+#[test]
+fn test_curves() {
+    let parse = |tag: &[u8]| Curve::parse(tag).unwrap();
+    assert_eq!(parse(b"curv\0\0\0\0\0\0\0\0"), Curve::Identity);
+    assert_eq!(parse(b"curv\0\0\0\0\0\0\0\x01\x02\x33"), Curve::Gamma(2.19921875));
+    let table = parse(b"curv\0\0\0\0\0\0\0\x03\0\0\x40\0\xff\xff");
+    assert!((table.eval(0.25) - 0.125).abs() < 1e-3);
+    assert!((table.eval(0.75) - 0.625).abs() < 1e-3);
+    assert_eq!(table.eval(1.0), 1.0);
+
+    let srgb = parse(&srgb_para_tag());
+    for v in [0.0, 0.02, 0.2, 0.5, 1.0].iter() {
+        assert!((srgb.eval(*v) - srgb_decode(*v)).abs() < 1e-4, "{}", v);
+    }
+    assert_eq!(Curve::Parametric(0, vec!(2.0)).eval(0.5), 0.25);
+    assert_eq!(Curve::Parametric(1, vec!(1.0, 2.0, -0.5)).eval(0.1), 0.0);
+    assert_eq!(Curve::Parametric(2, vec!(1.0, 2.0, -0.5, 0.25)).eval(0.5), 0.75);
+    assert_eq!(Curve::Parametric(4, vec!(1.0, 1.0, 0.0, 0.5, 0.5, 0.0, 0.125)).eval(0.25), 0.25);
+}
+
+#[test]
+fn test_profile_transforms() {
+    // sRGB to sRGB keeps the values
+    let profile = Profile::parse(&srgb_profile()).unwrap();
+    let original: Vec<u8> = (0..=255).flat_map(|v: u8| vec!(v, 255 - v, v / 2)).collect();
+    for transform in [profile.to_srgb().unwrap(), profile.from_srgb().unwrap()].iter() {
+        let mut rgb = original.clone();
+        transform.apply_rgb(&mut rgb, 3);
+        assert!(rgb.iter().zip(original.iter()).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1));
+    }
+
+    // a wider gamut, colors round trip and get more saturated in sRGB
+    let wide = build_profile(b"RGB ", &[
+        (b"rXYZ", xyz_tag([0.6097, 0.3111, 0.0195])),
+        (b"gXYZ", xyz_tag([0.2053, 0.6257, 0.0609])),
+        (b"bXYZ", xyz_tag([0.1492, 0.0632, 0.7446])),
+        (b"rTRC", b"curv\0\0\0\0\0\0\0\x01\x02\x33".to_vec()),
+        (b"gTRC", b"curv\0\0\0\0\0\0\0\x01\x02\x33".to_vec()),
+        (b"bTRC", b"curv\0\0\0\0\0\0\0\x01\x02\x33".to_vec()),
+    ]);
+    let profile = Profile::parse(&wide).unwrap();
+    let mut rgb = vec!(200, 100, 50, 128, 128, 128);
+    profile.to_srgb().unwrap().apply_rgb(&mut rgb, 3);
+    assert!(rgb[0] > 200 && rgb[1] <= 100 && rgb[2] < 50, "{:?}", rgb);
+    assert!(rgb[3..].iter().all(|&v| (v as i32 - rgb[3] as i32).abs() <= 1));
+    profile.from_srgb().unwrap().apply_rgb(&mut rgb, 3);
+    assert!(rgb.iter().zip([200, 100, 50, 128, 128, 128].iter()).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1), "{:?}", rgb);
+
+    // gray with a gamma of 1
+    let gray = Profile::parse(&build_profile(b"GRAY", &[(b"kTRC", b"curv\0\0\0\0\0\0\0\0".to_vec())])).unwrap();
+    let mut g = vec!(0, 50, 255);
+    gray.to_srgb().unwrap().apply_gray(&mut g, 1);
+    assert_eq!(g, vec!(0, 122, 255));
+    gray.from_srgb().unwrap().apply_gray(&mut g, 1);
+    assert_eq!(g, vec!(0, 50, 255));
+
+    // neither matrix/TRC nor gray
+    let lut = Profile::parse(&build_profile(b"CMYK", &[])).unwrap();
+    assert_eq!((lut.to_srgb(), lut.from_srgb()), (None, None));
+    let no_trc = Profile::parse(&build_profile(b"RGB ", &[(b"rXYZ", xyz_tag([0.4361, 0.2225, 0.0139]))])).unwrap();
+    assert_eq!(no_trc.to_srgb(), None);
+}
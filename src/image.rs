@@ -1,13 +1,133 @@
 use std::io::Write;
 use std::io::Read;
-use crate::error::ImageError;
+use crate::error::*;
+use crate::codecs::png;
+use crate::metadata::Metadata;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GenericImageColors {
     RGB,
     RGBA,
     G
 }
 
+/// Weights used to compute the luma of a RGB pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LumaWeights {
+    /// ITU-R BT.601, Y = 0.299 R + 0.587 G + 0.114 B
+    Rec601,
+    /// ITU-R BT.709, Y = 0.2126 R + 0.7152 G + 0.0722 B
+    Rec709,
+}
+
+impl LumaWeights {
+    pub fn luma(&self, r: u8, g: u8, b: u8) -> u8 {
+        // fixed point weights, each set sums to 256 so gray stays gray
+        let (wr, wg, wb): (u32, u32, u32) = match self {
+            LumaWeights::Rec601 => (77, 150, 29),
+            LumaWeights::Rec709 => (54, 183, 19),
+        };
+        ((wr * r as u32 + wg * g as u32 + wb * b as u32 + 128) >> 8) as u8
+    }
+}
+
+/// What to do with the alpha channel when converting to a format without one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlphaMode {
+    /// Ignore the alpha channel and keep the color as is
+    Drop,
+    /// Blend the color against the image background
+    Composite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConvertOptions {
+    pub luma: LumaWeights,
+    pub alpha: AlphaMode,
+    /// Convert the colors to sRGB when the image declares another color
+    /// space, instead of keeping the stored values
+    pub to_srgb: bool,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            luma: LumaWeights::Rec601,
+            alpha: AlphaMode::Composite,
+            to_srgb: false,
+        }
+    }
+}
+
+/// Resources a decoder may use on a single image, None means unlimited.
+/// The defaults accept any sensible image but stop decompression bombs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Width times height
+    pub max_pixels: Option<u64>,
+    /// Size of the decompressed image data, before any conversion
+    pub max_decoded_bytes: Option<u64>,
+    /// Length of a chunk loaded in memory, image data read by the streaming
+    /// decoder is not loaded as a whole
+    pub max_chunk_size: Option<u32>,
+    /// Total size of the metadata, after decompression
+    pub max_metadata_bytes: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: None,
+            max_height: None,
+            max_pixels: Some(1 << 28),
+            max_decoded_bytes: Some(1 << 30),
+            max_chunk_size: Some(1 << 28),
+            max_metadata_bytes: Some(1 << 24),
+        }
+    }
+}
+
+impl Limits {
+    /// No limit at all, for trusted input only
+    pub fn none() -> Self {
+        Limits {
+            max_width: None,
+            max_height: None,
+            max_pixels: None,
+            max_decoded_bytes: None,
+            max_chunk_size: None,
+            max_metadata_bytes: None,
+        }
+    }
+
+    fn check(limit: &'static str, value: u64, max: Option<u64>) -> Result<(), ImageError> {
+        match max {
+            Some(max) if value > max => Err(ImageError::decoding(DecodingErrorKind::LimitsExceeded { limit, max })),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_dimensions(&self, width: u32, height: u32) -> Result<(), ImageError> {
+        Limits::check("width", width as u64, self.max_width.map(u64::from))?;
+        Limits::check("height", height as u64, self.max_height.map(u64::from))?;
+        Limits::check("pixel count", width as u64 * height as u64, self.max_pixels)
+    }
+
+    pub(crate) fn check_decoded_bytes(&self, len: u64) -> Result<(), ImageError> {
+        Limits::check("decoded size", len, self.max_decoded_bytes)
+    }
+
+    pub(crate) fn check_chunk_size(&self, len: u32) -> Result<(), ImageError> {
+        Limits::check("chunk size", len as u64, self.max_chunk_size.map(u64::from))
+    }
+
+    pub(crate) fn check_metadata_bytes(&self, len: u64) -> Result<(), ImageError> {
+        Limits::check("metadata size", len, self.max_metadata_bytes)
+    }
+}
+
 pub struct GenericImage {
     pub width: u32,
     pub height: u32,
@@ -15,16 +135,149 @@ pub struct GenericImage {
     pub data: Vec<u8>,
 }
 
+/// Image file formats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+}
+
+/// Color model of the stored samples, before any conversion
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageColorType {
+    Gray,
+    GrayAlpha,
+    RGB,
+    RGBA,
+    Indexed,
+}
+
+/// Image properties read from its header, without decoding any pixel
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    pub color_type: ImageColorType,
+    /// Bits per sample, or per palette index for indexed images
+    pub bit_depth: u8,
+    pub interlaced: bool,
+    /// Only read when requested
+    pub metadata: Option<Metadata>,
+}
+
 pub trait GenericImageTo {
     fn to_rgb(&self) -> Result<GenericImage, ImageError>;
     fn to_rgba(&self) -> Result<GenericImage, ImageError>;
     fn to_g(&self) -> Result<GenericImage, ImageError>;
 }
 
+impl GenericImage {
+    pub fn nb_components(&self) -> usize {
+        match self.colors {
+            GenericImageColors::RGB => 3,
+            GenericImageColors::RGBA => 4,
+            GenericImageColors::G => 1,
+        }
+    }
+}
+
+impl GenericImageTo for GenericImage {
+    fn to_rgb(&self) -> Result<GenericImage, ImageError> {
+        let data = match self.colors {
+            GenericImageColors::RGB => self.data.clone(),
+            GenericImageColors::RGBA => {
+                // composite against a white background
+                self.data.chunks(4)
+                    .flat_map(|p| {
+                        let a = p[3] as u32;
+                        let blend = move |c: u8| ((c as u32 * a + 255 * (255 - a) + 127) / 255) as u8;
+                        vec!(blend(p[0]), blend(p[1]), blend(p[2]))
+                    })
+                    .collect()
+            }
+            GenericImageColors::G => self.data.iter().flat_map(|g| vec!(*g, *g, *g)).collect(),
+        };
+
+        Ok(GenericImage { width: self.width, height: self.height, colors: GenericImageColors::RGB, data })
+    }
+
+    fn to_rgba(&self) -> Result<GenericImage, ImageError> {
+        let data = match self.colors {
+            GenericImageColors::RGB => self.data.chunks(3).flat_map(|p| vec!(p[0], p[1], p[2], 255)).collect(),
+            GenericImageColors::RGBA => self.data.clone(),
+            GenericImageColors::G => self.data.iter().flat_map(|g| vec!(*g, *g, *g, 255)).collect(),
+        };
+
+        Ok(GenericImage { width: self.width, height: self.height, colors: GenericImageColors::RGBA, data })
+    }
+
+    fn to_g(&self) -> Result<GenericImage, ImageError> {
+        let data = match self.colors {
+            GenericImageColors::G => self.data.clone(),
+            _ => {
+                let rgb = self.to_rgb()?;
+                rgb.data.chunks(3).map(|p| LumaWeights::Rec601.luma(p[0], p[1], p[2])).collect()
+            }
+        };
+
+        Ok(GenericImage { width: self.width, height: self.height, colors: GenericImageColors::G, data })
+    }
+}
+
 pub trait WriteImage<W: Write, I: GenericImageTo> {
     fn write_image(writer: W, image: &I) -> Result<(), ImageError>;
 }
 
+/// Decoding of a whole image. Decoders accept any file they can decode and
+/// do not enforce the ordering rules of the format: for PNG only
+/// `PngImage::read_image_strict` and `codecs::png::validate` check them.
 pub trait ReadImage<R: Read> {
-    fn read_image(reader: R) -> Result<Box<Self>, ImageError>;
+    /// Read an image within the default `Limits`
+    fn read_image(reader: R) -> Result<Box<Self>, ImageError> {
+        Self::read_image_with_limits(reader, &Limits::default())
+    }
+
+    fn read_image_with_limits(reader: R, limits: &Limits) -> Result<Box<Self>, ImageError>;
+}
+
+/// Read the header of an image in any format the crate can decode. With
+/// `with_metadata` the metadata stored before the pixel data is read too.
+pub fn probe<R: Read>(mut reader: R, with_metadata: bool) -> Result<ImageInfo, ImageError> {
+    let mut magic: Vec<u8> = Vec::with_capacity(8);
+    reader.by_ref().take(8).read_to_end(&mut magic)?;
+    let chained = magic.as_slice().chain(reader);
+
+    if magic.starts_with(&png::PNG_SIGNATURE) {
+        return png::probe(chained, with_metadata);
+    }
+    if magic.starts_with(b"P6") {
+        return Err(ImageError::decoding(DecodingErrorKind::Unsupported("PPM decoding".to_string())));
+    }
+    Err(ImageError::decoding(DecodingErrorKind::UnknownFormat))
+}
+
+#[test]
+fn test_limits() {
+    let limits = Limits { max_width: Some(100), max_pixels: Some(1000), ..Limits::none() };
+    assert!(limits.check_dimensions(100, 10).is_ok());
+    assert!(limits.check_dimensions(101, 1).unwrap_err().is_limits_exceeded());
+    assert_eq!(limits.check_dimensions(50, 21).unwrap_err().decoding_kind(), Some(&DecodingErrorKind::LimitsExceeded { limit: "pixel count", max: 1000 }));
+    assert!(limits.check_decoded_bytes(u64::MAX).is_ok());
+
+    let e = Limits::default().check_chunk_size(u32::MAX).unwrap_err();
+    assert!(e.is_limits_exceeded() && ! e.is_corrupt() && ! e.is_unsupported());
+    assert!(Limits::none().check_dimensions(u32::MAX, u32::MAX).is_ok());
+}
+
+#[test]
+fn test_luma_weights() {
+    for w in &[LumaWeights::Rec601, LumaWeights::Rec709] {
+        assert_eq!(w.luma(0, 0, 0), 0);
+        assert_eq!(w.luma(255, 255, 255), 255);
+        assert_eq!(w.luma(128, 128, 128), 128);
+    }
+    assert_eq!(LumaWeights::Rec601.luma(255, 0, 0), 77);
+    assert_eq!(LumaWeights::Rec709.luma(255, 0, 0), 54);
+    assert_eq!(LumaWeights::Rec601.luma(0, 255, 0), 149);
+    assert_eq!(LumaWeights::Rec709.luma(0, 255, 0), 182);
 }
@@ -0,0 +1,15 @@
+#[macro_use]
+extern crate log;
+
+pub mod error;
+pub mod image;
+pub mod metadata;
+pub mod exif;
+pub mod color;
+pub mod icc;
+pub mod codecs {
+    pub mod png;
+    pub mod ppm;
+}
+pub mod hashs;
+pub mod compress;
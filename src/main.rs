@@ -2,22 +2,10 @@ use std::fs::File;
 use std::path::Path;
 use std::env;
 
-#[macro_use]
-extern crate log;
-
-mod error;
-mod image;
-mod codecs {
-    pub mod png;
-    pub mod ppm;
-}
-mod hashs;
-mod compress;
-
-use crate::codecs::png::*;
-use crate::codecs::ppm::*;
-use crate::image::WriteImage;
-use crate::image::ReadImage;
+use polms_image::codecs::png::*;
+use polms_image::codecs::ppm::*;
+use polms_image::image::WriteImage;
+use polms_image::image::ReadImage;
 
 fn main() {
     env_logger::builder()
@@ -26,7 +14,7 @@ fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
-        eprintln!("USAGE: {} file.png", args[0]);
+        eprintln!("USAGE: {} file.png [out.ppm|out.png]", args[0]);
         std::process::exit(1);
     }
     
@@ -35,8 +23,12 @@ fn main() {
 
     let image: Box<PngImage> = PngImage::read_image(file).unwrap();
 
-    let out_path = Path::new("out.ppm");
+    let out_path = Path::new(args.get(2).map(String::as_str).unwrap_or("out.ppm"));
     let out_file = File::create(&out_path).unwrap();
 
-    PpmImage::write_image(out_file, image.as_ref()).unwrap();
+    if out_path.extension().map_or(false, |e| e == "png") {
+        PngImage::write_image(out_file, image.as_ref()).unwrap();
+    } else {
+        PpmImage::write_image(out_file, image.as_ref()).unwrap();
+    }
 }
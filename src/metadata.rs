@@ -0,0 +1,174 @@
+use std::fmt;
+use std::fmt::Display;
+
+use crate::exif::Exif;
+
+/// Date and time in UTC, as stored in a PNG tIME chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    /// 0 to 60, for leap seconds
+    pub second: u8,
+}
+
+impl Timestamp {
+    /// None when a field is out of range
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Option<Self> {
+        let valid = (1..=12).contains(&month) && (1..=31).contains(&day) && hour < 24 && minute < 60 && second <= 60;
+        if ! valid {
+            return None;
+        }
+        Some(Timestamp { year, month, day, hour, minute, second })
+    }
+}
+
+/// ISO 8601, like 2024-03-09T17:05:00Z
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DensityUnit {
+    /// Only the pixel aspect ratio is known
+    Unknown,
+    Meter,
+}
+
+/// Number of pixels per unit on each axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelDensity {
+    pub x: u32,
+    pub y: u32,
+    pub unit: DensityUnit,
+}
+
+impl PixelDensity {
+    /// Dots per inch on each axis, None without a physical unit
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        match self.unit {
+            DensityUnit::Meter => Some((self.x as f64 * 0.0254, self.y as f64 * 0.0254)),
+            DensityUnit::Unknown => None,
+        }
+    }
+
+    /// Width of a pixel divided by its height
+    pub fn aspect_ratio(&self) -> Option<f64> {
+        if self.x == 0 {
+            return None;
+        }
+        Some(self.y as f64 / self.x as f64)
+    }
+}
+
+/// Text from a tEXt, zTXt or iTXt chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEntry {
+    pub keyword: String,
+    pub text: String,
+    /// Language tag like "en" or "fr-CA", only in international text
+    pub language: Option<String>,
+    /// Keyword translated to `language`, only in international text
+    pub translated_keyword: Option<String>,
+    /// The text was stored deflated
+    pub compressed: bool,
+}
+
+impl TextEntry {
+    pub fn new(keyword: &str, text: &str) -> Self {
+        TextEntry {
+            keyword: keyword.to_string(),
+            text: text.to_string(),
+            language: None,
+            translated_keyword: None,
+            compressed: false,
+        }
+    }
+}
+
+/// Metadata stored next to the pixels
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// Text entries in file order
+    pub texts: Vec<TextEntry>,
+    /// Last modification time
+    pub time: Option<Timestamp>,
+    pub density: Option<PixelDensity>,
+    /// Orientation, camera, capture time and GPS position
+    pub exif: Option<Exif>,
+}
+
+impl Metadata {
+    /// Text of the first entry with `keyword`
+    pub fn text(&self, keyword: &str) -> Option<&str> {
+        self.texts.iter().find(|t| t.keyword == keyword).map(|t| t.text.as_str())
+    }
+
+    /// Text of the entry with `keyword` in `language`, falling back to
+    /// the first entry with `keyword`. Language tags are case insensitive
+    /// and "en" matches "en-US".
+    pub fn localized_text(&self, keyword: &str, language: &str) -> Option<&str> {
+        let matches = |tag: &str| {
+            tag.eq_ignore_ascii_case(language)
+                || (tag.len() > language.len() && tag.as_bytes()[language.len()] == b'-' && tag[..language.len()].eq_ignore_ascii_case(language))
+        };
+        self.texts.iter()
+            .find(|t| t.keyword == keyword && t.language.as_deref().is_some_and(matches))
+            .map(|t| t.text.as_str())
+            .or_else(|| self.text(keyword))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.texts.is_empty() && self.time.is_none() && self.density.is_none() && self.exif.is_none()
+    }
+}
+
+#[test]
+fn test_timestamp() {
+    let t = Timestamp::new(2024, 3, 9, 17, 5, 0).unwrap();
+    assert_eq!(t.to_string(), "2024-03-09T17:05:00Z");
+    assert!(t < Timestamp::new(2024, 3, 10, 0, 0, 0).unwrap());
+
+    assert_eq!(Timestamp::new(2024, 0, 9, 17, 5, 0), None);
+    assert_eq!(Timestamp::new(2024, 3, 32, 17, 5, 0), None);
+    assert_eq!(Timestamp::new(2024, 3, 9, 24, 5, 0), None);
+    assert!(Timestamp::new(2016, 12, 31, 23, 59, 60).is_some());
+}
+
+#[test]
+fn test_pixel_density() {
+    let density = PixelDensity { x: 11811, y: 11811, unit: DensityUnit::Meter };
+    let (x, y) = density.dpi().unwrap();
+    assert!((x - 300.0).abs() < 0.01 && (y - 300.0).abs() < 0.01);
+    assert_eq!(density.aspect_ratio(), Some(1.0));
+
+    let density = PixelDensity { x: 2, y: 1, unit: DensityUnit::Unknown };
+    assert_eq!(density.dpi(), None);
+    assert_eq!(density.aspect_ratio(), Some(0.5));
+}
+
+#[test]
+fn test_localized_text() {
+    let international = |language: &str, text: &str| TextEntry {
+        language: Some(language.to_string()),
+        ..TextEntry::new("Title", text)
+    };
+    let metadata = Metadata {
+        texts: vec!(TextEntry::new("Title", "Sunset"), international("fr", "Coucher de soleil"), international("de-AT", "Sonnenuntergang")),
+        ..Metadata::default()
+    };
+
+    assert_eq!(metadata.text("Title"), Some("Sunset"));
+    assert_eq!(metadata.localized_text("Title", "FR"), Some("Coucher de soleil"));
+    assert_eq!(metadata.localized_text("Title", "de"), Some("Sonnenuntergang"));
+    assert_eq!(metadata.localized_text("Title", "d"), Some("Sunset"));
+    assert_eq!(metadata.localized_text("Title", "it"), Some("Sunset"));
+    assert_eq!(metadata.localized_text("Author", "fr"), None);
+}